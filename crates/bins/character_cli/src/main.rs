@@ -1,4 +1,4 @@
-use character::{CharacterClient, Notification};
+use character::{CharacterClient, Notification, StatBar, StatDelta, StatKind, StatType, StatValue};
 use clap::Parser;
 use log::info;
 use rustyline::Editor;
@@ -18,6 +18,10 @@ struct Args {
     /// The port of the character server.
     #[arg(long, default_value = "6675")]
     port: u16,
+
+    /// A token from the character server's config, granting that credential's scopes.
+    #[arg(long)]
+    token: Option<String>,
 }
 
 // =================================================================================================
@@ -30,7 +34,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = format!("{}:{}", args.host, args.port);
 
     println!("Connecting to character server at {}", addr);
-    let client = CharacterClient::connect(addr)?;
+    let client = CharacterClient::connect_with_token(addr, args.token.as_deref())?;
     println!("Successfully connected. Type 'help' for commands.");
 
     let mut rl = Editor::<(), _>::new()?;
@@ -120,6 +124,22 @@ fn handle_command(command: &str, args: &[&str], client: &CharacterClient) -> boo
                 eprintln!("Usage: sub_creditz <user_id> <amount>");
             }
         }
+        "transfer" => {
+            let from = args.first().and_then(|s| s.parse::<u32>().ok());
+            let to = args.get(1).and_then(|s| s.parse::<u32>().ok());
+            let amount = args.get(2).and_then(|s| s.parse::<u32>().ok());
+            if let (Some(from), Some(to), Some(amount)) = (from, to, amount) {
+                match client.transfer_creditz(from, to, amount) {
+                    Ok(_) => println!(
+                        "Transferred {} creditz from user {} to user {}",
+                        amount, from, to
+                    ),
+                    Err(e) => eprintln!("Error transferring creditz: {}", e),
+                }
+            } else {
+                eprintln!("Usage: transfer <from_user_id> <to_user_id> <amount>");
+            }
+        }
         "get_happiness" => {
             let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
             if let Some(id) = user_id {
@@ -143,6 +163,18 @@ fn handle_command(command: &str, args: &[&str], client: &CharacterClient) -> boo
                 eprintln!("Usage: set_happiness <user_id> <value>");
             }
         }
+        "add_happiness" => {
+            let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
+            let delta = args.get(1).and_then(|s| s.parse::<f32>().ok());
+            if let (Some(id), Some(d)) = (user_id, delta) {
+                match client.add_happiness(id, d) {
+                    Ok(_) => println!("Adjusted happiness for user {} by {:.2}", id, d),
+                    Err(e) => eprintln!("Error adjusting happiness: {}", e),
+                }
+            } else {
+                eprintln!("Usage: add_happiness <user_id> <delta>");
+            }
+        }
         "get_hunger" => {
             let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
             if let Some(id) = user_id {
@@ -166,6 +198,18 @@ fn handle_command(command: &str, args: &[&str], client: &CharacterClient) -> boo
                 eprintln!("Usage: set_hunger <user_id> <value>");
             }
         }
+        "add_hunger" => {
+            let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
+            let delta = args.get(1).and_then(|s| s.parse::<f32>().ok());
+            if let (Some(id), Some(d)) = (user_id, delta) {
+                match client.add_hunger(id, d) {
+                    Ok(_) => println!("Adjusted hunger for user {} by {:.2}", id, d),
+                    Err(e) => eprintln!("Error adjusting hunger: {}", e),
+                }
+            } else {
+                eprintln!("Usage: add_hunger <user_id> <delta>");
+            }
+        }
         "get_boredom" => {
             let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
             if let Some(id) = user_id {
@@ -189,18 +233,288 @@ fn handle_command(command: &str, args: &[&str], client: &CharacterClient) -> boo
                 eprintln!("Usage: set_boredom <user_id> <value>");
             }
         }
+        "add_boredom" => {
+            let user_id = args.get(0).and_then(|s| s.parse::<u32>().ok());
+            let delta = args.get(1).and_then(|s| s.parse::<f32>().ok());
+            if let (Some(id), Some(d)) = (user_id, delta) {
+                match client.add_boredom(id, d) {
+                    Ok(_) => println!("Adjusted boredom for user {} by {:.2}", id, d),
+                    Err(e) => eprintln!("Error adjusting boredom: {}", e),
+                }
+            } else {
+                eprintln!("Usage: add_boredom <user_id> <delta>");
+            }
+        }
+        "stats" => match client.list_stats() {
+            Ok(stats) => {
+                for stat in stats {
+                    println!("{} ({:?})", stat.name, stat.stat_type);
+                }
+            }
+            Err(e) => eprintln!("Error listing stats: {}", e),
+        },
+        "get_stat" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            if let (Some(id), Some(stat)) = (user_id, args.get(1)) {
+                match client.get_stat(id, stat) {
+                    Ok(value) => println!("{} for user {}: {}", stat, id, format_stat(&value)),
+                    Err(e) => eprintln!("Error getting {}: {}", stat, e),
+                }
+            } else {
+                eprintln!("Usage: get_stat <user_id> <stat>");
+            }
+        }
+        "set_stat" | "add_stat" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            let (Some(id), Some(stat), Some(number)) = (user_id, args.get(1), args.get(2)) else {
+                eprintln!("Usage: {} <user_id> <stat> <value>", command);
+                return true;
+            };
+            // The server knows whether the stat is a bar or an integer; ask it how to parse.
+            let stat_type = match client.list_stats() {
+                Ok(stats) => stats
+                    .into_iter()
+                    .find(|s| s.name == *stat)
+                    .map(|s| s.stat_type),
+                Err(e) => {
+                    eprintln!("Error listing stats: {}", e);
+                    return true;
+                }
+            };
+            let result = match (command.eq_ignore_ascii_case("set_stat"), stat_type) {
+                (_, None) => {
+                    eprintln!("Unknown stat {}. Type 'stats' for a list.", stat);
+                    return true;
+                }
+                (true, Some(StatType::Bar)) => number
+                    .parse::<f32>()
+                    .ok()
+                    .map(|v| client.set_stat(id, stat, StatValue::Bar(StatBar::from_f32(v)))),
+                (true, Some(StatType::Integer)) => number
+                    .parse::<i64>()
+                    .ok()
+                    .map(|v| client.set_stat(id, stat, StatValue::Integer(v))),
+                (false, Some(StatType::Bar)) => number
+                    .parse::<f32>()
+                    .ok()
+                    .map(|d| client.add_stat(id, stat, StatDelta::Bar(d))),
+                (false, Some(StatType::Integer)) => number
+                    .parse::<i64>()
+                    .ok()
+                    .map(|d| client.add_stat(id, stat, StatDelta::Integer(d))),
+            };
+            match result {
+                Some(Ok(_)) => println!("Updated {} for user {}", stat, id),
+                Some(Err(e)) => eprintln!("Error updating {}: {}", stat, e),
+                None => eprintln!("{} is not a valid {} value", number, stat),
+            }
+        }
+        "inventory" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            if let Some(id) = user_id {
+                match client.get_inventory(id) {
+                    Ok(items) if items.is_empty() => println!("User {} has no items", id),
+                    Ok(items) => {
+                        for item in items {
+                            println!("{} x{}", item.item, item.quantity);
+                        }
+                    }
+                    Err(e) => eprintln!("Error getting inventory: {}", e),
+                }
+            } else {
+                eprintln!("Usage: inventory <user_id>");
+            }
+        }
+        "count_item" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            if let (Some(id), Some(item)) = (user_id, args.get(1)) {
+                match client.count_item(id, item) {
+                    Ok(count) => println!("User {} has {} {}", id, count, item),
+                    Err(e) => eprintln!("Error counting {}: {}", item, e),
+                }
+            } else {
+                eprintln!("Usage: count_item <user_id> <item>");
+            }
+        }
+        "grant_item" | "consume_item" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            let quantity = match args.get(2) {
+                Some(s) => s.parse::<u32>().ok(),
+                None => Some(1),
+            };
+            let (Some(id), Some(item), Some(quantity)) = (user_id, args.get(1), quantity) else {
+                eprintln!("Usage: {} <user_id> <item> [quantity]", command);
+                return true;
+            };
+            let result = if command.eq_ignore_ascii_case("grant_item") {
+                client.grant_item(id, item, quantity)
+            } else {
+                client.consume_item(id, item, quantity)
+            };
+            match result {
+                Ok(_) => println!("Updated {} for user {}", item, id),
+                Err(e) => eprintln!("Error updating {}: {}", item, e),
+            }
+        }
+        "kv_get" | "kv_delete" => {
+            let user_id = args.get(1).and_then(|s| s.parse::<u32>().ok());
+            let (Some(namespace), Some(id), Some(key)) = (args.first(), user_id, args.get(2))
+            else {
+                eprintln!("Usage: {} <namespace> <user_id> <key>", command);
+                return true;
+            };
+            if command.eq_ignore_ascii_case("kv_get") {
+                match client.get_value(namespace, id, key) {
+                    Ok(Some(value)) => println!("{}", value),
+                    Ok(None) => println!("{} is not set", key),
+                    Err(e) => eprintln!("Error getting {}: {}", key, e),
+                }
+            } else {
+                match client.delete_value(namespace, id, key) {
+                    Ok(_) => println!("Deleted {}", key),
+                    Err(e) => eprintln!("Error deleting {}: {}", key, e),
+                }
+            }
+        }
+        "kv_set" => {
+            let user_id = args.get(1).and_then(|s| s.parse::<u32>().ok());
+            match (args.first(), user_id, args.get(2)) {
+                (Some(namespace), Some(id), Some(key)) if args.len() > 3 => {
+                    // Words after the key are joined back into one value.
+                    let value = args[3..].join(" ");
+                    match client.set_value(namespace, id, key, &value) {
+                        Ok(_) => println!("Set {}", key),
+                        Err(e) => eprintln!("Error setting {}: {}", key, e),
+                    }
+                }
+                _ => eprintln!("Usage: kv_set <namespace> <user_id> <key> <value...>"),
+            }
+        }
+        "kv_list" => {
+            let user_id = args.get(1).and_then(|s| s.parse::<u32>().ok());
+            if let (Some(namespace), Some(id)) = (args.first(), user_id) {
+                match client.list_values(namespace, id) {
+                    Ok(values) if values.is_empty() => {
+                        println!("User {} has nothing in {}", id, namespace)
+                    }
+                    Ok(values) => {
+                        for entry in values {
+                            println!("{} = {}", entry.key, entry.value);
+                        }
+                    }
+                    Err(e) => eprintln!("Error listing {}: {}", namespace, e),
+                }
+            } else {
+                eprintln!("Usage: kv_list <namespace> <user_id>");
+            }
+        }
+        "get_character" => {
+            let user_ids: Option<Vec<u32>> = args.iter().map(|s| s.parse::<u32>().ok()).collect();
+            match user_ids {
+                Some(user_ids) if !user_ids.is_empty() => match client.get_characters(&user_ids) {
+                    Ok(characters) => {
+                        for c in characters {
+                            println!(
+                                "User {}: creditz {}, happiness {:.2}, hunger {:.2}, boredom {:.2}",
+                                c.user_id,
+                                c.creditz,
+                                c.happiness.to_f32(),
+                                c.hunger.to_f32(),
+                                c.boredom.to_f32()
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Error getting characters: {}", e),
+                },
+                _ => eprintln!("Usage: get_character <user_id> [user_id...]"),
+            }
+        }
+        "history" => {
+            let user_id = args.first().and_then(|s| s.parse::<u32>().ok());
+            // Outer None means the argument is malformed; inner None means it was left out.
+            let before = match args.get(1) {
+                Some(s) => s.parse::<u64>().ok().map(Some),
+                None => Some(None),
+            };
+            match (user_id, before) {
+                (Some(id), Some(before)) => match client.get_creditz_history(id, before, 20) {
+                    Ok(entries) if entries.is_empty() => {
+                        println!("No creditz history for user {}", id)
+                    }
+                    Ok(entries) => {
+                        for entry in &entries {
+                            println!(
+                                "#{} at {} ms: {:+} -> {} ({}, via {})",
+                                entry.id,
+                                entry.timestamp,
+                                entry.delta,
+                                entry.balance,
+                                entry.reason.as_deref().unwrap_or("no reason"),
+                                entry.source.as_deref().unwrap_or("anonymous")
+                            );
+                        }
+                        if let Some(last) = entries.last() {
+                            println!("Older entries: history {} {}", id, last.id);
+                        }
+                    }
+                    Err(e) => eprintln!("Error getting creditz history: {}", e),
+                },
+                _ => eprintln!("Usage: history <user_id> [before_entry_id]"),
+            }
+        }
+        "subscribe" | "unsubscribe" => {
+            let user_ids: Option<Vec<u32>> = args.iter().map(|s| s.parse::<u32>().ok()).collect();
+            let Some(user_ids) = user_ids else {
+                eprintln!("Usage: {} [user_id...]", command);
+                return true;
+            };
+            let result = if command.eq_ignore_ascii_case("subscribe") {
+                client.subscribe(&user_ids, &StatKind::EVERY)
+            } else {
+                client.unsubscribe(&user_ids, &StatKind::EVERY)
+            };
+            let users = if user_ids.is_empty() {
+                "all users".to_string()
+            } else {
+                format!("users {:?}", user_ids)
+            };
+            match result {
+                Ok(_) => println!("Updated notifications for {}", users),
+                Err(e) => eprintln!("Error updating subscriptions: {}", e),
+            }
+        }
         "help" => {
             println!("Available commands:");
             println!("  get_creditz <user_id>");
             println!("  set_creditz <user_id> <value>");
             println!("  add_creditz <user_id> <amount>");
             println!("  sub_creditz <user_id> <amount>");
+            println!("  transfer <from_user_id> <to_user_id> <amount>");
             println!("  get_happiness <user_id>");
             println!("  set_happiness <user_id> <value>");
+            println!("  add_happiness <user_id> <delta>");
             println!("  get_hunger <user_id>");
             println!("  set_hunger <user_id> <value>");
+            println!("  add_hunger <user_id> <delta>");
             println!("  get_boredom <user_id>");
             println!("  set_boredom <user_id> <value>");
+            println!("  add_boredom <user_id> <delta>");
+            println!("  stats");
+            println!("  get_stat <user_id> <stat>");
+            println!("  set_stat <user_id> <stat> <value>");
+            println!("  add_stat <user_id> <stat> <delta>");
+            println!("  inventory <user_id>");
+            println!("  count_item <user_id> <item>");
+            println!("  grant_item <user_id> <item> [quantity]");
+            println!("  consume_item <user_id> <item> [quantity]");
+            println!("  kv_get <namespace> <user_id> <key>");
+            println!("  kv_set <namespace> <user_id> <key> <value...>");
+            println!("  kv_delete <namespace> <user_id> <key>");
+            println!("  kv_list <namespace> <user_id>");
+            println!("  get_character <user_id> [user_id...]");
+            println!("  history <user_id> [before_entry_id]");
+            println!("  subscribe [user_id...]     (all users if none given)");
+            println!("  unsubscribe [user_id...]   (all users if none given)");
             println!("  help");
             println!("  quit");
         }
@@ -240,5 +554,46 @@ fn handle_notification(notification: Notification) {
                 new_value.to_f32()
             );
         }
+        Notification::StatChanged {
+            user_id,
+            stat,
+            new_value,
+        } => {
+            println!(
+                "\n[Notification] User {}'s {} changed to {}",
+                user_id,
+                stat,
+                format_stat(&new_value)
+            );
+        }
+        Notification::ItemGranted {
+            user_id,
+            item,
+            quantity,
+            new_count,
+        } => {
+            println!(
+                "\n[Notification] User {} was given {} {} and now has {}",
+                user_id, quantity, item, new_count
+            );
+        }
+        Notification::ItemConsumed {
+            user_id,
+            item,
+            quantity,
+            new_count,
+        } => {
+            println!(
+                "\n[Notification] User {} used {} {} and now has {}",
+                user_id, quantity, item, new_count
+            );
+        }
+    }
+}
+
+fn format_stat(value: &StatValue) -> String {
+    match value {
+        StatValue::Bar(bar) => format!("{:.2}", bar.to_f32()),
+        StatValue::Integer(value) => value.to_string(),
     }
 }
@@ -0,0 +1,531 @@
+use crate::auth::{Authenticator, Session};
+use crate::config::AdminApiConfig;
+use crate::metrics::METRICS;
+use crate::{load_failure, process_request, Attribution, Backend};
+use axum::extract::{Path, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use character::protocol::PROTOCOL_VERSION;
+use character::{
+    Op, Request, Response, Scope, ServerError, StatBar, StatDelta, StatType, StatValue,
+};
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+
+/// How many ledger entries `GET /citizens/{id}/ledger` returns unless asked for another number.
+const DEFAULT_LEDGER_LIMIT: u32 = 50;
+
+/// Everything the admin API's handlers share.
+#[derive(Clone)]
+struct AdminState {
+    backend: Backend,
+    authenticator: Arc<Authenticator>,
+    started: Instant,
+}
+
+/// Starts serving the admin API in the background.
+///
+/// Changes go through the same path as character protocol requests, so they are checked
+/// against the credential's scopes, recorded in the ledger, and announced to subscribers.
+pub async fn start(
+    config: AdminApiConfig,
+    backend: Backend,
+    authenticator: Arc<Authenticator>,
+) -> std::io::Result<()> {
+    let state = AdminState {
+        backend,
+        authenticator,
+        started: Instant::now(),
+    };
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .route("/citizens", get(list_citizens))
+        .route("/citizens/{id}", get(get_citizen))
+        .route("/citizens/{id}/stats/{stat}", get(get_stat).put(set_stat))
+        .route("/citizens/{id}/stats/{stat}/add", post(add_stat))
+        .route("/citizens/{id}/ledger", get(get_ledger))
+        .with_state(state);
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Admin API listening on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Admin API stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+// =================================================================================================
+//                                           HANDLERS
+// =================================================================================================
+
+/// `GET /health`. Needs no token, so that load balancers and monitors can call it.
+async fn health(State(state): State<AdminState>) -> Json<Value> {
+    Json(json!({
+        "status": "ok",
+        "protocol_version": PROTOCOL_VERSION,
+        "uptime_secs": state.started.elapsed().as_secs(),
+    }))
+}
+
+/// `GET /metrics`, in the Prometheus text format. Needs no token, so that a local Prometheus
+/// can scrape it.
+async fn metrics(State(state): State<AdminState>) -> Result<impl IntoResponse, ApiError> {
+    let db = state.backend.db.clone();
+    let total_creditz = tokio::task::spawn_blocking(move || db.blocking_lock().total_creditz())
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let total_creditz = match total_creditz {
+        Ok(total) => Some(total),
+        Err(e) => {
+            METRICS.storage_error("load");
+            error!("Failed to total creditz for metrics: {}", e);
+            None
+        }
+    };
+    let body = METRICS
+        .encode(total_creditz)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    Ok((
+        [(
+            CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        body,
+    ))
+}
+
+/// `GET /citizens`: the id of every citizen the server has stats for.
+async fn list_citizens(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    if !session.has_scope(Scope::Read) {
+        return Err(ApiError::Server(ServerError::Unauthorized));
+    }
+
+    let db = state.backend.db.clone();
+    let mut citizens =
+        tokio::task::spawn_blocking(move || db.blocking_lock().citizen_ids().map_err(load_failure))
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))??;
+    citizens.sort_unstable();
+    Ok(Json(json!({ "citizens": citizens })))
+}
+
+/// `GET /citizens/{id}`: every stat of one citizen, built-in or configured, by name.
+async fn get_citizen(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(user_id): Path<u32>,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    state.require_citizen(&session, user_id).await?;
+    let names: Vec<String> = state
+        .backend
+        .registry
+        .stats
+        .list()
+        .into_iter()
+        .map(|stat| stat.name)
+        .collect();
+    let ops = names
+        .iter()
+        .map(|stat| Op::GetStat {
+            user_id,
+            stat: stat.clone(),
+        })
+        .collect();
+
+    let Response::Transaction(responses) =
+        state.run(session, Request::Transaction(ops), None).await?
+    else {
+        return Err(ApiError::unexpected());
+    };
+    let mut stats = Map::new();
+    for (name, response) in names.into_iter().zip(responses) {
+        let Response::Stat(value) = response else {
+            return Err(ApiError::unexpected());
+        };
+        stats.insert(name, stat_json(&value));
+    }
+    Ok(Json(json!({ "user_id": user_id, "stats": stats })))
+}
+
+/// `GET /citizens/{id}/stats/{stat}`.
+async fn get_stat(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path((user_id, stat)): Path<(u32, String)>,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    state.require_citizen(&session, user_id).await?;
+    match state
+        .run(session, Request::GetStat { user_id, stat }, None)
+        .await?
+    {
+        Response::Stat(value) => Ok(Json(json!({ "value": stat_json(&value) }))),
+        _ => Err(ApiError::unexpected()),
+    }
+}
+
+#[derive(Deserialize)]
+struct SetStatBody {
+    value: f64,
+    /// Recorded in the ledger if the stat is creditz.
+    reason: Option<String>,
+}
+
+/// `PUT /citizens/{id}/stats/{stat}` with `{"value": ..., "reason": ...}`. Replies with the
+/// stat's new value.
+async fn set_stat(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path((user_id, stat)): Path<(u32, String)>,
+    Json(body): Json<SetStatBody>,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    let value = match state.stat_type(&stat)? {
+        StatType::Bar => StatValue::Bar(StatBar::from_f32(body.value as f32)),
+        StatType::Integer => StatValue::Integer(whole_number(body.value)?),
+    };
+    let op = Op::SetStat {
+        user_id,
+        stat: stat.clone(),
+        value,
+    };
+    state
+        .run_and_read(session, op, user_id, stat, body.reason)
+        .await
+}
+
+#[derive(Deserialize)]
+struct AddStatBody {
+    delta: f64,
+    /// Recorded in the ledger if the stat is creditz.
+    reason: Option<String>,
+}
+
+/// `POST /citizens/{id}/stats/{stat}/add` with `{"delta": ..., "reason": ...}`. Replies with
+/// the stat's new value.
+async fn add_stat(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path((user_id, stat)): Path<(u32, String)>,
+    Json(body): Json<AddStatBody>,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    let delta = match state.stat_type(&stat)? {
+        StatType::Bar => StatDelta::Bar(body.delta as f32),
+        StatType::Integer => StatDelta::Integer(whole_number(body.delta)?),
+    };
+    let op = Op::AddStat {
+        user_id,
+        stat: stat.clone(),
+        delta,
+    };
+    state
+        .run_and_read(session, op, user_id, stat, body.reason)
+        .await
+}
+
+#[derive(Deserialize)]
+struct LedgerQuery {
+    /// Only entries older than this entry id, for paging.
+    before: Option<u64>,
+    limit: Option<u32>,
+}
+
+/// `GET /citizens/{id}/ledger?before=...&limit=...`: recent creditz changes, newest first.
+async fn get_ledger(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Path(user_id): Path<u32>,
+    Query(query): Query<LedgerQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let session = state.session(&headers)?;
+    let request = Request::GetCreditzHistory {
+        user_id,
+        before: query.before,
+        limit: query.limit.unwrap_or(DEFAULT_LEDGER_LIMIT),
+    };
+    match state.run(session, request, None).await? {
+        Response::CreditzHistory(entries) => Ok(Json(json!({ "entries": entries }))),
+        _ => Err(ApiError::unexpected()),
+    }
+}
+
+// =================================================================================================
+//                                           HELPERS
+// =================================================================================================
+
+impl AdminState {
+    /// The session of the credential whose token is in the `Authorization` header.
+    fn session(&self, headers: &HeaderMap) -> Result<Session, ApiError> {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.authenticator.authenticate(token))
+            .ok_or(ApiError::Unauthenticated)
+    }
+
+    /// Fails with 404 unless `user_id` has stats. Reading goes through the same path as
+    /// changing, which creates the player, so reads check first.
+    async fn require_citizen(&self, session: &Session, user_id: u32) -> Result<(), ApiError> {
+        // Whether a citizen exists is only for those who may read their stats.
+        if !session.has_scope(Scope::Read) {
+            return Err(ApiError::Server(ServerError::Unauthorized));
+        }
+        let db = self.backend.db.clone();
+        let exists = tokio::task::spawn_blocking(move || {
+            db.blocking_lock()
+                .player_exists(user_id)
+                .map_err(load_failure)
+        })
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))??;
+        if exists {
+            Ok(())
+        } else {
+            Err(ApiError::Server(ServerError::UnknownUser(user_id)))
+        }
+    }
+
+    fn stat_type(&self, stat: &str) -> Result<StatType, ApiError> {
+        Ok(self.backend.registry.stats.get(stat)?.stat_type)
+    }
+
+    /// Processes `request` as `session`, and announces any changes it makes.
+    async fn run(
+        &self,
+        session: Session,
+        request: Request,
+        reason: Option<String>,
+    ) -> Result<Response, ApiError> {
+        let attribution = Attribution {
+            reason,
+            source: session.name.clone(),
+        };
+        let backend = self.backend.clone();
+        let (response, _) = tokio::task::spawn_blocking(move || {
+            process_request(request, &attribution, &session, &backend)
+        })
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+        match response {
+            Response::Error(e) => Err(ApiError::Server(e)),
+            response => Ok(response),
+        }
+    }
+
+    /// Applies `op` and reads the stat it changed back, in one transaction.
+    async fn run_and_read(
+        &self,
+        session: Session,
+        op: Op,
+        user_id: u32,
+        stat: String,
+        reason: Option<String>,
+    ) -> Result<Json<Value>, ApiError> {
+        let request = Request::Transaction(vec![op, Op::GetStat { user_id, stat }]);
+        match self.run(session, request, reason).await? {
+            Response::Transaction(responses) => match responses.last() {
+                Some(Response::Stat(value)) => Ok(Json(json!({ "value": stat_json(value) }))),
+                _ => Err(ApiError::unexpected()),
+            },
+            _ => Err(ApiError::unexpected()),
+        }
+    }
+}
+
+fn stat_json(value: &StatValue) -> Value {
+    match value {
+        StatValue::Bar(bar) => json!(bar.to_f32()),
+        StatValue::Integer(value) => json!(value),
+    }
+}
+
+fn whole_number(value: f64) -> Result<i64, ApiError> {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        Ok(value as i64)
+    } else {
+        Err(ApiError::Server(ServerError::InvalidValue(format!(
+            "{} is not a whole number",
+            value
+        ))))
+    }
+}
+
+/// Why an admin API request failed, sent as `{"error": "..."}` with a matching status code.
+enum ApiError {
+    /// No token, or one that matches no credential.
+    Unauthenticated,
+    Server(ServerError),
+    Internal(String),
+}
+
+impl ApiError {
+    fn unexpected() -> Self {
+        ApiError::Internal("Unexpected response".to_string())
+    }
+}
+
+impl From<ServerError> for ApiError {
+    fn from(e: ServerError) -> Self {
+        ApiError::Server(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, message) = match self {
+            ApiError::Unauthenticated => (
+                StatusCode::UNAUTHORIZED,
+                "A valid bearer token is required".to_string(),
+            ),
+            ApiError::Server(e) => {
+                let status = match e {
+                    ServerError::Unauthorized => StatusCode::FORBIDDEN,
+                    ServerError::UnknownUser(_)
+                    | ServerError::UnknownStat(_)
+                    | ServerError::UnknownItem(_) => StatusCode::NOT_FOUND,
+                    ServerError::InsufficientFunds
+                    | ServerError::NotEnoughItems
+                    | ServerError::Overflow => StatusCode::CONFLICT,
+                    ServerError::InvalidValue(_) | ServerError::InvalidRequest(_) => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    ServerError::StorageFailure(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, e.to_string())
+            }
+            ApiError::Internal(message) => {
+                error!("Admin API request failed: {}", message);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal error".to_string(),
+                )
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CredentialConfig, NotificationQueueConfig};
+    use crate::items::ItemRegistry;
+    use crate::notifications::NotificationHub;
+    use crate::stats::StatRegistry;
+    use crate::storage::MemoryStorage;
+    use crate::Registry;
+    use axum::http::HeaderValue;
+    use tokio::sync::Mutex;
+
+    fn state() -> AdminState {
+        let stats = StatRegistry::new(Vec::new()).unwrap();
+        let items = ItemRegistry::new(Vec::new(), &stats).unwrap();
+        let credential = |name: &str, scopes: Vec<Scope>| CredentialConfig {
+            name: name.to_string(),
+            token: format!("{}-token", name),
+            scopes,
+            notification_queue: None,
+        };
+        AdminState {
+            backend: Backend {
+                db: Arc::new(Mutex::new(Box::new(MemoryStorage::new()))),
+                notifier: Arc::new(Mutex::new(NotificationHub::new(
+                    16,
+                    NotificationQueueConfig::default(),
+                ))),
+                registry: Arc::new(Registry { stats, items }),
+            },
+            authenticator: Arc::new(Authenticator::new(
+                vec![
+                    credential("ops", vec![Scope::Admin]),
+                    credential("game", vec![Scope::CreditzWrite]),
+                ],
+                vec![Scope::Admin],
+            )),
+            started: Instant::now(),
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(token).unwrap());
+        headers
+    }
+
+    fn status(error: ApiError) -> StatusCode {
+        error.into_response().status()
+    }
+
+    #[test]
+    fn requests_without_a_valid_token_are_unauthenticated() {
+        let state = state();
+
+        for headers in [
+            HeaderMap::new(),
+            bearer("ops-token"),
+            bearer("Bearer wrong-token"),
+        ] {
+            let error = state.session(&headers).err().unwrap();
+            assert_eq!(status(error), StatusCode::UNAUTHORIZED);
+        }
+        let session = state.session(&bearer("Bearer ops-token")).ok().unwrap();
+        assert_eq!(session.name.as_deref(), Some("ops"));
+    }
+
+    #[tokio::test]
+    async fn unknown_citizens_are_not_found_and_not_created() {
+        let state = state();
+        let ops = state.session(&bearer("Bearer ops-token")).ok().unwrap();
+
+        let error = state.require_citizen(&ops, 7).await.err().unwrap();
+
+        assert_eq!(status(error), StatusCode::NOT_FOUND);
+        assert!(!state.backend.db.lock().await.player_exists(7).unwrap());
+    }
+
+    #[tokio::test]
+    async fn citizens_are_hidden_from_credentials_that_cannot_read() {
+        let state = state();
+        let game = state.session(&bearer("Bearer game-token")).ok().unwrap();
+
+        let error = state.require_citizen(&game, 7).await.err().unwrap();
+
+        assert_eq!(status(error), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn server_errors_map_to_status_codes() {
+        for (error, expected) in [
+            (ServerError::InsufficientFunds, StatusCode::CONFLICT),
+            (
+                ServerError::InvalidValue(String::new()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServerError::StorageFailure(String::new()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ] {
+            assert_eq!(status(ApiError::Server(error)), expected);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::config::{CredentialConfig, NotificationQueueConfig};
+use crate::items::ItemRegistry;
+use character::{Op, Request, Scope, ServerError};
+
+/// What a connection is allowed to do, and who it authenticated as.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The name of the credential the connection authenticated with, if any.
+    pub name: Option<String>,
+    scopes: Vec<Scope>,
+    /// The credential's own notification queue settings, if it has any.
+    pub notification_queue: Option<NotificationQueueConfig>,
+}
+
+impl Session {
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    /// Returns true if the session was granted `scope`. `Scope::Admin` implies every scope.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    /// Checks that the session holds every scope `request` needs, including those needed
+    /// to change the stats of any item it consumes.
+    pub fn authorize(&self, request: &Request, items: &ItemRegistry) -> Result<(), ServerError> {
+        if required_scopes(request, items)
+            .into_iter()
+            .all(|scope| self.has_scope(scope))
+        {
+            Ok(())
+        } else {
+            Err(ServerError::Unauthorized)
+        }
+    }
+}
+
+/// Checks tokens against the credentials from the server config.
+pub struct Authenticator {
+    credentials: Vec<CredentialConfig>,
+    anonymous_scopes: Vec<Scope>,
+}
+
+impl Authenticator {
+    pub fn new(credentials: Vec<CredentialConfig>, anonymous_scopes: Vec<Scope>) -> Self {
+        Self {
+            credentials,
+            anonymous_scopes,
+        }
+    }
+
+    /// The session every connection starts with, before it authenticates.
+    pub fn anonymous_session(&self) -> Session {
+        Session {
+            name: None,
+            scopes: self.anonymous_scopes.clone(),
+            notification_queue: None,
+        }
+    }
+
+    /// Returns the session for the credential holding `token`, if there is one.
+    pub fn authenticate(&self, token: &str) -> Option<Session> {
+        self.credentials
+            .iter()
+            .find(|credential| constant_time_eq(credential.token.as_bytes(), token.as_bytes()))
+            .map(|credential| Session {
+                name: Some(credential.name.clone()),
+                scopes: credential.scopes.clone(),
+                notification_queue: credential.notification_queue,
+            })
+    }
+}
+
+/// The scopes needed to perform `request`. A transaction needs the scopes of all its ops.
+fn required_scopes(request: &Request, items: &ItemRegistry) -> Vec<Scope> {
+    match request {
+        Request::Authenticate(_) | Request::Unsubscribe { .. } | Request::Resume { .. } => {
+            Vec::new()
+        }
+        Request::Subscribe { .. } => vec![Scope::Read],
+        Request::Transaction(ops) => ops
+            .iter()
+            .flat_map(|op| {
+                let mut scopes = vec![required_scope(op)];
+                if let Op::ConsumeItem { item, .. } = op {
+                    scopes.extend(effect_scopes(item, items));
+                }
+                scopes
+            })
+            .collect(),
+        Request::GetCreditz(_)
+        | Request::GetHappiness(_)
+        | Request::GetBoredom(_)
+        | Request::GetHunger(_)
+        | Request::GetCharacter(_)
+        | Request::GetCharacters(_)
+        | Request::GetCreditzHistory { .. }
+        | Request::GetStat { .. }
+        | Request::ListStats
+        | Request::CountItem { .. }
+        | Request::GetInventory(_) => vec![Scope::Read],
+        // Overwriting a balance outright is reserved for administrators; game bots should
+        // only ever award or charge.
+        Request::SetCreditz(..) => vec![Scope::Admin],
+        Request::AddCreditz(..)
+        | Request::SubtractCreditz(..)
+        | Request::TransferCreditz { .. } => vec![Scope::CreditzWrite],
+        Request::SetHappiness(..)
+        | Request::AddHappiness(..)
+        | Request::SetBoredom(..)
+        | Request::AddBoredom(..)
+        | Request::SetHunger(..)
+        | Request::AddHunger(..) => vec![Scope::StatWrite],
+        Request::SetStat { stat, .. } => vec![named_stat_scope(stat, Scope::Admin)],
+        Request::AddStat { stat, .. } => vec![named_stat_scope(stat, Scope::CreditzWrite)],
+        Request::GrantItem { .. } => vec![Scope::ItemWrite],
+        Request::ConsumeItem { item, .. } => {
+            let mut scopes = vec![Scope::ItemWrite];
+            scopes.extend(effect_scopes(item, items));
+            scopes
+        }
+        Request::GetValue { .. }
+        | Request::SetValue { .. }
+        | Request::DeleteValue { .. }
+        | Request::ListValues { .. } => vec![Scope::KeyValue],
+    }
+}
+
+fn required_scope(op: &Op) -> Scope {
+    match op {
+        Op::GetCreditz(_)
+        | Op::GetHappiness(_)
+        | Op::GetBoredom(_)
+        | Op::GetHunger(_)
+        | Op::GetStat { .. }
+        | Op::CountItem { .. } => Scope::Read,
+        Op::SetCreditz(..) => Scope::Admin,
+        Op::AddCreditz(..) | Op::SubtractCreditz(..) => Scope::CreditzWrite,
+        Op::SetHappiness(..)
+        | Op::AddHappiness(..)
+        | Op::SetBoredom(..)
+        | Op::AddBoredom(..)
+        | Op::SetHunger(..)
+        | Op::AddHunger(..) => Scope::StatWrite,
+        Op::SetStat { stat, .. } => named_stat_scope(stat, Scope::Admin),
+        Op::AddStat { stat, .. } => named_stat_scope(stat, Scope::CreditzWrite),
+        Op::GrantItem { .. } | Op::ConsumeItem { .. } => Scope::ItemWrite,
+    }
+}
+
+/// Consuming an item changes the stats it affects, which needs the same scopes as adding to
+/// them directly. An unknown item needs none; consuming it fails anyway.
+fn effect_scopes(item: &str, items: &ItemRegistry) -> Vec<Scope> {
+    let Ok(definition) = items.get(item) else {
+        return Vec::new();
+    };
+    definition
+        .affected_stats()
+        .map(|stat| named_stat_scope(stat, Scope::CreditzWrite))
+        .collect()
+}
+
+/// Changing creditz by name needs the same scope as changing it directly, `creditz_scope`;
+/// every other stat needs `StatWrite`.
+fn named_stat_scope(stat: &str, creditz_scope: Scope) -> Scope {
+    if stat == "creditz" {
+        creditz_scope
+    } else {
+        Scope::StatWrite
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so that a token
+/// can't be guessed one byte at a time by timing failed attempts.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::StatRegistry;
+
+    fn authenticator() -> Authenticator {
+        let credential = |name: &str, token: &str, scopes: Vec<Scope>| CredentialConfig {
+            name: name.to_string(),
+            token: token.to_string(),
+            scopes,
+            notification_queue: None,
+        };
+        Authenticator::new(
+            vec![
+                credential("hud", "hud-token", vec![Scope::Read]),
+                credential("game", "game-token", vec![Scope::CreditzWrite]),
+                credential("ops", "ops-token", vec![Scope::Admin]),
+            ],
+            Vec::new(),
+        )
+    }
+
+    fn items() -> ItemRegistry {
+        ItemRegistry::new(Vec::new(), &StatRegistry::new(Vec::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn authenticates_only_known_tokens() {
+        let authenticator = authenticator();
+
+        let session = authenticator.authenticate("game-token").unwrap();
+        assert_eq!(session.name.as_deref(), Some("game"));
+        assert!(authenticator.authenticate("game-token ").is_none());
+        assert!(authenticator.authenticate("").is_none());
+    }
+
+    #[test]
+    fn scopes_limit_what_each_credential_may_do() {
+        let authenticator = authenticator();
+        let items = items();
+        let hud = authenticator.authenticate("hud-token").unwrap();
+        let game = authenticator.authenticate("game-token").unwrap();
+        let ops = authenticator.authenticate("ops-token").unwrap();
+
+        assert!(hud.authorize(&Request::GetCreditz(1), &items).is_ok());
+        assert!(hud.authorize(&Request::AddCreditz(1, 5), &items).is_err());
+        assert!(game.authorize(&Request::AddCreditz(1, 5), &items).is_ok());
+        assert!(game.authorize(&Request::SetCreditz(1, 5), &items).is_err());
+        assert!(game.authorize(&Request::GetCreditz(1), &items).is_err());
+        assert!(ops.authorize(&Request::SetCreditz(1, 5), &items).is_ok());
+    }
+
+    #[test]
+    fn anonymous_connections_get_the_configured_scopes() {
+        let items = items();
+        let session = authenticator().anonymous_session();
+
+        assert!(session.authorize(&Request::GetCreditz(1), &items).is_err());
+        assert!(session
+            .authorize(&Request::Authenticate("hud-token".to_string()), &items)
+            .is_ok());
+    }
+
+    #[test]
+    fn a_transaction_needs_the_scopes_of_all_its_ops() {
+        let items = items();
+        let game = authenticator().authenticate("game-token").unwrap();
+
+        let transaction = Request::Transaction(vec![Op::AddCreditz(1, 5), Op::GetCreditz(1)]);
+        assert!(matches!(
+            game.authorize(&transaction, &items),
+            Err(ServerError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn compares_tokens_by_content() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
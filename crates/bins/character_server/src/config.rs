@@ -1,9 +1,243 @@
 use aw_db::DatabaseConfig;
+use character::protocol::DEFAULT_MAX_FRAME_SIZE;
+use character::{Scope, StatType};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
 pub struct CharacterServerConfig {
     pub host: String,
     pub port: u16,
-    pub database: DatabaseConfig,
+    /// Where stats are kept: in `database`, the default, or in memory.
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// The SQLite or MySQL database stats are kept in. Required unless `storage = "memory"`.
+    pub database: Option<DatabaseConfig>,
+    /// The largest frame a client may send, in bytes. A client sending a larger one is
+    /// disconnected before the frame is read.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: u32,
+    /// How many clients may be connected at once. Further connections are closed straight
+    /// away until one of them leaves.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// How long a client may go without sending a complete frame before it is disconnected,
+    /// in seconds. 0 never disconnects idle clients.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// How often clients that support heartbeats are pinged, in seconds. Their answers count
+    /// as activity, so only a dead or stuck one reaches `idle_timeout_secs`, and each client
+    /// can tell from the pings that the server is still there. 0 never pings.
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Scopes granted to connections that haven't authenticated. None by default.
+    #[serde(default)]
+    pub anonymous_scopes: Vec<Scope>,
+    /// The tokens clients may authenticate with, e.g.
+    ///
+    /// ```toml
+    /// [[credentials]]
+    /// name = "stat_hud_bot"
+    /// token = "..."
+    /// scopes = ["read"]
+    /// ```
+    #[serde(default)]
+    pub credentials: Vec<CredentialConfig>,
+    /// How many recent notifications are kept for clients resuming after a reconnect.
+    #[serde(default = "default_replay_buffer_size")]
+    pub replay_buffer_size: usize,
+    /// What happens when a client reads its notifications more slowly than they are
+    /// published, unless its credential says otherwise, e.g.
+    ///
+    /// ```toml
+    /// [notification_queue]
+    /// policy = "coalesce"
+    /// capacity = 256
+    /// ```
+    #[serde(default)]
+    pub notification_queue: NotificationQueueConfig,
+    /// Stats beyond the built-in creditz, happiness, hunger and boredom, e.g.
+    ///
+    /// ```toml
+    /// [[stats]]
+    /// name = "energy"
+    /// type = "bar"
+    /// default = 1.0
+    /// ```
+    #[serde(default)]
+    pub stats: Vec<StatConfig>,
+    /// How often `stat_rates` are applied, in seconds.
+    #[serde(default = "default_stat_tick_secs")]
+    pub stat_tick_secs: u64,
+    /// How every player's stats change over time, e.g.
+    ///
+    /// ```toml
+    /// [[stat_rates]]
+    /// stat = "hunger"
+    /// per_hour = 0.05
+    ///
+    /// [[stat_rates]]
+    /// stat = "happiness"
+    /// per_hour = -0.1
+    /// when = { stat = "boredom", above = 0.7 }
+    /// ```
+    #[serde(default)]
+    pub stat_rates: Vec<StatRateConfig>,
+    /// The items players can be given, e.g.
+    ///
+    /// ```toml
+    /// [[items]]
+    /// name = "apple"
+    /// effects = [{ stat = "hunger", delta = -0.2 }, { stat = "happiness", delta = 0.05 }]
+    /// ```
+    #[serde(default)]
+    pub items: Vec<ItemConfig>,
+    /// Serves an HTTP/JSON admin API alongside the character protocol, e.g.
+    ///
+    /// ```toml
+    /// [admin_api]
+    /// host = "127.0.0.1"
+    /// port = 6676
+    /// ```
+    ///
+    /// Requests authenticate with `Authorization: Bearer <token>`, using any of `credentials`,
+    /// except for `/health` and the Prometheus metrics at `/metrics`, which need no token.
+    pub admin_api: Option<AdminApiConfig>,
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_replay_buffer_size() -> usize {
+    4096
+}
+
+fn default_stat_tick_secs() -> u64 {
+    60
+}
+
+fn default_notification_queue_capacity() -> usize {
+    1024
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CredentialConfig {
+    /// Identifies the client in logs.
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    /// Replaces the server's `notification_queue` for connections using this credential.
+    #[serde(default)]
+    pub notification_queue: Option<NotificationQueueConfig>,
+}
+
+/// How many notifications may wait to be written to one client, and what to do when more
+/// arrive.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct NotificationQueueConfig {
+    #[serde(default)]
+    pub policy: BackpressurePolicy,
+    #[serde(default = "default_notification_queue_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for NotificationQueueConfig {
+    fn default() -> Self {
+        Self {
+            policy: BackpressurePolicy::default(),
+            capacity: default_notification_queue_capacity(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    /// The configured `database`.
+    #[default]
+    Database,
+    /// Memory, so that everything is lost when the server stops. For tests and local
+    /// development.
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackpressurePolicy {
+    /// Discard the oldest waiting notification to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Replace a waiting notification of the same user's same stat with the new one, so the
+    /// client only hears the latest value. Item notifications aren't replaced, and the oldest
+    /// is discarded if the queue fills up with them.
+    Coalesce,
+    /// Disconnect the client once the queue is full. It can reconnect and resume.
+    Disconnect,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdminApiConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatConfig {
+    /// Lowercase letters, digits and underscores.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub stat_type: StatType,
+    /// The value a player starts with: 0.0 to 1.0 for a bar, a whole number for an integer.
+    /// Zero if not given.
+    #[serde(default)]
+    pub default: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatRateConfig {
+    /// The name of a bar stat.
+    pub stat: String,
+    /// How much the stat changes per hour, where 1.0 is a full bar. Negative rates decay it.
+    pub per_hour: f32,
+    /// If set, the rate only applies while this holds.
+    #[serde(default)]
+    pub when: Option<StatCondition>,
+}
+
+/// Holds while `stat` is strictly above `above` and strictly below `below`, whichever are set.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatCondition {
+    /// The name of a bar stat.
+    pub stat: String,
+    pub above: Option<f32>,
+    pub below: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ItemConfig {
+    /// Lowercase letters, digits and underscores.
+    pub name: String,
+    /// Applied once for every one of the item consumed.
+    #[serde(default)]
+    pub effects: Vec<ItemEffectConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ItemEffectConfig {
+    /// The name of any stat, built-in or configured.
+    pub stat: String,
+    /// Added to the stat: a fraction of a full bar, or a whole number for an integer stat.
+    pub delta: f64,
 }
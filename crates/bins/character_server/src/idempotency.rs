@@ -0,0 +1,158 @@
+use character::{OperationId, Response, ServerError};
+use std::collections::{HashMap, VecDeque};
+
+/// How many completed operations are remembered for each client identity.
+const OPERATIONS_PER_CLIENT: usize = 256;
+/// How many client identities are tracked before the least recently active one is forgotten.
+const MAX_CLIENTS: usize = 1024;
+
+/// Who an operation belongs to: the name of the session that sent it, if any, and the
+/// client ID it chose. One session can't see, or clash with, another's operations.
+type ClientKey = (Option<String>, u64);
+
+/// Remembers the responses to recently applied mutating requests, so that a request resent
+/// after a reconnect is answered with the original response instead of being applied again.
+pub struct OperationCache {
+    clients: HashMap<ClientKey, ClientOperations>,
+    /// Incremented on every insert; used to find the least recently active client.
+    clock: u64,
+}
+
+struct ClientOperations {
+    last_active: u64,
+    completed: VecDeque<(u64, Response)>,
+}
+
+impl OperationCache {
+    pub fn new() -> Self {
+        Self {
+            clients: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the response originally sent to `session` for `operation_id`, if it has been
+    /// applied.
+    pub fn get(&self, session: Option<&str>, operation_id: &OperationId) -> Option<&Response> {
+        self.clients
+            .get(&(session.map(str::to_owned), operation_id.client_id))?
+            .completed
+            .iter()
+            .find(|(sequence, _)| *sequence == operation_id.sequence)
+            .map(|(_, response)| response)
+    }
+
+    /// Records the response sent to `session` for a newly applied operation. Failures that
+    /// a resend might not meet again, such as a storage failure, are not recorded, so the
+    /// resend is applied afresh.
+    pub fn insert(&mut self, session: Option<&str>, operation_id: OperationId, response: Response) {
+        if matches!(
+            response,
+            Response::Error(ServerError::StorageFailure(_) | ServerError::Unauthorized)
+        ) {
+            return;
+        }
+        self.clock += 1;
+
+        let key = (session.map(str::to_owned), operation_id.client_id);
+        if !self.clients.contains_key(&key) && self.clients.len() >= MAX_CLIENTS {
+            self.evict_least_recently_active();
+        }
+
+        let client = self.clients.entry(key).or_insert_with(|| ClientOperations {
+            last_active: 0,
+            completed: VecDeque::new(),
+        });
+        client.last_active = self.clock;
+        if client.completed.len() >= OPERATIONS_PER_CLIENT {
+            client.completed.pop_front();
+        }
+        client
+            .completed
+            .push_back((operation_id.sequence, response));
+    }
+
+    fn evict_least_recently_active(&mut self) {
+        let oldest = self
+            .clients
+            .iter()
+            .min_by_key(|(_, client)| client.last_active)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.clients.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operation(client_id: u64, sequence: u64) -> OperationId {
+        OperationId {
+            client_id,
+            sequence,
+        }
+    }
+
+    #[test]
+    fn forgets_a_clients_oldest_operations() {
+        let mut cache = OperationCache::new();
+        for sequence in 0..=OPERATIONS_PER_CLIENT as u64 {
+            cache.insert(None, operation(1, sequence), Response::Success);
+        }
+
+        assert!(cache.get(None, &operation(1, 0)).is_none());
+        assert!(cache.get(None, &operation(1, 1)).is_some());
+        assert!(cache
+            .get(None, &operation(1, OPERATIONS_PER_CLIENT as u64))
+            .is_some());
+    }
+
+    #[test]
+    fn forgets_the_least_recently_active_client() {
+        let mut cache = OperationCache::new();
+        for client_id in 0..MAX_CLIENTS as u64 {
+            cache.insert(None, operation(client_id, 0), Response::Success);
+        }
+        // Client 0 is active again, which leaves client 1 the least recently active.
+        cache.insert(None, operation(0, 1), Response::Success);
+        cache.insert(None, operation(MAX_CLIENTS as u64, 0), Response::Success);
+
+        assert_eq!(cache.clients.len(), MAX_CLIENTS);
+        assert!(cache.get(None, &operation(0, 0)).is_some());
+        assert!(cache.get(None, &operation(1, 0)).is_none());
+        assert!(cache.get(None, &operation(MAX_CLIENTS as u64, 0)).is_some());
+    }
+
+    #[test]
+    fn keeps_each_sessions_operations_apart() {
+        let mut cache = OperationCache::new();
+        cache.insert(Some("game"), operation(1, 0), Response::Success);
+
+        assert!(cache.get(Some("game"), &operation(1, 0)).is_some());
+        assert!(cache.get(Some("bot"), &operation(1, 0)).is_none());
+        assert!(cache.get(None, &operation(1, 0)).is_none());
+    }
+
+    #[test]
+    fn does_not_record_failures_a_resend_might_not_meet() {
+        let mut cache = OperationCache::new();
+        let failure = ServerError::StorageFailure("locked".to_string());
+        cache.insert(None, operation(1, 0), Response::Error(failure));
+        cache.insert(
+            None,
+            operation(1, 1),
+            Response::Error(ServerError::Unauthorized),
+        );
+        cache.insert(
+            None,
+            operation(1, 2),
+            Response::Error(ServerError::InsufficientFunds),
+        );
+
+        assert!(cache.get(None, &operation(1, 0)).is_none());
+        assert!(cache.get(None, &operation(1, 1)).is_none());
+        assert!(cache.get(None, &operation(1, 2)).is_some());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::ItemConfig;
+use crate::stats::{self, StatRegistry};
+use character::{Op, ServerError, StatDelta, StatType};
+use std::collections::HashMap;
+
+/// Every item declared in the server configuration, by name.
+pub struct ItemRegistry {
+    items: HashMap<String, ItemDefinition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ItemDefinition {
+    /// What consuming one of the item does to each stat it affects.
+    effects: Vec<(String, StatDelta)>,
+}
+
+impl ItemRegistry {
+    pub fn new(configs: Vec<ItemConfig>, stats: &StatRegistry) -> Result<Self, String> {
+        let mut items = HashMap::new();
+        for config in configs {
+            if !stats::is_valid_name(&config.name) {
+                return Err(format!(
+                    "Item name {:?} must be 1 to {} lowercase letters, digits or underscores",
+                    config.name,
+                    stats::MAX_NAME_LEN
+                ));
+            }
+            if items.contains_key(&config.name) {
+                return Err(format!("Item {} is declared more than once", config.name));
+            }
+
+            let mut effects = Vec::with_capacity(config.effects.len());
+            for effect in config.effects {
+                let stat = stats
+                    .get(&effect.stat)
+                    .map_err(|e| format!("Item {}: {}", config.name, e))?;
+                let delta = match stat.stat_type {
+                    StatType::Bar if effect.delta.is_finite() => {
+                        StatDelta::Bar(effect.delta as f32)
+                    }
+                    StatType::Integer
+                        if effect.delta.fract() == 0.0 && effect.delta.abs() < i64::MAX as f64 =>
+                    {
+                        StatDelta::Integer(effect.delta as i64)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Item {}: {} is not a valid change to {}",
+                            config.name, effect.delta, stat.name
+                        ))
+                    }
+                };
+                effects.push((stat.name.clone(), delta));
+            }
+
+            items.insert(config.name, ItemDefinition { effects });
+        }
+        Ok(Self { items })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ItemDefinition, ServerError> {
+        self.items
+            .get(name)
+            .ok_or_else(|| ServerError::UnknownItem(name.to_string()))
+    }
+}
+
+impl ItemDefinition {
+    /// The names of the stats consuming this item changes.
+    pub fn affected_stats(&self) -> impl Iterator<Item = &str> {
+        self.effects.iter().map(|(stat, _)| stat.as_str())
+    }
+
+    /// The stat changes consuming `quantity` of this item makes to `user_id`.
+    pub fn effect_ops(&self, user_id: u32, quantity: u32) -> Result<Vec<Op>, ServerError> {
+        self.effects
+            .iter()
+            .map(|(stat, delta)| {
+                let delta = match *delta {
+                    StatDelta::Bar(delta) => StatDelta::Bar(delta * quantity as f32),
+                    StatDelta::Integer(delta) => StatDelta::Integer(
+                        delta
+                            .checked_mul(i64::from(quantity))
+                            .ok_or(ServerError::Overflow)?,
+                    ),
+                };
+                Ok(Op::AddStat {
+                    user_id,
+                    stat: stat.clone(),
+                    delta,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ItemEffectConfig;
+
+    fn item(name: &str, stat: &str, delta: f64) -> ItemConfig {
+        ItemConfig {
+            name: name.to_string(),
+            effects: vec![ItemEffectConfig {
+                stat: stat.to_string(),
+                delta,
+            }],
+        }
+    }
+
+    #[test]
+    fn refuses_bad_declarations() {
+        let stats = StatRegistry::new(Vec::new()).unwrap();
+        for config in [
+            item("Coin", "creditz", 1.0),
+            item("coin", "charisma", 1.0),
+            item("coin", "creditz", 0.5),
+            item("snack", "hunger", f64::NAN),
+        ] {
+            let name = config.name.clone();
+            assert!(
+                ItemRegistry::new(vec![config], &stats).is_err(),
+                "{:?}",
+                name
+            );
+        }
+        let coin = || item("coin", "creditz", 1.0);
+        assert!(ItemRegistry::new(vec![coin(), coin()], &stats).is_err());
+    }
+
+    #[test]
+    fn effects_scale_with_quantity() {
+        let stats = StatRegistry::new(Vec::new()).unwrap();
+        let items = ItemRegistry::new(vec![item("coin", "creditz", 10.0)], &stats).unwrap();
+        let coin = items.get("coin").unwrap();
+
+        assert!(matches!(
+            &coin.effect_ops(1, 3).unwrap()[..],
+            [Op::AddStat {
+                delta: StatDelta::Integer(30),
+                ..
+            }]
+        ));
+        let huge = ItemRegistry::new(vec![item("gem", "creditz", 1e18)], &stats).unwrap();
+        assert!(matches!(
+            huge.get("gem").unwrap().effect_ops(1, 100),
+            Err(ServerError::Overflow)
+        ));
+    }
+}
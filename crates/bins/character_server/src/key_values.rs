@@ -0,0 +1,168 @@
+use crate::storage::Storage;
+use crate::{load_failure, save_failure};
+use character::{Request, Response, ServerError};
+
+/// The longest namespace, in bytes.
+const MAX_NAMESPACE_LEN: usize = 64;
+/// The longest key, in bytes.
+const MAX_KEY_LEN: usize = 128;
+/// The longest value, in bytes. The store is for small bits of state, not files.
+const MAX_VALUE_LEN: usize = 4096;
+/// How many keys one player may have in one namespace.
+const MAX_KEYS: usize = 256;
+
+/// Handles a request to the key/value store: `GetValue`, `SetValue`, `DeleteValue` or
+/// `ListValues`.
+pub fn process(request: Request, db: &mut dyn Storage) -> Result<Response, ServerError> {
+    match request {
+        Request::GetValue {
+            namespace,
+            user_id,
+            key,
+        } => {
+            check_namespace(&namespace)?;
+            check_key(&key)?;
+            let value = db
+                .get_value(&namespace, user_id, &key)
+                .map_err(load_failure)?;
+            Ok(Response::Value(value))
+        }
+        Request::SetValue {
+            namespace,
+            user_id,
+            key,
+            value,
+        } => {
+            check_namespace(&namespace)?;
+            check_key(&key)?;
+            if value.len() > MAX_VALUE_LEN {
+                return Err(ServerError::InvalidValue(format!(
+                    "Values can be at most {} bytes long.",
+                    MAX_VALUE_LEN
+                )));
+            }
+            let is_new = db
+                .get_value(&namespace, user_id, &key)
+                .map_err(load_failure)?
+                .is_none();
+            if is_new && db.values(&namespace, user_id).map_err(load_failure)?.len() >= MAX_KEYS {
+                return Err(ServerError::InvalidRequest(format!(
+                    "A user can have at most {} keys in a namespace.",
+                    MAX_KEYS
+                )));
+            }
+            db.set_value(&namespace, user_id, &key, &value)
+                .map_err(save_failure)?;
+            Ok(Response::Success)
+        }
+        Request::DeleteValue {
+            namespace,
+            user_id,
+            key,
+        } => {
+            check_namespace(&namespace)?;
+            check_key(&key)?;
+            db.delete_value(&namespace, user_id, &key)
+                .map_err(save_failure)?;
+            Ok(Response::Success)
+        }
+        Request::ListValues { namespace, user_id } => {
+            check_namespace(&namespace)?;
+            let values = db.values(&namespace, user_id).map_err(load_failure)?;
+            Ok(Response::Values(values))
+        }
+        _ => Err(ServerError::InvalidRequest(
+            "Not a key/value store request.".to_string(),
+        )),
+    }
+}
+
+fn check_namespace(namespace: &str) -> Result<(), ServerError> {
+    if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+        return Err(ServerError::InvalidRequest(format!(
+            "Namespaces must be 1 to {} bytes long.",
+            MAX_NAMESPACE_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn check_key(key: &str) -> Result<(), ServerError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(ServerError::InvalidRequest(format!(
+            "Keys must be 1 to {} bytes long.",
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn set(db: &mut MemoryStorage, key: &str, value: &str) -> Result<Response, ServerError> {
+        let request = Request::SetValue {
+            namespace: "quests".to_string(),
+            user_id: 1,
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        process(request, db)
+    }
+
+    fn get(db: &mut MemoryStorage, key: &str) -> Option<String> {
+        let request = Request::GetValue {
+            namespace: "quests".to_string(),
+            user_id: 1,
+            key: key.to_string(),
+        };
+        match process(request, db) {
+            Ok(Response::Value(value)) => value,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_keys_and_values() {
+        let mut db = MemoryStorage::new();
+
+        assert!(matches!(
+            set(&mut db, &"k".repeat(MAX_KEY_LEN + 1), "v"),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            set(&mut db, "", "v"),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            set(&mut db, "key", &"v".repeat(MAX_VALUE_LEN + 1)),
+            Err(ServerError::InvalidValue(_))
+        ));
+        let request = Request::ListValues {
+            namespace: "n".repeat(MAX_NAMESPACE_LEN + 1),
+            user_id: 1,
+        };
+        assert!(matches!(
+            process(request, &mut db),
+            Err(ServerError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn limits_the_keys_per_namespace_but_not_overwrites() {
+        let mut db = MemoryStorage::new();
+        for i in 0..MAX_KEYS {
+            assert!(set(&mut db, &format!("key{}", i), "v").is_ok());
+        }
+
+        assert!(matches!(
+            set(&mut db, "one_too_many", "v"),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(set(&mut db, "key0", "changed").is_ok());
+        assert_eq!(get(&mut db, "key0").as_deref(), Some("changed"));
+        assert_eq!(get(&mut db, "one_too_many"), None);
+    }
+}
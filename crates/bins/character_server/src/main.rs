@@ -1,19 +1,53 @@
+use aw_db::DatabaseConfig;
 use bytes::Bytes;
-use character::{Notification, Request, Response, ServerMessage, StatBar};
+use character::protocol::{
+    capability, negotiate_version, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION,
+};
+use character::{
+    Character, ClientMessage, Hello, HelloReply, Notification, Op, OperationId, Request, Response,
+    Scope, ServerError, ServerMessage, StatBar, StatValue,
+};
 use clap::Parser;
 use log::{error, info, warn};
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{Interval, MissedTickBehavior};
 
+mod admin;
+mod auth;
+use auth::{Authenticator, Session};
 mod config;
-use config::CharacterServerConfig;
-mod database;
-use database::MiuchizDatabase;
+use config::{CharacterServerConfig, StorageKind};
+mod idempotency;
+use idempotency::OperationCache;
+mod items;
+use items::ItemRegistry;
+mod key_values;
+mod metrics;
+use metrics::METRICS;
+mod notifications;
+use notifications::NotificationHub;
+mod scheduler;
+use scheduler::StatScheduler;
+mod stats;
+use stats::{StatDefinition, StatRegistry};
+mod storage;
+use storage::{CreditzChange, MemoryStorage, SqlStorage, Storage, StorageError};
+
+/// The most users a single `GetCharacters` request may ask for.
+const MAX_CHARACTERS_PER_REQUEST: usize = 256;
+/// The most ledger entries a single `GetCreditzHistory` request may ask for.
+const MAX_HISTORY_PAGE: u32 = 500;
+/// The longest reason that can be recorded with a creditz change, in bytes.
+const MAX_REASON_LEN: usize = 255;
+/// How many undecodable frames in a row a client may send before it is disconnected.
+const MAX_BAD_FRAMES: u32 = 16;
 
 // =================================================================================================
 //                                     COMMAND LINE ARGUMENTS
@@ -24,16 +58,55 @@ struct Args {
     /// Path to the TOML configuration file.
     #[arg(short, long)]
     config: PathBuf,
+    /// Print the database migrations that are waiting to be applied, then exit without
+    /// applying them.
+    #[arg(long)]
+    dry_run_migrations: bool,
 }
 
 // =================================================================================================
 //                                         SERVER STATE
 // =================================================================================================
 
-/// A map of connected client addresses to a sender for their dedicated message channel.
-type ClientMap = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>>;
-/// The shared, thread-safe database connection, protected by a Tokio Mutex.
-type Db = Arc<Mutex<MiuchizDatabase>>;
+/// Delivers notifications to subscribed clients, and keeps recent ones for replay.
+type Notifier = Arc<Mutex<NotificationHub>>;
+/// The shared storage backend, protected by a Tokio Mutex.
+type Db = Arc<Mutex<Box<dyn Storage>>>;
+/// Responses to recently applied mutating requests, keyed by client identity.
+type Operations = Arc<Mutex<OperationCache>>;
+
+/// The stats and items declared in the server configuration.
+struct Registry {
+    stats: StatRegistry,
+    items: ItemRegistry,
+}
+
+/// Where requests are applied, and where the changes they make are announced.
+#[derive(Clone)]
+struct Backend {
+    db: Db,
+    notifier: Notifier,
+    registry: Arc<Registry>,
+}
+
+/// What every connection shares with the rest of the server.
+#[derive(Clone)]
+struct Shared {
+    backend: Backend,
+    operations: Operations,
+    authenticator: Arc<Authenticator>,
+    limits: ConnectionLimits,
+    /// How often to ping clients that support heartbeats. `None` never pings.
+    ping_interval: Option<Duration>,
+}
+
+/// Limits on what one connection may take up.
+#[derive(Debug, Clone, Copy)]
+struct ConnectionLimits {
+    max_frame_size: u32,
+    /// How long the next frame may take to arrive in full. `None` waits forever.
+    idle_timeout: Option<Duration>,
+}
 
 // =================================================================================================
 //                                          ENTRYPOINT
@@ -45,34 +118,139 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     let config = toml::from_str::<CharacterServerConfig>(&std::fs::read_to_string(args.config)?)?;
 
+    if args.dry_run_migrations {
+        return dry_run_migrations(config.database);
+    }
+
+    if config.credentials.is_empty() && config.anonymous_scopes.is_empty() {
+        warn!("No credentials or anonymous scopes are configured; every request will be refused.");
+    }
+    let stats = StatRegistry::new(config.stats)?;
+    let items = ItemRegistry::new(config.items, &stats)?;
+    let scheduler = StatScheduler::new(config.stat_tick_secs, config.stat_rates, &stats)?;
+    let registry = Arc::new(Registry { stats, items });
+    let authenticator = Arc::new(Authenticator::new(
+        config.credentials,
+        config.anonymous_scopes,
+    ));
+
+    let limits = ConnectionLimits {
+        max_frame_size: config.max_frame_size,
+        idle_timeout: (config.idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.idle_timeout_secs)),
+    };
+    let ping_interval =
+        (config.ping_interval_secs > 0).then(|| Duration::from_secs(config.ping_interval_secs));
+    let max_connections = config.max_connections;
+    let connection_slots = Arc::new(Semaphore::new(max_connections));
+
     let addr = format!("{}:{}", config.host, config.port);
     let listener = TcpListener::bind(&addr).await?;
     info!("Character server listening on {}", addr);
 
-    // Initialize the real database connection from the config.
-    info!("Connecting to database...");
-    let db = MiuchizDatabase::new(config.database);
-    let db = Arc::new(Mutex::new(db)); // Wrap in Arc<Mutex> for thread safety
-
-    info!("Database connection successful.");
+    let storage: Box<dyn Storage> = match (config.storage, config.database) {
+        (StorageKind::Database, Some(database)) => {
+            info!("Connecting to database...");
+            let storage = SqlStorage::new(database)?;
+            info!("Database connection successful.");
+            Box::new(storage)
+        }
+        (StorageKind::Database, None) => {
+            return Err(
+                "No database is configured. To keep stats in memory instead, and lose \
+                        them on shutdown, set storage = 'memory'."
+                    .into(),
+            );
+        }
+        (StorageKind::Memory, _) => {
+            warn!("Stats are kept in memory and lost on shutdown.");
+            Box::new(MemoryStorage::new())
+        }
+    };
+    let db = Db::new(Mutex::new(storage));
 
     // Initialize shared state for clients
-    let clients = ClientMap::new(Mutex::new(HashMap::new()));
+    let notifier = Notifier::new(Mutex::new(NotificationHub::new(
+        config.replay_buffer_size,
+        config.notification_queue,
+    )));
+    let operations = Operations::new(Mutex::new(OperationCache::new()));
 
+    if !scheduler.is_empty() {
+        tokio::spawn(scheduler.run(db.clone(), notifier.clone()));
+    }
+    let backend = Backend {
+        db,
+        notifier,
+        registry,
+    };
+    if let Some(admin_api) = config.admin_api {
+        admin::start(admin_api, backend.clone(), authenticator.clone()).await?;
+    }
+
+    let shared = Shared {
+        backend,
+        operations,
+        authenticator,
+        limits,
+        ping_interval,
+    };
     loop {
         let (stream, addr) = listener.accept().await?;
-        let clients_clone = clients.clone();
-        let db_clone = db.clone();
+        // Dropping the stream closes it, before anything has been read from it.
+        let Ok(slot) = connection_slots.clone().try_acquire_owned() else {
+            warn!(
+                "Refusing connection from {}: {} clients are already connected.",
+                addr, max_connections
+            );
+            METRICS.connection_rejected();
+            continue;
+        };
+        let shared = shared.clone();
 
         tokio::spawn(async move {
+            // The slot is freed when the connection ends, however it ends.
+            let _slot = slot;
             info!("Accepted connection from: {}", addr);
-            if let Err(e) = handle_connection(stream, addr, clients_clone, db_clone).await {
+            if let Err(e) = handle_connection(stream, addr, shared).await {
                 error!("Error handling connection from {}: {}", addr, e);
             }
         });
     }
 }
 
+/// Prints what starting the server would do to the database schema.
+fn dry_run_migrations(database: Option<DatabaseConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(database) = database else {
+        println!("No database is configured; there is nothing to migrate.");
+        return Ok(());
+    };
+    let sql = SqlStorage::connect(database)?;
+    let version = sql.schema_version()?;
+    let pending = sql.pending_migrations()?;
+    println!(
+        "{:?} database at schema version {}.",
+        sql.dialect(),
+        version
+    );
+    if pending.is_empty() {
+        println!("The schema is up to date.");
+    }
+    if version == 0 {
+        println!("\n{};", storage::migrations::CREATE_SCHEMA_VERSION);
+    }
+    for migration in pending {
+        println!(
+            "\nMigration {}: {}",
+            migration.version, migration.description
+        );
+        for statement in migration.statements(sql.dialect()) {
+            println!("{};", statement);
+        }
+    }
+    Ok(())
+}
+
 // =================================================================================================
 //                                       CONNECTION HANDLING
 // =================================================================================================
@@ -80,47 +258,236 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn handle_connection(
     stream: TcpStream,
     addr: SocketAddr,
-    clients: ClientMap,
-    db: Db,
+    shared: Shared,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let Shared {
+        backend,
+        operations,
+        authenticator,
+        limits,
+        ping_interval,
+    } = shared;
+    let notifier = &backend.notifier;
+    let _connected = METRICS.client_connected();
     let (mut reader, mut writer) = tokio::io::split(stream);
+
+    // Agree on a protocol version before anything else is exchanged.
+    let hello: Hello = bincode::deserialize(&read_frame(&mut reader, limits).await?)?;
+    if hello.magic != PROTOCOL_MAGIC {
+        warn!("Closing connection from {}: not a character client.", addr);
+        return Ok(());
+    }
+    let Some(protocol_version) = negotiate_version(
+        PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION,
+        hello.protocol_version,
+        hello.min_protocol_version,
+    ) else {
+        warn!(
+            "Rejecting {}: it speaks protocol versions {}-{}, we speak {}-{}.",
+            addr,
+            hello.min_protocol_version,
+            hello.protocol_version,
+            MIN_PROTOCOL_VERSION,
+            PROTOCOL_VERSION
+        );
+        let reply = HelloReply::Rejected {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        };
+        write_frame(&mut writer, &bincode::serialize(&reply)?).await?;
+        return Ok(());
+    };
+    let capabilities: Vec<String> = capability::all()
+        .into_iter()
+        .filter(|c| hello.capabilities.contains(c))
+        .collect();
+    info!(
+        "Client {} uses protocol version {} with capabilities {:?}",
+        addr, protocol_version, capabilities
+    );
+    let mut heartbeat = ping_interval
+        .filter(|_| capabilities.iter().any(|c| c == capability::HEARTBEAT))
+        .map(|period| {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+    let mut ping_nonce = 0;
+    let reply = HelloReply::Welcome {
+        protocol_version,
+        capabilities,
+    };
+    write_frame(&mut writer, &bincode::serialize(&reply)?).await?;
+
     let (tx, mut rx) = mpsc::channel::<Bytes>(32);
+    let mut session = authenticator.anonymous_session();
+
+    // Register the new client for notifications. It receives none until it subscribes.
+    let notifications = notifier.lock().await.register(addr);
 
-    // Add the new client's message sender to the shared map.
-    clients.lock().await.insert(addr, tx.clone());
+    // Frames are read on their own task, so that one is never abandoned half-read while a
+    // response or notification is written. Dropping the set stops the task, however this
+    // function returns.
+    let (frame_tx, mut frames) = mpsc::channel(32);
+    let mut frame_reader = JoinSet::new();
+    frame_reader.spawn(async move {
+        loop {
+            let frame = read_frame(&mut reader, limits).await;
+            let failed = frame.is_err();
+            if frame_tx.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
 
+    // Requests that change state are applied one at a time, in the order they arrived, so a
+    // client's writes land in the order it sent them. The queue is unbounded because the
+    // worker waits on `tx`, which only this loop drains.
+    let (writes_tx, mut writes) = mpsc::unbounded_channel::<(ClientMessage, Session)>();
+    let mut write_worker = JoinSet::new();
+    {
+        let tx = tx.clone();
+        let backend = backend.clone();
+        let operations = operations.clone();
+        write_worker.spawn(async move {
+            while let Some((message, session)) = writes.recv().await {
+                let result = handle_request(
+                    message,
+                    session,
+                    tx.clone(),
+                    backend.clone(),
+                    operations.clone(),
+                )
+                .await;
+                if let Err(e) = result {
+                    error!("Error handling request from {}: {}", addr, e);
+                }
+            }
+        });
+    }
+
+    let mut bad_frames = 0;
     loop {
         tokio::select! {
             // Read data from the client's socket
-            result = reader.read_u32() => {
-                let len = match result {
-                    Ok(len) => len,
-                    Err(_) => break, // Client disconnected
+            frame = frames.recv() => {
+                let buffer = match frame {
+                    Some(Ok(buffer)) => buffer,
+                    // Client disconnected
+                    Some(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    Some(Err(e)) => {
+                        warn!("Closing connection from {}: {}", addr, e);
+                        break;
+                    }
+                    None => break,
                 };
 
-                let mut buffer = vec![0; len as usize];
-                if reader.read_exact(&mut buffer).await.is_err() {
-                    break; // Client disconnected
-                }
+                let message: ClientMessage = match bincode::deserialize(&buffer) {
+                    Ok(message) => {
+                        bad_frames = 0;
+                        message
+                    }
+                    Err(e) => {
+                        // The frame was read whole, so the stream is still in step and the
+                        // connection can carry on, unless the client sends nothing but these.
+                        METRICS.frame_rejected("undecodable");
+                        bad_frames += 1;
+                        if bad_frames >= MAX_BAD_FRAMES {
+                            warn!(
+                                "Closing connection from {}: {} undecodable frames in a row.",
+                                addr, bad_frames
+                            );
+                            break;
+                        }
+                        // Answer the request with an error if it can be told which one it
+                        // was, e.g. one from a newer client that this build doesn't know.
+                        let Some(request_id) = ClientMessage::peek_request_id(&buffer) else {
+                            warn!("Skipping undecodable frame from {}: {}", addr, e);
+                            continue;
+                        };
+                        warn!("Could not decode request {} from {}: {}", request_id, addr, e);
+                        let payload = bincode::serialize(&ServerMessage::Response {
+                            request_id,
+                            response: Response::Error(ServerError::InvalidRequest(format!(
+                                "Could not decode the request: {}",
+                                e
+                            ))),
+                        })?;
+                        if write_frame(&mut writer, &payload).await.is_err() {
+                            break; // Failed to write to client
+                        }
+                        continue;
+                    }
+                };
+                let message = match message {
+                    ClientMessage::Ping { nonce } => {
+                        let payload = bincode::serialize(&ServerMessage::Pong { nonce })?;
+                        if write_frame(&mut writer, &payload).await.is_err() {
+                            break; // Failed to write to client
+                        }
+                        continue;
+                    }
+                    // Arriving at all was the point.
+                    ClientMessage::Pong { .. } => continue,
+                    message => message,
+                };
 
-                // Process the request using the real database.
-                let request: Request = bincode::deserialize(&buffer)?;
-                let db_clone = db.clone();
-                let (response, notification) =
-                    tokio::task::spawn_blocking(move || process_request(request, &db_clone))
-                        .await?;
-
-                // Send the direct response back to the requester via its channel.
-                let response_payload = bincode::serialize(&ServerMessage::Response(response))?;
-                if tx.send(response_payload.into()).await.is_err() {
-                    break; // Channel closed
+                // Requests that change the connection's own state are handled here, in order,
+                // rather than on their own task, so they apply to every request that follows.
+                if let ClientMessage::Request {
+                    request_id,
+                    request:
+                        request @ (Request::Authenticate(_)
+                        | Request::Subscribe { .. }
+                        | Request::Unsubscribe { .. }
+                        | Request::Resume { .. }),
+                    ..
+                } = &message
+                {
+                    let started = Instant::now();
+                    let response =
+                        handle_session_request(request, &mut session, &authenticator, &backend.registry.items, addr, notifier)
+                            .await?;
+                    METRICS.observe_request(metrics::request_type(request), &response, started.elapsed());
+                    let payload = bincode::serialize(&ServerMessage::Response {
+                        request_id: *request_id,
+                        response,
+                    })?;
+                    if write_frame(&mut writer, &payload).await.is_err() {
+                        break; // Failed to write to client
+                    }
+                    continue;
                 }
 
-                // If there was a state change, broadcast the notification to all clients.
-                if let Some(notif) = notification {
-                    let notif_payload = bincode::serialize(&ServerMessage::Notification(notif))?;
-                    broadcast_notification(&clients, &notif_payload.into()).await;
+                if let ClientMessage::Request { request, .. } = &message {
+                    if request.is_mutating() {
+                        // The worker has stopped only if the connection is closing anyway.
+                        let _ = writes_tx.send((message, session.clone()));
+                        continue;
+                    }
                 }
+
+                // Process each read on its own task, so a client can have several in flight.
+                // Responses may finish out of order; the request ID matches them up.
+                let session_clone = session.clone();
+                let tx_clone = tx.clone();
+                let backend_clone = backend.clone();
+                let operations_clone = operations.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_request(
+                        message,
+                        session_clone,
+                        tx_clone,
+                        backend_clone,
+                        operations_clone,
+                    )
+                    .await
+                    {
+                        error!("Error handling request from {}: {}", addr, e);
+                    }
+                });
             },
             // Receive messages from other tasks to be written to this client's socket
             Some(payload) = rx.recv() => {
@@ -128,217 +495,821 @@ async fn handle_connection(
                     break; // Failed to write to client
                 }
             }
+            payload = notifications.pop() => {
+                let Some(payload) = payload else {
+                    break; // Disconnected for falling behind
+                };
+                if write_frame(&mut writer, &payload).await.is_err() {
+                    break; // Failed to write to client
+                }
+            }
+            _ = tick(&mut heartbeat) => {
+                ping_nonce += 1;
+                let payload = bincode::serialize(&ServerMessage::Ping { nonce: ping_nonce })?;
+                if write_frame(&mut writer, &payload).await.is_err() {
+                    break; // Failed to write to client
+                }
+            }
         }
     }
 
     // On disconnect, remove the client from the map.
     info!("Closing connection from: {}", addr);
-    clients.lock().await.remove(&addr);
+    notifier.lock().await.unregister(&addr);
+    Ok(())
+}
+
+/// Handles a request that changes the connection's session rather than any stats.
+async fn handle_session_request(
+    request: &Request,
+    session: &mut Session,
+    authenticator: &Authenticator,
+    items: &ItemRegistry,
+    addr: SocketAddr,
+    notifier: &Notifier,
+) -> Result<Response, bincode::Error> {
+    if let Request::Authenticate(token) = request {
+        let Some(new_session) = authenticator.authenticate(token) else {
+            warn!("Client {} presented an unknown token.", addr);
+            return Ok(Response::Error(ServerError::Unauthorized));
+        };
+        info!(
+            "Client {} authenticated as {:?} with scopes {:?}",
+            addr,
+            new_session.name,
+            new_session.scopes()
+        );
+        *session = new_session;
+        let mut notifier_lock = notifier.lock().await;
+        notifier_lock.configure_queue(&addr, session.notification_queue);
+        // Notifications carry stat values, so they are only for clients that may read them.
+        if !session.has_scope(Scope::Read) {
+            notifier_lock.clear_subscriptions(&addr);
+        }
+        return Ok(Response::Authenticated(session.scopes().to_vec()));
+    }
+
+    if let Err(e) = session.authorize(request, items) {
+        return Ok(Response::Error(e));
+    }
+    let mut notifier_lock = notifier.lock().await;
+    if let Request::Resume { last_sequence } = request {
+        return Ok(if notifier_lock.resume(&addr, *last_sequence)? {
+            Response::Resumed
+        } else {
+            info!(
+                "Client {} missed notifications that are no longer buffered.",
+                addr
+            );
+            Response::ResyncRequired
+        });
+    }
+    let sequence = notifier_lock.update_subscriptions(&addr, request);
+    Ok(Response::Subscribed { sequence })
+}
+
+/// Processes one request and sends the response back to the requester. Any resulting
+/// notifications have been broadcast to every subscribed client by then.
+async fn handle_request(
+    message: ClientMessage,
+    session: Session,
+    tx: mpsc::Sender<Bytes>,
+    backend: Backend,
+    operations: Operations,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let ClientMessage::Request {
+        request_id,
+        operation_id,
+        reason,
+        request,
+    } = message
+    else {
+        unreachable!("pings and pongs are answered by the connection");
+    };
+
+    // Process the request using the real database.
+    let attribution = Attribution {
+        reason,
+        source: session.name.clone(),
+    };
+    let (response, _) = tokio::task::spawn_blocking(move || {
+        process_operation(
+            operation_id,
+            request,
+            &attribution,
+            &session,
+            &backend,
+            &operations,
+        )
+    })
+    .await?;
+
+    // Send the direct response back to the requester via its channel. If the requester has
+    // disconnected, the change still happened and has been announced regardless.
+    let response_payload = bincode::serialize(&ServerMessage::Response {
+        request_id,
+        response,
+    })?;
+    let _ = tx.send(response_payload.into()).await;
     Ok(())
 }
 
-fn process_request(request: Request, db: &Db) -> (Response, Option<Notification>) {
+/// Processes a request at most once per session and `OperationId`.
+///
+/// A duplicate is answered with the response recorded for the original, and no notification
+/// is sent, since the original already produced one. A request the session may not make is
+/// rejected before the cache is consulted, so it can't learn another's responses.
+fn process_operation(
+    operation_id: Option<OperationId>,
+    request: Request,
+    attribution: &Attribution,
+    session: &Session,
+    backend: &Backend,
+    operations: &Operations,
+) -> (Response, Vec<Notification>) {
+    let Some(operation_id) =
+        operation_id.filter(|_| session.authorize(&request, &backend.registry.items).is_ok())
+    else {
+        return process_request(request, attribution, session, backend);
+    };
+    let session_name = session.name.as_deref();
+
+    // Hold the cache lock while applying the request, so that a resend arriving on a new
+    // connection cannot slip in before the original has been recorded.
+    let mut operations_lock = operations.blocking_lock();
+    if let Some(response) = operations_lock.get(session_name, &operation_id) {
+        info!(
+            "Ignoring duplicate operation {:?}; replaying original response.",
+            operation_id
+        );
+        return (response.clone(), Vec::new());
+    }
+
+    let (response, notifications) = process_request(request, attribution, session, backend);
+    operations_lock.insert(session_name, operation_id, response.clone());
+    (response, notifications)
+}
+
+/// Processes a request, counting it and timing it, and announces the changes it made.
+fn process_request(
+    request: Request,
+    attribution: &Attribution,
+    session: &Session,
+    backend: &Backend,
+) -> (Response, Vec<Notification>) {
+    let request_type = metrics::request_type(&request);
+    let started = Instant::now();
     // Lock the mutex to gain exclusive access to the database.
-    let db_lock = db.blocking_lock();
-
-    let user_id = match &request {
-        Request::GetCreditz(id)
-        | Request::SetCreditz(id, _)
-        | Request::AddCreditz(id, _)
-        | Request::SubtractCreditz(id, _)
-        | Request::GetHappiness(id)
-        | Request::SetHappiness(id, _)
-        | Request::GetHunger(id)
-        | Request::SetHunger(id, _)
-        | Request::GetBoredom(id)
-        | Request::SetBoredom(id, _) => *id,
+    let mut db_lock = backend.db.blocking_lock();
+    let (response, notifications) = apply_request(
+        request,
+        attribution,
+        session,
+        db_lock.as_mut(),
+        &backend.registry,
+    );
+    METRICS.observe_request(request_type, &response, started.elapsed());
+    publish(&backend.notifier, &notifications);
+    (response, notifications)
+}
+
+/// Announces changes to every subscribed client. Callers hold the database lock, so that
+/// changes are announced in the order they were made, and a stale value is never heard last.
+fn publish(notifier: &Notifier, notifications: &[Notification]) {
+    if notifications.is_empty() {
+        return;
+    }
+    let mut notifier_lock = notifier.blocking_lock();
+    for notification in notifications {
+        if let Err(e) = notifier_lock.publish(notification.clone()) {
+            error!("Failed to publish notification: {}", e);
+        }
+    }
+}
+
+/// Who made a request and why, recorded with any creditz change it makes.
+struct Attribution {
+    reason: Option<String>,
+    /// The name of the credential the request was made with.
+    source: Option<String>,
+}
+
+fn apply_request(
+    request: Request,
+    attribution: &Attribution,
+    session: &Session,
+    db_lock: &mut dyn Storage,
+    registry: &Registry,
+) -> (Response, Vec<Notification>) {
+    if let Err(e) = session.authorize(&request, &registry.items) {
+        warn!(
+            "Refusing {:?} from {:?}: missing scope.",
+            request, session.name
+        );
+        return (Response::Error(e), Vec::new());
+    }
+    if let Some(reason) = &attribution.reason {
+        if reason.len() > MAX_REASON_LEN {
+            return (
+                Response::Error(ServerError::InvalidRequest(format!(
+                    "Reasons can be at most {} bytes long.",
+                    MAX_REASON_LEN
+                ))),
+                Vec::new(),
+            );
+        }
+    }
+
+    let op = match request {
+        Request::Transaction(ops) => {
+            return process_transaction(ops, attribution, registry, db_lock)
+        }
+        Request::GetCharacter(id) => {
+            let response = match load_character(id, db_lock) {
+                Ok(character) => Response::Character(character),
+                Err(e) => Response::Error(e),
+            };
+            return (response, Vec::new());
+        }
+        Request::GetCharacters(ids) => {
+            if ids.len() > MAX_CHARACTERS_PER_REQUEST {
+                return (
+                    Response::Error(ServerError::InvalidRequest(format!(
+                        "At most {} characters can be requested at once.",
+                        MAX_CHARACTERS_PER_REQUEST
+                    ))),
+                    Vec::new(),
+                );
+            }
+            let response = match ids
+                .into_iter()
+                .map(|id| load_character(id, db_lock))
+                .collect()
+            {
+                Ok(characters) => Response::Characters(characters),
+                Err(e) => Response::Error(e),
+            };
+            return (response, Vec::new());
+        }
+        Request::GetCreditzHistory {
+            user_id,
+            before,
+            limit,
+        } => {
+            if limit > MAX_HISTORY_PAGE {
+                return (
+                    Response::Error(ServerError::InvalidRequest(format!(
+                        "At most {} ledger entries can be requested at once.",
+                        MAX_HISTORY_PAGE
+                    ))),
+                    Vec::new(),
+                );
+            }
+            let response = match db_lock.creditz_history(user_id, before, limit) {
+                Ok(entries) => Response::CreditzHistory(entries),
+                Err(e) => Response::Error(load_failure(e)),
+            };
+            return (response, Vec::new());
+        }
+        Request::TransferCreditz { from, to, amount } => {
+            return process_transfer(from, to, amount, attribution, registry, db_lock)
+        }
+        Request::ListStats => return (Response::StatList(registry.stats.list()), Vec::new()),
+        request @ (Request::GetValue { .. }
+        | Request::SetValue { .. }
+        | Request::DeleteValue { .. }
+        | Request::ListValues { .. }) => {
+            let response = key_values::process(request, db_lock).unwrap_or_else(Response::Error);
+            return (response, Vec::new());
+        }
+        Request::GetInventory(user_id) => {
+            let response = match db_lock.inventory(user_id) {
+                Ok(items) => Response::Inventory(items),
+                Err(e) => Response::Error(load_failure(e)),
+            };
+            return (response, Vec::new());
+        }
+        Request::GetCreditz(id) => Op::GetCreditz(id),
+        Request::SetCreditz(id, value) => Op::SetCreditz(id, value),
+        Request::AddCreditz(id, amount) => Op::AddCreditz(id, amount),
+        Request::SubtractCreditz(id, amount) => Op::SubtractCreditz(id, amount),
+        Request::GetHappiness(id) => Op::GetHappiness(id),
+        Request::SetHappiness(id, value) => Op::SetHappiness(id, value),
+        Request::AddHappiness(id, delta) => Op::AddHappiness(id, delta),
+        Request::GetHunger(id) => Op::GetHunger(id),
+        Request::SetHunger(id, value) => Op::SetHunger(id, value),
+        Request::AddHunger(id, delta) => Op::AddHunger(id, delta),
+        Request::GetBoredom(id) => Op::GetBoredom(id),
+        Request::SetBoredom(id, value) => Op::SetBoredom(id, value),
+        Request::AddBoredom(id, delta) => Op::AddBoredom(id, delta),
+        Request::GetStat { user_id, stat } => Op::GetStat { user_id, stat },
+        Request::SetStat {
+            user_id,
+            stat,
+            value,
+        } => Op::SetStat {
+            user_id,
+            stat,
+            value,
+        },
+        Request::AddStat {
+            user_id,
+            stat,
+            delta,
+        } => Op::AddStat {
+            user_id,
+            stat,
+            delta,
+        },
+        Request::GrantItem {
+            user_id,
+            item,
+            quantity,
+        } => Op::GrantItem {
+            user_id,
+            item,
+            quantity,
+        },
+        Request::ConsumeItem {
+            user_id,
+            item,
+            quantity,
+        } => Op::ConsumeItem {
+            user_id,
+            item,
+            quantity,
+        },
+        Request::CountItem { user_id, item } => Op::CountItem { user_id, item },
+        Request::Authenticate(_)
+        | Request::Subscribe { .. }
+        | Request::Unsubscribe { .. }
+        | Request::Resume { .. } => {
+            return (
+                Response::Error(ServerError::InvalidRequest(
+                    "Session requests are handled by the connection.".to_string(),
+                )),
+                Vec::new(),
+            )
+        }
     };
 
-    // Every operation should ensure the user exists in the database first.
-    if let database::DatabaseResult::DatabaseError = db_lock.init_player_if_not_exists(user_id) {
+    // A change is applied in a transaction, so that it can't be stored without its ledger
+    // entry or vice versa.
+    let result = if op.is_mutating() {
+        in_transaction(db_lock, |db| apply_op(op, attribution, registry, db))
+    } else {
+        apply_op(op, attribution, registry, db_lock)
+    };
+    match result {
+        Ok((response, notifications)) => (response, notifications),
+        Err(e) => (Response::Error(e), Vec::new()),
+    }
+}
+
+/// Applies every operation inside one database transaction, all-or-nothing.
+///
+/// Notifications are only returned once the transaction has committed, so clients never hear
+/// about changes that were rolled back.
+fn process_transaction(
+    ops: Vec<Op>,
+    attribution: &Attribution,
+    registry: &Registry,
+    db_lock: &mut dyn Storage,
+) -> (Response, Vec<Notification>) {
+    let result = in_transaction(db_lock, |db| {
+        ops.into_iter()
+            .map(|op| apply_op(op, attribution, registry, db))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    match result {
+        Ok(results) => {
+            let (responses, notifications): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+            (
+                Response::Transaction(responses),
+                notifications.into_iter().flatten().collect(),
+            )
+        }
+        Err(e) => (Response::Error(e), Vec::new()),
+    }
+}
+
+/// Moves creditz between two users in one database transaction, notifying both.
+fn process_transfer(
+    from: u32,
+    to: u32,
+    amount: u32,
+    attribution: &Attribution,
+    registry: &Registry,
+    db_lock: &mut dyn Storage,
+) -> (Response, Vec<Notification>) {
+    if from == to {
         return (
-            Response::Error("Database error: Could not initialize player.".to_string()),
-            None,
+            Response::Error(ServerError::InvalidRequest(
+                "Cannot transfer creditz to the same user.".to_string(),
+            )),
+            Vec::new(),
         );
     }
 
-    match request {
-        Request::GetCreditz(user_id) => match db_lock.get_stats(user_id) {
-            database::DatabaseResult::Ok(stats) => (Response::Creditz(stats.creditz), None),
-            database::DatabaseResult::DatabaseError => (
-                Response::Error("Database error: Failed to retrieve stats.".to_string()),
-                None,
-            ),
-        },
-        Request::SetCreditz(user_id, value) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
+    let result = in_transaction(db_lock, |db| {
+        let (_, debited) = apply_op(Op::SubtractCreditz(from, amount), attribution, registry, db)?;
+        let (_, credited) = apply_op(Op::AddCreditz(to, amount), attribution, registry, db)?;
+        Ok(debited.into_iter().chain(credited).collect())
+    });
+    match result {
+        Ok(notifications) => (Response::Success, notifications),
+        Err(e) => (Response::Error(e), Vec::new()),
+    }
+}
+
+/// Runs `f` inside a database transaction, committing if it succeeds and rolling back if it
+/// fails.
+fn in_transaction<T>(
+    db_lock: &mut dyn Storage,
+    f: impl FnOnce(&mut dyn Storage) -> Result<T, ServerError>,
+) -> Result<T, ServerError> {
+    if let Err(e) = db_lock.begin_transaction() {
+        METRICS.storage_error("transaction");
+        error!("Could not begin transaction: {}", e);
+        return Err(ServerError::StorageFailure(
+            "Could not begin transaction.".to_string(),
+        ));
+    }
+
+    let value = match f(db_lock) {
+        Ok(value) => value,
+        Err(e) => {
+            if let Err(rollback_error) = db_lock.rollback_transaction() {
+                METRICS.storage_error("transaction");
+                error!(
+                    "Failed to roll back transaction after error {}: {}",
+                    e, rollback_error
+                );
+            }
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = db_lock.commit_transaction() {
+        METRICS.storage_error("transaction");
+        error!("Could not commit transaction: {}", e);
+        if let Err(e) = db_lock.rollback_transaction() {
+            error!(
+                "Failed to roll back transaction after a failed commit: {}",
+                e
+            );
+        }
+        return Err(ServerError::StorageFailure(
+            "Could not commit transaction.".to_string(),
+        ));
+    }
+
+    Ok(value)
+}
+
+/// Reads every stat of one user, creating the user first if needed.
+fn load_character(user_id: u32, db_lock: &mut dyn Storage) -> Result<Character, ServerError> {
+    db_lock
+        .init_player_if_not_exists(user_id)
+        .map_err(init_failure)?;
+
+    let stats = db_lock.get_stats(user_id).map_err(load_failure)?;
+    Ok(Character {
+        user_id,
+        creditz: stats.creditz,
+        happiness: stats.happiness,
+        boredom: stats.boredom,
+        hunger: stats.hunger,
+    })
+}
+
+/// Applies a single operation against the database.
+fn apply_op(
+    op: Op,
+    attribution: &Attribution,
+    registry: &Registry,
+    db_lock: &mut dyn Storage,
+) -> Result<(Response, Vec<Notification>), ServerError> {
+    match &op {
+        Op::GetStat { stat, .. } | Op::SetStat { stat, .. } | Op::AddStat { stat, .. } => {
+            let definition = registry.stats.get(stat)?.clone();
+            return apply_named_op(op, &definition, attribution, registry, db_lock);
+        }
+        Op::GrantItem { .. } | Op::ConsumeItem { .. } | Op::CountItem { .. } => {
+            return apply_item_op(op, attribution, registry, db_lock);
+        }
+        _ => {}
+    }
+
+    // Every operation should ensure the user exists in the database first.
+    let user_id = op.user_id();
+    db_lock
+        .init_player_if_not_exists(user_id)
+        .map_err(init_failure)?;
+    let mut stats = db_lock.get_stats(user_id).map_err(load_failure)?;
+    let previous_creditz = stats.creditz;
+
+    let notification = match op {
+        Op::GetCreditz(_) => return Ok((Response::Creditz(stats.creditz), Vec::new())),
+        Op::GetHappiness(_) => return Ok((Response::Happiness(stats.happiness), Vec::new())),
+        Op::GetHunger(_) => return Ok((Response::Hunger(stats.hunger), Vec::new())),
+        Op::GetBoredom(_) => return Ok((Response::Boredom(stats.boredom), Vec::new())),
+        Op::GetStat { .. }
+        | Op::SetStat { .. }
+        | Op::AddStat { .. }
+        | Op::GrantItem { .. }
+        | Op::ConsumeItem { .. }
+        | Op::CountItem { .. } => unreachable!("handled above"),
+        Op::SetCreditz(_, value) => {
             stats.creditz = value;
-            match db_lock.set_stats(user_id, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::CreditzChanged {
-                        user_id,
-                        new_value: value,
-                    }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+            Notification::CreditzChanged {
+                user_id,
+                new_value: value,
             }
         }
-        Request::AddCreditz(user_id, amount) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
-            stats.creditz += amount;
-            let new_value = stats.creditz;
-            match db_lock.set_stats(user_id, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::CreditzChanged { user_id, new_value }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+        Op::AddCreditz(_, amount) => {
+            stats.creditz = stats
+                .creditz
+                .checked_add(amount)
+                .ok_or(ServerError::Overflow)?;
+            Notification::CreditzChanged {
+                user_id,
+                new_value: stats.creditz,
             }
         }
-        Request::SubtractCreditz(user_id, amount) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
+        Op::SubtractCreditz(_, amount) => {
             if stats.creditz < amount {
-                return (Response::Error("Insufficient funds".to_string()), None);
+                return Err(ServerError::InsufficientFunds);
             }
             stats.creditz -= amount;
-            let new_value = stats.creditz;
-            match db_lock.set_stats(user_id as u32, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::CreditzChanged { user_id, new_value }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+            Notification::CreditzChanged {
+                user_id,
+                new_value: stats.creditz,
             }
         }
-        Request::GetHappiness(user_id) => match db_lock.get_stats(user_id) {
-            database::DatabaseResult::Ok(s) => (Response::Happiness(s.happiness), None),
-            database::DatabaseResult::DatabaseError => (Response::Error("DB Error".into()), None),
-        },
-        Request::SetHappiness(user_id, value) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
+        Op::SetHappiness(_, value) => {
+            check_finite(value)?;
             stats.happiness = StatBar::from_f32(value);
-            match db_lock.set_stats(user_id, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::HappinessChanged {
-                        user_id,
-                        new_value: StatBar::from_f32(value),
-                    }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+            Notification::HappinessChanged {
+                user_id,
+                new_value: stats.happiness.clone(),
             }
         }
-        Request::GetHunger(user_id) => match db_lock.get_stats(user_id) {
-            database::DatabaseResult::Ok(s) => (Response::Hunger(s.hunger), None),
-            database::DatabaseResult::DatabaseError => (Response::Error("DB Error".into()), None),
-        },
-        Request::SetHunger(user_id, value) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
+        Op::AddHappiness(_, delta) => {
+            check_finite(delta)?;
+            stats.happiness = stats.happiness.saturating_add(delta);
+            Notification::HappinessChanged {
+                user_id,
+                new_value: stats.happiness.clone(),
+            }
+        }
+        Op::SetHunger(_, value) => {
+            check_finite(value)?;
             stats.hunger = StatBar::from_f32(value);
-            match db_lock.set_stats(user_id, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::HungerChanged {
-                        user_id,
-                        new_value: StatBar::from_f32(value),
-                    }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+            Notification::HungerChanged {
+                user_id,
+                new_value: stats.hunger.clone(),
             }
         }
-        Request::GetBoredom(user_id) => match db_lock.get_stats(user_id) {
-            database::DatabaseResult::Ok(s) => (Response::Boredom(s.boredom), None),
-            database::DatabaseResult::DatabaseError => (Response::Error("DB Error".into()), None),
-        },
-        Request::SetBoredom(user_id, value) => {
-            let mut stats = match db_lock.get_stats(user_id) {
-                database::DatabaseResult::Ok(s) => s,
-                database::DatabaseResult::DatabaseError => {
-                    return (Response::Error("DB Error".into()), None)
-                }
-            };
+        Op::AddHunger(_, delta) => {
+            check_finite(delta)?;
+            stats.hunger = stats.hunger.saturating_add(delta);
+            Notification::HungerChanged {
+                user_id,
+                new_value: stats.hunger.clone(),
+            }
+        }
+        Op::SetBoredom(_, value) => {
+            check_finite(value)?;
             stats.boredom = StatBar::from_f32(value);
-            match db_lock.set_stats(user_id, stats) {
-                database::DatabaseResult::Ok(_) => (
-                    Response::Success,
-                    Some(Notification::BoredomChanged {
-                        user_id,
-                        new_value: StatBar::from_f32(value),
-                    }),
-                ),
-                database::DatabaseResult::DatabaseError => {
-                    (Response::Error("DB Error".into()), None)
-                }
+            Notification::BoredomChanged {
+                user_id,
+                new_value: stats.boredom.clone(),
+            }
+        }
+        Op::AddBoredom(_, delta) => {
+            check_finite(delta)?;
+            stats.boredom = stats.boredom.saturating_add(delta);
+            Notification::BoredomChanged {
+                user_id,
+                new_value: stats.boredom.clone(),
             }
         }
+    };
+
+    db_lock.set_stats(user_id, &stats).map_err(save_failure)?;
+    if stats.creditz != previous_creditz {
+        db_lock
+            .record_creditz_change(&CreditzChange {
+                citizen_id: user_id,
+                timestamp: unix_millis(),
+                delta: i64::from(stats.creditz) - i64::from(previous_creditz),
+                balance: stats.creditz,
+                reason: attribution.reason.clone(),
+                source: attribution.source.clone(),
+            })
+            .map_err(save_failure)?;
     }
+    Ok((Response::Success, vec![notification]))
 }
 
-async fn broadcast_notification(clients: &ClientMap, payload: &Bytes) {
-    let mut dead_clients = Vec::new();
+/// Applies an operation that names its stat. Built-in stats are handed to their own
+/// operations; configured stats are read from and written to the named stats table.
+fn apply_named_op(
+    op: Op,
+    definition: &StatDefinition,
+    attribution: &Attribution,
+    registry: &Registry,
+    db_lock: &mut dyn Storage,
+) -> Result<(Response, Vec<Notification>), ServerError> {
+    if definition.builtin.is_some() {
+        let (response, notifications) =
+            apply_op(definition.builtin_op(op)?, attribution, registry, db_lock)?;
+        let response = match response {
+            Response::Creditz(value) => Response::Stat(StatValue::Integer(value.into())),
+            Response::Happiness(bar) | Response::Hunger(bar) | Response::Boredom(bar) => {
+                Response::Stat(StatValue::Bar(bar))
+            }
+            response => response,
+        };
+        return Ok((response, notifications));
+    }
 
-    // Clone the senders to avoid holding the lock across an .await point.
-    let senders: Vec<(SocketAddr, mpsc::Sender<Bytes>)> = clients
-        .lock()
-        .await
-        .iter()
-        .map(|(addr, tx)| (*addr, tx.clone()))
-        .collect();
+    let user_id = op.user_id();
+    db_lock
+        .init_player_if_not_exists(user_id)
+        .map_err(init_failure)?;
+    let stats = db_lock.get_stats(user_id).map_err(load_failure)?;
+    let current = definition.read(user_id, &stats, db_lock)?;
 
-    // Now, iterate over the cloned senders without holding the lock.
-    for (addr, tx) in senders {
-        if tx.send(payload.clone()).await.is_err() {
-            // The channel is closed, meaning the client's task has terminated.
-            warn!(
-                "Failed to send notification to {}: channel closed. Marking for removal.",
-                addr
-            );
-            dead_clients.push(addr);
+    let new_value = match op {
+        Op::SetStat { value, .. } => value,
+        Op::AddStat { delta, .. } => definition.add(current, delta)?,
+        _ => return Ok((Response::Stat(current), Vec::new())),
+    };
+    let notification = definition.write(user_id, new_value, db_lock)?;
+    Ok((Response::Success, vec![notification]))
+}
+
+/// Applies an operation on a user's inventory. Consuming an item applies its effects as
+/// `AddStat` operations, so they happen in the same transaction as the item's removal.
+fn apply_item_op(
+    op: Op,
+    attribution: &Attribution,
+    registry: &Registry,
+    db_lock: &mut dyn Storage,
+) -> Result<(Response, Vec<Notification>), ServerError> {
+    let user_id = op.user_id();
+    db_lock
+        .init_player_if_not_exists(user_id)
+        .map_err(init_failure)?;
+
+    match op {
+        Op::CountItem { item, .. } => {
+            registry.items.get(&item)?;
+            let count = db_lock.item_count(user_id, &item).map_err(load_failure)?;
+            Ok((Response::ItemCount(count), Vec::new()))
+        }
+        Op::GrantItem { item, quantity, .. } => {
+            registry.items.get(&item)?;
+            check_quantity(quantity)?;
+            let new_count = db_lock
+                .item_count(user_id, &item)
+                .map_err(load_failure)?
+                .checked_add(quantity)
+                .ok_or(ServerError::Overflow)?;
+            db_lock
+                .set_item_count(user_id, &item, new_count)
+                .map_err(save_failure)?;
+            let notification = Notification::ItemGranted {
+                user_id,
+                item,
+                quantity,
+                new_count,
+            };
+            Ok((Response::Success, vec![notification]))
+        }
+        Op::ConsumeItem { item, quantity, .. } => {
+            let definition = registry.items.get(&item)?;
+            check_quantity(quantity)?;
+            let new_count = db_lock
+                .item_count(user_id, &item)
+                .map_err(load_failure)?
+                .checked_sub(quantity)
+                .ok_or(ServerError::NotEnoughItems)?;
+            db_lock
+                .set_item_count(user_id, &item, new_count)
+                .map_err(save_failure)?;
+
+            let mut notifications = vec![Notification::ItemConsumed {
+                user_id,
+                item: item.clone(),
+                quantity,
+                new_count,
+            }];
+            for effect in definition.effect_ops(user_id, quantity)? {
+                let (_, effect_notifications) = apply_op(effect, attribution, registry, db_lock)?;
+                notifications.extend(effect_notifications);
+            }
+            Ok((Response::Success, notifications))
+        }
+        _ => unreachable!("not an item operation"),
+    }
+}
+
+fn init_failure(e: StorageError) -> ServerError {
+    METRICS.storage_error("init");
+    error!("Failed to initialize player: {}", e);
+    ServerError::StorageFailure("Could not initialize player.".to_string())
+}
+
+fn load_failure(e: StorageError) -> ServerError {
+    METRICS.storage_error("load");
+    error!("Failed to load stats: {}", e);
+    ServerError::StorageFailure("Failed to load stats.".to_string())
+}
+
+fn save_failure(e: StorageError) -> ServerError {
+    METRICS.storage_error("save");
+    error!("Failed to save stats: {}", e);
+    ServerError::StorageFailure("Failed to save stats.".to_string())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Rejects granting or consuming no items at all.
+fn check_quantity(quantity: u32) -> Result<(), ServerError> {
+    if quantity > 0 {
+        Ok(())
+    } else {
+        Err(ServerError::InvalidValue(
+            "The quantity must be at least 1.".to_string(),
+        ))
+    }
+}
+
+/// Rejects NaN and infinite stat values, which would otherwise be silently stored as 0.
+fn check_finite(value: f32) -> Result<(), ServerError> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(ServerError::InvalidValue(format!(
+            "{} is not a finite number",
+            value
+        )))
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
         }
+        None => std::future::pending().await,
     }
+}
 
-    // Re-acquire the lock briefly just to clean up any dead clients.
-    if !dead_clients.is_empty() {
-        let mut clients_lock = clients.lock().await;
-        for addr in dead_clients {
-            clients_lock.remove(&addr);
+/// Helper to read a length-prefixed frame asynchronously.
+///
+/// Fails without allocating anything for a frame larger than `limits.max_frame_size`, and
+/// fails if the whole frame doesn't arrive within `limits.idle_timeout`, so that a client
+/// can't hold on to memory or a connection slot by sending too much or too slowly.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    limits: ConnectionLimits,
+) -> Result<Vec<u8>, std::io::Error> {
+    let read = async {
+        let len = reader.read_u32().await?;
+        if len > limits.max_frame_size {
+            METRICS.frame_rejected("too_large");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes is larger than the maximum of {}",
+                    len, limits.max_frame_size
+                ),
+            ));
         }
+        let mut buffer = vec![0; len as usize];
+        reader.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    };
+    match limits.idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "idle for too long",
+                ))
+            }),
+        None => read.await,
     }
 }
 
@@ -351,3 +1322,495 @@ async fn write_frame(
     writer.write_all(payload).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use character::LedgerEntry;
+
+    struct Server {
+        backend: Backend,
+        operations: Operations,
+        session: Session,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Self::with_items(Vec::new())
+        }
+
+        fn with_items(items: Vec<config::ItemConfig>) -> Self {
+            let stats = StatRegistry::new(Vec::new()).unwrap();
+            let items = ItemRegistry::new(items, &stats).unwrap();
+            Self {
+                backend: Backend {
+                    db: Arc::new(Mutex::new(Box::new(MemoryStorage::new()))),
+                    notifier: Arc::new(Mutex::new(NotificationHub::new(
+                        16,
+                        config::NotificationQueueConfig::default(),
+                    ))),
+                    registry: Arc::new(Registry { stats, items }),
+                },
+                operations: Arc::new(Mutex::new(OperationCache::new())),
+                session: Authenticator::new(Vec::new(), vec![Scope::Admin]).anonymous_session(),
+            }
+        }
+
+        fn request(&self, request: Request) -> (Response, Vec<Notification>) {
+            self.operation(None, request)
+        }
+
+        fn operation(
+            &self,
+            operation_id: Option<OperationId>,
+            request: Request,
+        ) -> (Response, Vec<Notification>) {
+            self.operation_as(&self.session, operation_id, request)
+        }
+
+        fn operation_as(
+            &self,
+            session: &Session,
+            operation_id: Option<OperationId>,
+            request: Request,
+        ) -> (Response, Vec<Notification>) {
+            let attribution = Attribution {
+                reason: None,
+                source: None,
+            };
+            process_operation(
+                operation_id,
+                request,
+                &attribution,
+                session,
+                &self.backend,
+                &self.operations,
+            )
+        }
+
+        fn request_because(&self, reason: &str, request: Request) -> Response {
+            let attribution = Attribution {
+                reason: Some(reason.to_string()),
+                source: Some("game".to_string()),
+            };
+            process_request(request, &attribution, &self.session, &self.backend).0
+        }
+
+        fn history(&self, user_id: u32, before: Option<u64>, limit: u32) -> Vec<LedgerEntry> {
+            let request = Request::GetCreditzHistory {
+                user_id,
+                before,
+                limit,
+            };
+            match self.request(request).0 {
+                Response::CreditzHistory(entries) => entries,
+                response => panic!("unexpected response {:?}", response),
+            }
+        }
+
+        fn creditz(&self, user_id: u32) -> u32 {
+            match self.request(Request::GetCreditz(user_id)).0 {
+                Response::Creditz(creditz) => creditz,
+                response => panic!("unexpected response {:?}", response),
+            }
+        }
+
+        fn history_len(&self, user_id: u32) -> usize {
+            self.history(user_id, None, 100).len()
+        }
+    }
+
+    #[test]
+    fn failed_transaction_rolls_back() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 10));
+
+        let (response, notifications) = server.request(Request::Transaction(vec![
+            Op::AddCreditz(1, 5),
+            Op::SubtractCreditz(2, 1),
+        ]));
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InsufficientFunds)
+        ));
+        assert!(notifications.is_empty());
+        assert_eq!(server.creditz(1), 10);
+        assert_eq!(server.history_len(1), 1);
+    }
+
+    #[test]
+    fn subtracting_more_than_the_balance_fails() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 3));
+
+        let (response, notifications) = server.request(Request::SubtractCreditz(1, 4));
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InsufficientFunds)
+        ));
+        assert!(notifications.is_empty());
+        assert_eq!(server.creditz(1), 3);
+    }
+
+    #[test]
+    fn duplicate_operation_replays_its_response() {
+        let server = Server::new();
+        let operation_id = OperationId {
+            client_id: 7,
+            sequence: 0,
+        };
+
+        let (first, notifications) =
+            server.operation(Some(operation_id), Request::AddCreditz(1, 5));
+        assert_eq!(notifications.len(), 1);
+        let (replayed, notifications) =
+            server.operation(Some(operation_id), Request::AddCreditz(1, 5));
+
+        assert_eq!(format!("{:?}", replayed), format!("{:?}", first));
+        assert!(notifications.is_empty());
+        assert_eq!(server.creditz(1), 5);
+        assert_eq!(server.history_len(1), 1);
+    }
+
+    #[test]
+    fn unauthorized_operation_is_not_answered_from_the_cache() {
+        let server = Server::new();
+        let operation_id = OperationId {
+            client_id: 7,
+            sequence: 0,
+        };
+        server.operation(Some(operation_id), Request::AddCreditz(1, 5));
+
+        let reader = Authenticator::new(Vec::new(), vec![Scope::Read]).anonymous_session();
+        let (response, _) =
+            server.operation_as(&reader, Some(operation_id), Request::AddCreditz(1, 5));
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::Unauthorized)
+        ));
+        assert_eq!(server.creditz(1), 5);
+    }
+
+    fn item(name: &str, stat: &str, delta: f64) -> config::ItemConfig {
+        config::ItemConfig {
+            name: name.to_string(),
+            effects: vec![config::ItemEffectConfig {
+                stat: stat.to_string(),
+                delta,
+            }],
+        }
+    }
+
+    #[test]
+    fn consuming_an_item_needs_the_scopes_of_its_effects() {
+        let server = Server::with_items(vec![
+            item("coin", "creditz", 10.0),
+            item("snack", "hunger", -0.1),
+        ]);
+        for item in ["coin", "snack"] {
+            let grant = Request::GrantItem {
+                user_id: 1,
+                item: item.to_string(),
+                quantity: 2,
+            };
+            assert!(matches!(server.request(grant).0, Response::Success));
+        }
+        let vendor = Authenticator::new(Vec::new(), vec![Scope::ItemWrite, Scope::StatWrite])
+            .anonymous_session();
+        let consume = |item: &str| Op::ConsumeItem {
+            user_id: 1,
+            item: item.to_string(),
+            quantity: 1,
+        };
+
+        let coin = Request::ConsumeItem {
+            user_id: 1,
+            item: "coin".to_string(),
+            quantity: 1,
+        };
+        let (response, _) = server.operation_as(&vendor, None, coin);
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::Unauthorized)
+        ));
+        let transaction = Request::Transaction(vec![consume("snack"), consume("coin")]);
+        let (response, _) = server.operation_as(&vendor, None, transaction);
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::Unauthorized)
+        ));
+        assert_eq!(server.creditz(1), 0);
+
+        let (response, _) =
+            server.operation_as(&vendor, None, Request::Transaction(vec![consume("snack")]));
+        assert!(matches!(response, Response::Transaction(_)));
+    }
+
+    #[test]
+    fn transaction_answers_every_op_in_order() {
+        let server = Server::new();
+
+        let (response, notifications) = server.request(Request::Transaction(vec![
+            Op::AddCreditz(1, 5),
+            Op::GetCreditz(1),
+            Op::SubtractCreditz(1, 2),
+            Op::GetCreditz(1),
+        ]));
+
+        let Response::Transaction(responses) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert!(matches!(
+            responses[..],
+            [
+                Response::Success,
+                Response::Creditz(5),
+                Response::Success,
+                Response::Creditz(3)
+            ]
+        ));
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(server.creditz(1), 3);
+    }
+
+    #[test]
+    fn adding_past_the_largest_balance_overflows() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, u32::MAX - 1));
+
+        let (response, notifications) = server.request(Request::AddCreditz(1, 2));
+
+        assert!(matches!(response, Response::Error(ServerError::Overflow)));
+        assert!(notifications.is_empty());
+        assert_eq!(server.creditz(1), u32::MAX - 1);
+    }
+
+    #[test]
+    fn unknown_stat_is_reported_by_name() {
+        let server = Server::new();
+
+        let (response, _) = server.request(Request::GetStat {
+            user_id: 1,
+            stat: "charisma".to_string(),
+        });
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::UnknownStat(stat)) if stat == "charisma"
+        ));
+    }
+
+    #[test]
+    fn characters_come_back_in_the_order_asked_for() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 10));
+        server.request(Request::SetCreditz(2, 20));
+
+        let (response, _) = server.request(Request::GetCharacters(vec![2, 3, 1]));
+
+        let Response::Characters(characters) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        let creditz: Vec<_> = characters
+            .iter()
+            .map(|character| (character.user_id, character.creditz))
+            .collect();
+        assert_eq!(creditz, [(2, 20), (3, 0), (1, 10)]);
+    }
+
+    #[test]
+    fn too_many_characters_at_once_is_refused() {
+        let server = Server::new();
+        let ids = (0..=MAX_CHARACTERS_PER_REQUEST as u32).collect();
+
+        let (response, _) = server.request(Request::GetCharacters(ids));
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn ledger_records_each_change_newest_first() {
+        let server = Server::new();
+        server.request_because("ticket:MagicForest", Request::AddCreditz(1, 50));
+        server.request_because("prize:CoreMaze", Request::AddCreditz(1, 30));
+        server.request_because("shop:Hat", Request::SubtractCreditz(1, 20));
+
+        let entries = server.history(1, None, 2);
+
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.delta, entry.balance, entry.reason.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (-20, 60, Some("shop:Hat")),
+                (30, 80, Some("prize:CoreMaze"))
+            ]
+        );
+        assert_eq!(entries[0].source.as_deref(), Some("game"));
+
+        let rest = server.history(1, Some(entries[1].id), 2);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].reason.as_deref(), Some("ticket:MagicForest"));
+    }
+
+    #[test]
+    fn overlong_reason_is_refused() {
+        let server = Server::new();
+
+        let response =
+            server.request_because(&"x".repeat(MAX_REASON_LEN + 1), Request::AddCreditz(1, 5));
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InvalidRequest(_))
+        ));
+        assert_eq!(server.creditz(1), 0);
+        assert_eq!(server.history_len(1), 0);
+    }
+
+    #[test]
+    fn transfer_moves_creditz_and_notifies_both_parties() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 10));
+
+        let (response, notifications) = server.request(Request::TransferCreditz {
+            from: 1,
+            to: 2,
+            amount: 4,
+        });
+
+        assert!(matches!(response, Response::Success));
+        assert_eq!(notifications.len(), 2);
+        assert_eq!((server.creditz(1), server.creditz(2)), (6, 4));
+    }
+
+    #[test]
+    fn transfer_beyond_the_balance_changes_nothing() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 3));
+        server.request(Request::SetCreditz(2, 5));
+
+        let (response, notifications) = server.request(Request::TransferCreditz {
+            from: 1,
+            to: 2,
+            amount: 4,
+        });
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InsufficientFunds)
+        ));
+        assert!(notifications.is_empty());
+        assert_eq!((server.creditz(1), server.creditz(2)), (3, 5));
+        assert_eq!(server.history_len(2), 1);
+    }
+
+    #[test]
+    fn transfer_to_oneself_is_refused() {
+        let server = Server::new();
+        server.request(Request::SetCreditz(1, 3));
+
+        let (response, _) = server.request(Request::TransferCreditz {
+            from: 1,
+            to: 1,
+            amount: 1,
+        });
+
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InvalidRequest(_))
+        ));
+        assert_eq!(server.history_len(1), 1);
+    }
+
+    #[test]
+    fn consuming_an_item_whose_effect_fails_keeps_the_item() {
+        let server = Server::with_items(vec![item("ticket", "creditz", -5.0)]);
+        server.request(Request::SetCreditz(1, 3));
+        server.request(Request::GrantItem {
+            user_id: 1,
+            item: "ticket".to_string(),
+            quantity: 2,
+        });
+        let count = || {
+            let request = Request::CountItem {
+                user_id: 1,
+                item: "ticket".to_string(),
+            };
+            match server.request(request).0 {
+                Response::ItemCount(count) => count,
+                response => panic!("unexpected response {:?}", response),
+            }
+        };
+
+        let (response, notifications) = server.request(Request::ConsumeItem {
+            user_id: 1,
+            item: "ticket".to_string(),
+            quantity: 1,
+        });
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::InsufficientFunds)
+        ));
+        assert!(notifications.is_empty());
+        assert_eq!((count(), server.creditz(1)), (2, 3));
+
+        let (response, _) = server.request(Request::ConsumeItem {
+            user_id: 1,
+            item: "ticket".to_string(),
+            quantity: 3,
+        });
+        assert!(matches!(
+            response,
+            Response::Error(ServerError::NotEnoughItems)
+        ));
+        assert_eq!(count(), 2);
+    }
+
+    fn limits(idle_timeout: Option<Duration>) -> ConnectionLimits {
+        ConnectionLimits {
+            max_frame_size: 16,
+            idle_timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_size_limit_are_rejected_unread() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(17).await.unwrap();
+
+        let error = read_frame(&mut server, limits(None)).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(16).await.unwrap();
+        client.write_all(&[7; 16]).await.unwrap();
+        assert_eq!(
+            read_frame(&mut server, limits(None)).await.unwrap(),
+            [7; 16]
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connections_time_out_even_mid_frame() {
+        let idle = limits(Some(Duration::from_millis(50)));
+        let (_client, mut server) = tokio::io::duplex(64);
+
+        let error = read_frame(&mut server, idle).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_u32(8).await.unwrap();
+        client.write_all(&[7; 4]).await.unwrap();
+        let error = read_frame(&mut server, idle).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
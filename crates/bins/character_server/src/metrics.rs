@@ -0,0 +1,313 @@
+use character::{Request, Response, ServerError};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+/// The server's metrics, served in the Prometheus text format at the admin API's `/metrics`.
+///
+/// They are global rather than passed around, because storage failures are counted from deep
+/// inside code that has no other reason to know about them.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    connected_clients: Gauge,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: Family<RequestTypeLabels, Histogram, fn() -> Histogram>,
+    storage_errors: Family<StorageLabels, Counter>,
+    notification_fanout: Histogram,
+    queued_notifications: Gauge,
+    notifications_dropped: Family<DropLabels, Counter>,
+    slow_client_disconnects: Counter,
+    rejected_frames: Family<RejectLabels, Counter>,
+    rejected_connections: Counter,
+    creditz_in_circulation: Gauge,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    request: &'static str,
+    /// `ok`, or the kind of error the request was answered with.
+    outcome: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestTypeLabels {
+    request: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StorageLabels {
+    operation: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DropLabels {
+    /// `queue_full`, `coalesced` or `disconnected`.
+    reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RejectLabels {
+    /// `too_large` or `undecodable`.
+    reason: &'static str,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Self {
+            registry: Registry::with_prefix("character"),
+            connected_clients: Gauge::default(),
+            requests: Family::default(),
+            // From half a millisecond to about four seconds.
+            request_duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.0005, 2.0, 14))
+            }),
+            storage_errors: Family::default(),
+            notification_fanout: Histogram::new(exponential_buckets(1.0, 2.0, 10)),
+            queued_notifications: Gauge::default(),
+            notifications_dropped: Family::default(),
+            slow_client_disconnects: Counter::default(),
+            rejected_frames: Family::default(),
+            rejected_connections: Counter::default(),
+            creditz_in_circulation: Gauge::default(),
+        };
+        metrics.register()
+    }
+
+    fn register(mut self) -> Self {
+        let registry = &mut self.registry;
+        registry.register(
+            "connected_clients",
+            "Clients connected over the character protocol",
+            self.connected_clients.clone(),
+        );
+        registry.register(
+            "requests",
+            "Requests processed, by type and outcome",
+            self.requests.clone(),
+        );
+        registry.register(
+            "request_duration_seconds",
+            "How long requests took to process, by type",
+            self.request_duration.clone(),
+        );
+        registry.register(
+            "storage_errors",
+            "Storage operations that failed, by operation",
+            self.storage_errors.clone(),
+        );
+        registry.register(
+            "notification_fanout",
+            "How many clients each published notification was sent to",
+            self.notification_fanout.clone(),
+        );
+        registry.register(
+            "queued_notifications",
+            "Notifications waiting to be written, across every client",
+            self.queued_notifications.clone(),
+        );
+        registry.register(
+            "notifications_dropped",
+            "Notifications that were never sent because a client fell behind, by reason",
+            self.notifications_dropped.clone(),
+        );
+        registry.register(
+            "slow_client_disconnects",
+            "Clients disconnected for falling too far behind on their notifications",
+            self.slow_client_disconnects.clone(),
+        );
+        registry.register(
+            "rejected_frames",
+            "Frames from clients that were too large or couldn't be decoded, by reason",
+            self.rejected_frames.clone(),
+        );
+        registry.register(
+            "rejected_connections",
+            "Connections closed straight away because max_connections clients were connected",
+            self.rejected_connections.clone(),
+        );
+        registry.register(
+            "creditz_in_circulation",
+            "The creditz of every player added together",
+            self.creditz_in_circulation.clone(),
+        );
+        self
+    }
+
+    /// Renders every metric in the Prometheus text format. `total_creditz` is read from
+    /// storage when the metrics are scraped, if it could be.
+    pub fn encode(&self, total_creditz: Option<u64>) -> Result<String, std::fmt::Error> {
+        if let Some(total) = total_creditz {
+            self.creditz_in_circulation
+                .set(i64::try_from(total).unwrap_or(i64::MAX));
+        }
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+
+    /// Counts a client as connected until the returned guard is dropped.
+    pub fn client_connected(&self) -> ConnectedClient {
+        self.connected_clients.inc();
+        ConnectedClient(())
+    }
+
+    /// Counts a processed request, and how long it took.
+    pub fn observe_request(&self, request: &'static str, response: &Response, elapsed: Duration) {
+        let outcome = match response {
+            Response::Error(e) => error_kind(e),
+            _ => "ok",
+        };
+        self.requests
+            .get_or_create(&RequestLabels { request, outcome })
+            .inc();
+        self.request_duration
+            .get_or_create(&RequestTypeLabels { request })
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts a failed storage operation: `init`, `load`, `save` or `transaction`.
+    pub fn storage_error(&self, operation: &'static str) {
+        self.storage_errors
+            .get_or_create(&StorageLabels { operation })
+            .inc();
+    }
+
+    pub fn notification_published(&self, recipients: usize) {
+        self.notification_fanout.observe(recipients as f64);
+    }
+
+    /// Accounts for a client's queue going from `old` to `new` notifications.
+    pub fn queue_depth_changed(&self, old: usize, new: usize) {
+        self.queued_notifications.inc_by(new as i64 - old as i64);
+    }
+
+    /// Counts notifications a client will never receive: `queue_full` when its queue made
+    /// room for newer ones, `coalesced` when a newer value of the same stat replaced them, or
+    /// `disconnected` when they were waiting as it was disconnected.
+    pub fn notifications_dropped(&self, reason: &'static str, count: usize) {
+        self.notifications_dropped
+            .get_or_create(&DropLabels { reason })
+            .inc_by(count as u64);
+    }
+
+    pub fn slow_client_disconnected(&self) {
+        self.slow_client_disconnects.inc();
+    }
+
+    /// Counts a frame from a client that was `too_large` to read or `undecodable`.
+    pub fn frame_rejected(&self, reason: &'static str) {
+        self.rejected_frames
+            .get_or_create(&RejectLabels { reason })
+            .inc();
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.inc();
+    }
+}
+
+/// Counts a client as connected for as long as it is alive.
+pub struct ConnectedClient(());
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        METRICS.connected_clients.dec();
+    }
+}
+
+/// The name requests of this type are counted under.
+pub fn request_type(request: &Request) -> &'static str {
+    match request {
+        Request::GetCreditz(_) => "get_creditz",
+        Request::SetCreditz(..) => "set_creditz",
+        Request::AddCreditz(..) => "add_creditz",
+        Request::SubtractCreditz(..) => "subtract_creditz",
+        Request::GetHappiness(_) => "get_happiness",
+        Request::SetHappiness(..) => "set_happiness",
+        Request::AddHappiness(..) => "add_happiness",
+        Request::GetBoredom(_) => "get_boredom",
+        Request::SetBoredom(..) => "set_boredom",
+        Request::AddBoredom(..) => "add_boredom",
+        Request::GetHunger(_) => "get_hunger",
+        Request::SetHunger(..) => "set_hunger",
+        Request::AddHunger(..) => "add_hunger",
+        Request::Transaction(_) => "transaction",
+        Request::Authenticate(_) => "authenticate",
+        Request::Subscribe { .. } => "subscribe",
+        Request::Unsubscribe { .. } => "unsubscribe",
+        Request::Resume { .. } => "resume",
+        Request::GetCharacter(_) => "get_character",
+        Request::GetCharacters(_) => "get_characters",
+        Request::GetCreditzHistory { .. } => "get_creditz_history",
+        Request::TransferCreditz { .. } => "transfer_creditz",
+        Request::GetStat { .. } => "get_stat",
+        Request::SetStat { .. } => "set_stat",
+        Request::AddStat { .. } => "add_stat",
+        Request::ListStats => "list_stats",
+        Request::GrantItem { .. } => "grant_item",
+        Request::ConsumeItem { .. } => "consume_item",
+        Request::CountItem { .. } => "count_item",
+        Request::GetInventory(_) => "get_inventory",
+        Request::GetValue { .. } => "get_value",
+        Request::SetValue { .. } => "set_value",
+        Request::DeleteValue { .. } => "delete_value",
+        Request::ListValues { .. } => "list_values",
+    }
+}
+
+fn error_kind(e: &ServerError) -> &'static str {
+    match e {
+        ServerError::InsufficientFunds => "insufficient_funds",
+        ServerError::StorageFailure(_) => "storage_failure",
+        ServerError::UnknownUser(_) => "unknown_user",
+        ServerError::Overflow => "overflow",
+        ServerError::InvalidValue(_) => "invalid_value",
+        ServerError::Unauthorized => "unauthorized",
+        ServerError::InvalidRequest(_) => "invalid_request",
+        ServerError::UnknownStat(_) => "unknown_stat",
+        ServerError::UnknownItem(_) => "unknown_item",
+        ServerError::NotEnoughItems => "not_enough_items",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_by_type_and_outcome() {
+        let metrics = Metrics::new();
+        let request = request_type(&Request::SubtractCreditz(1, 5));
+        let refused = Response::Error(ServerError::InsufficientFunds);
+
+        metrics.observe_request(request, &refused, Duration::from_millis(1));
+        metrics.observe_request(request, &refused, Duration::from_millis(1));
+        metrics.observe_request(request, &Response::Success, Duration::from_millis(1));
+
+        let text = metrics.encode(None).unwrap();
+        assert!(text.contains(
+            "character_requests_total{request=\"subtract_creditz\",outcome=\"insufficient_funds\"} 2"
+        ));
+        assert!(text
+            .contains("character_requests_total{request=\"subtract_creditz\",outcome=\"ok\"} 1"));
+    }
+
+    #[test]
+    fn reports_queued_notifications_and_creditz_in_circulation() {
+        let metrics = Metrics::new();
+
+        metrics.queue_depth_changed(0, 5);
+        metrics.queue_depth_changed(5, 2);
+
+        let text = metrics.encode(Some(1234)).unwrap();
+        assert!(text.contains("character_queued_notifications 2"));
+        assert!(text.contains("character_creditz_in_circulation 1234"));
+    }
+}
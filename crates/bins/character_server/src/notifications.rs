@@ -0,0 +1,473 @@
+use crate::config::{BackpressurePolicy, NotificationQueueConfig};
+use crate::metrics::METRICS;
+use bytes::Bytes;
+use character::{Notification, Request, ServerMessage, Subscriptions};
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Numbers every notification, delivers each one to the clients subscribed to it, and keeps
+/// the most recent ones so that a reconnecting client can catch up on what it missed.
+///
+/// Sequence numbers are assigned, buffered and sent under one lock, so every client receives
+/// its notifications in sequence order.
+pub struct NotificationHub {
+    subscribers: HashMap<SocketAddr, Subscriber>,
+    next_sequence: u64,
+    replay: VecDeque<(u64, Notification)>,
+    replay_capacity: usize,
+    /// The queue settings of clients whose credential doesn't have its own.
+    default_queue: NotificationQueueConfig,
+}
+
+/// A client's notification queue, and the notifications it wants.
+struct Subscriber {
+    queue: Arc<NotificationQueue>,
+    subscriptions: Subscriptions,
+}
+
+impl NotificationHub {
+    pub fn new(replay_capacity: usize, default_queue: NotificationQueueConfig) -> Self {
+        // Start from the current time in microseconds, rather than zero, so that a sequence
+        // number from before a restart is always older than anything this process hands out.
+        // A client resuming from one is then told to resync instead of silently missing out.
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as u64)
+            .unwrap_or(0);
+        Self {
+            subscribers: HashMap::new(),
+            next_sequence: start + 1,
+            replay: VecDeque::with_capacity(replay_capacity),
+            replay_capacity,
+            default_queue,
+        }
+    }
+
+    /// Adds a client, returning the queue its notifications are put on. It receives nothing
+    /// until it subscribes to some notifications.
+    pub fn register(&mut self, addr: SocketAddr) -> Arc<NotificationQueue> {
+        let queue = Arc::new(NotificationQueue::new(self.default_queue));
+        self.subscribers.insert(
+            addr,
+            Subscriber {
+                queue: queue.clone(),
+                subscriptions: Subscriptions::new(),
+            },
+        );
+        queue
+    }
+
+    pub fn unregister(&mut self, addr: &SocketAddr) {
+        self.subscribers.remove(addr);
+    }
+
+    /// Applies a `Subscribe` or `Unsubscribe` request from `addr`. Returns the sequence number
+    /// of the latest notification; everything after it is filtered by the new subscriptions.
+    pub fn update_subscriptions(&mut self, addr: &SocketAddr, request: &Request) -> u64 {
+        if let Some(subscriber) = self.subscribers.get_mut(addr) {
+            subscriber.subscriptions.apply(request);
+        }
+        self.last_sequence()
+    }
+
+    pub fn clear_subscriptions(&mut self, addr: &SocketAddr) {
+        if let Some(subscriber) = self.subscribers.get_mut(addr) {
+            subscriber.subscriptions.clear();
+        }
+    }
+
+    /// Applies a credential's queue settings to `addr`'s queue, or the server's if `None`.
+    pub fn configure_queue(&mut self, addr: &SocketAddr, config: Option<NotificationQueueConfig>) {
+        if let Some(subscriber) = self.subscribers.get(addr) {
+            subscriber
+                .queue
+                .configure(config.unwrap_or(self.default_queue));
+        }
+    }
+
+    /// Numbers a notification and sends it to every client subscribed to it.
+    pub fn publish(&mut self, notification: Notification) -> Result<(), bincode::Error> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let recipients: Vec<SocketAddr> = self
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.subscriptions.matches(&notification))
+            .map(|(addr, _)| *addr)
+            .collect();
+        METRICS.notification_published(recipients.len());
+        if !recipients.is_empty() {
+            let payload = encode(sequence, &notification)?;
+            let key = coalesce_key(&notification);
+            for addr in recipients {
+                self.enqueue(addr, key.clone(), payload.clone());
+            }
+        }
+
+        if self.replay.len() >= self.replay_capacity {
+            self.replay.pop_front();
+        }
+        if self.replay_capacity > 0 {
+            self.replay.push_back((sequence, notification));
+        }
+        Ok(())
+    }
+
+    /// Resends every buffered notification after `last_sequence` that `addr` is subscribed to.
+    ///
+    /// Returns false, sending nothing, if some of the notifications `addr` missed are no
+    /// longer buffered, there are more of them than its queue has room for, or
+    /// `last_sequence` wasn't handed out by this process. The client then has to fetch
+    /// everything it cares about afresh.
+    pub fn resume(
+        &mut self,
+        addr: &SocketAddr,
+        last_sequence: u64,
+    ) -> Result<bool, bincode::Error> {
+        let oldest_available = self
+            .replay
+            .front()
+            .map_or(self.next_sequence, |(sequence, _)| *sequence);
+        // The client chooses last_sequence, so check it against the newest first: that keeps
+        // the addition from overflowing.
+        if last_sequence > self.last_sequence() || last_sequence + 1 < oldest_available {
+            return Ok(false);
+        }
+
+        let Some(subscriber) = self.subscribers.get(addr) else {
+            return Ok(false);
+        };
+        let missed = self
+            .replay
+            .iter()
+            .filter(|(sequence, notification)| {
+                *sequence > last_sequence && subscriber.subscriptions.matches(notification)
+            })
+            .map(|(sequence, notification)| {
+                Ok((coalesce_key(notification), encode(*sequence, notification)?))
+            })
+            .collect::<Result<Vec<_>, bincode::Error>>()?;
+        // Replaying only some of them would leave the client believing it had caught up.
+        if missed.len() > subscriber.queue.room() {
+            return Ok(false);
+        }
+        for (key, payload) in missed {
+            self.enqueue(*addr, key, payload);
+        }
+        Ok(true)
+    }
+
+    /// Puts a notification on `addr`'s queue, disconnecting the client if its queue's policy
+    /// says to.
+    fn enqueue(&mut self, addr: SocketAddr, key: Option<CoalesceKey>, payload: Bytes) {
+        let Some(subscriber) = self.subscribers.get(&addr) else {
+            return;
+        };
+        // Only the hub holds the queue once the client's task has terminated.
+        if Arc::strong_count(&subscriber.queue) == 1 {
+            warn!("Client {} is gone without unregistering. Removing.", addr);
+            self.unregister(&addr);
+            return;
+        }
+        if !subscriber.queue.push(key, payload) {
+            warn!(
+                "Disconnecting {}: it isn't keeping up with its notifications.",
+                addr
+            );
+            self.unregister(&addr);
+        }
+    }
+
+    /// The sequence number of the most recently published notification.
+    fn last_sequence(&self) -> u64 {
+        self.next_sequence - 1
+    }
+}
+
+/// Identifies the stat a notification carries the latest value of: a user, and the stat's
+/// name. Notifications with the same key can be coalesced.
+type CoalesceKey = (u32, String);
+
+fn coalesce_key(notification: &Notification) -> Option<CoalesceKey> {
+    match notification {
+        Notification::CreditzChanged { user_id, .. } => Some((*user_id, "creditz".to_string())),
+        Notification::HappinessChanged { user_id, .. } => Some((*user_id, "happiness".to_string())),
+        Notification::HungerChanged { user_id, .. } => Some((*user_id, "hunger".to_string())),
+        Notification::BoredomChanged { user_id, .. } => Some((*user_id, "boredom".to_string())),
+        Notification::StatChanged { user_id, stat, .. } => Some((*user_id, stat.clone())),
+        // These announce a change rather than a value, so every one of them matters.
+        Notification::ItemGranted { .. } | Notification::ItemConsumed { .. } => None,
+    }
+}
+
+/// The notifications waiting to be written to one client.
+///
+/// The hub puts notifications on it while holding its own lock, so putting one on never
+/// waits for the client; when the queue is full, its policy decides what gives.
+pub struct NotificationQueue {
+    state: Mutex<QueueState>,
+    ready: Notify,
+}
+
+struct QueueState {
+    config: NotificationQueueConfig,
+    entries: VecDeque<(Option<CoalesceKey>, Bytes)>,
+    /// How many entries the metrics last counted, so only the difference is reported.
+    reported_depth: usize,
+    /// Set when the client is disconnected for falling behind.
+    closed: bool,
+}
+
+impl NotificationQueue {
+    fn new(config: NotificationQueueConfig) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                config,
+                entries: VecDeque::new(),
+                reported_depth: 0,
+                closed: false,
+            }),
+            ready: Notify::new(),
+        }
+    }
+
+    fn configure(&self, config: NotificationQueueConfig) {
+        self.lock().config = config;
+    }
+
+    /// Adds a notification, making room for it as the queue's policy says. Returns false if
+    /// the queue has been closed instead, and the client should be disconnected.
+    fn push(&self, key: Option<CoalesceKey>, payload: Bytes) -> bool {
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+        let capacity = state.config.capacity.max(1);
+
+        match state.config.policy {
+            BackpressurePolicy::Disconnect if state.entries.len() >= capacity => {
+                METRICS.notifications_dropped("disconnected", state.entries.len() + 1);
+                METRICS.slow_client_disconnected();
+                state.entries.clear();
+                state.closed = true;
+                state.report_depth();
+                drop(state);
+                self.ready.notify_one();
+                return false;
+            }
+            BackpressurePolicy::Coalesce if key.is_some() => {
+                // Remove the older value rather than overwrite it in place, so the queue stays
+                // in sequence order.
+                if let Some(index) = state.entries.iter().position(|(k, _)| *k == key) {
+                    state.entries.remove(index);
+                    METRICS.notifications_dropped("coalesced", 1);
+                }
+            }
+            _ => {}
+        }
+        while state.entries.len() >= capacity {
+            state.entries.pop_front();
+            METRICS.notifications_dropped("queue_full", 1);
+        }
+
+        state.entries.push_back((key, payload));
+        state.report_depth();
+        drop(state);
+        self.ready.notify_one();
+        true
+    }
+
+    /// How many more notifications fit before the policy has to make room, or lose the client.
+    fn room(&self) -> usize {
+        let state = self.lock();
+        if state.closed {
+            return 0;
+        }
+        state
+            .config
+            .capacity
+            .max(1)
+            .saturating_sub(state.entries.len())
+    }
+
+    /// Waits for the next notification. Returns `None` if the queue has been closed because
+    /// the client fell too far behind.
+    ///
+    /// Cancel-safe: a notification is only taken off the queue when it is returned.
+    pub async fn pop(&self) -> Option<Bytes> {
+        loop {
+            {
+                let mut state = self.lock();
+                if let Some((_, payload)) = state.entries.pop_front() {
+                    state.report_depth();
+                    return Some(payload);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        // A panic while the lock was held can't leave the queue itself inconsistent.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for NotificationQueue {
+    fn drop(&mut self) {
+        let state = self.lock();
+        METRICS.queue_depth_changed(state.reported_depth, 0);
+    }
+}
+
+impl QueueState {
+    fn report_depth(&mut self) {
+        METRICS.queue_depth_changed(self.reported_depth, self.entries.len());
+        self.reported_depth = self.entries.len();
+    }
+}
+
+fn encode(sequence: u64, notification: &Notification) -> Result<Bytes, bincode::Error> {
+    Ok(bincode::serialize(&ServerMessage::Notification {
+        sequence,
+        notification: notification.clone(),
+    })?
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use character::StatKind;
+
+    fn queue(policy: BackpressurePolicy, capacity: usize) -> NotificationQueue {
+        NotificationQueue::new(NotificationQueueConfig { policy, capacity })
+    }
+
+    fn key(user_id: u32, stat: &str) -> Option<CoalesceKey> {
+        Some((user_id, stat.to_string()))
+    }
+
+    fn queued(queue: &NotificationQueue) -> Vec<Bytes> {
+        queue
+            .lock()
+            .entries
+            .iter()
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_the_newest() {
+        let queue = queue(BackpressurePolicy::DropOldest, 2);
+
+        assert!(queue.push(key(1, "creditz"), Bytes::from("a")));
+        assert!(queue.push(key(1, "creditz"), Bytes::from("b")));
+        assert!(queue.push(key(1, "creditz"), Bytes::from("c")));
+
+        assert_eq!(queued(&queue), ["b", "c"]);
+    }
+
+    #[test]
+    fn coalesce_replaces_the_same_stat_and_keeps_order() {
+        let queue = queue(BackpressurePolicy::Coalesce, 2);
+
+        queue.push(key(1, "creditz"), Bytes::from("a"));
+        queue.push(key(1, "hunger"), Bytes::from("b"));
+        queue.push(key(1, "creditz"), Bytes::from("c"));
+        assert_eq!(queued(&queue), ["b", "c"]);
+
+        // Items can't be coalesced, so a full queue drops its oldest entry for them.
+        queue.push(None, Bytes::from("d"));
+        assert_eq!(queued(&queue), ["c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_a_full_queue() {
+        let queue = queue(BackpressurePolicy::Disconnect, 2);
+
+        assert!(queue.push(key(1, "creditz"), Bytes::from("a")));
+        assert!(queue.push(key(1, "hunger"), Bytes::from("b")));
+        assert!(!queue.push(key(1, "boredom"), Bytes::from("c")));
+
+        assert!(queued(&queue).is_empty());
+        assert!(!queue.push(key(1, "creditz"), Bytes::from("d")));
+        assert_eq!(queue.pop().await, None);
+    }
+
+    fn hub(capacity: usize) -> NotificationHub {
+        let queue = NotificationQueueConfig {
+            policy: BackpressurePolicy::DropOldest,
+            capacity,
+        };
+        NotificationHub::new(16, queue)
+    }
+
+    fn subscribe(hub: &mut NotificationHub, addr: &SocketAddr) {
+        let request = Request::Subscribe {
+            user_ids: Vec::new(),
+            stats: StatKind::EVERY.to_vec(),
+        };
+        hub.update_subscriptions(addr, &request);
+    }
+
+    fn creditz(user_id: u32, new_value: u32) -> Notification {
+        Notification::CreditzChanged { user_id, new_value }
+    }
+
+    #[test]
+    fn resume_replays_what_was_missed() {
+        let mut hub = hub(8);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6675));
+        let queue = hub.register(addr);
+        subscribe(&mut hub, &addr);
+        let last_sequence = hub.last_sequence();
+        for value in 0..3 {
+            hub.publish(creditz(1, value)).unwrap();
+        }
+        queue.lock().entries.clear();
+
+        assert!(hub.resume(&addr, last_sequence + 1).unwrap());
+        assert_eq!(queued(&queue).len(), 2);
+    }
+
+    #[test]
+    fn resume_refuses_more_than_the_queue_has_room_for() {
+        let mut hub = hub(2);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6675));
+        let queue = hub.register(addr);
+        subscribe(&mut hub, &addr);
+        let last_sequence = hub.last_sequence();
+        for value in 0..3 {
+            hub.publish(creditz(value, value)).unwrap();
+        }
+        queue.lock().entries.clear();
+
+        assert!(!hub.resume(&addr, last_sequence).unwrap());
+        assert!(queued(&queue).is_empty());
+    }
+
+    #[test]
+    fn resume_refuses_what_is_no_longer_buffered() {
+        let mut hub = hub(8);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 6675));
+        let _queue = hub.register(addr);
+        subscribe(&mut hub, &addr);
+        let last_sequence = hub.last_sequence();
+        for value in 0..20 {
+            hub.publish(creditz(1, value)).unwrap();
+        }
+
+        assert!(!hub.resume(&addr, last_sequence).unwrap());
+        assert!(!hub.resume(&addr, hub.last_sequence() + 1).unwrap());
+        assert!(hub.resume(&addr, hub.last_sequence()).unwrap());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::config::{StatCondition, StatRateConfig};
+use crate::stats::{StatDefinition, StatRegistry};
+use crate::storage::{Storage, StoredStats};
+use crate::{in_transaction, load_failure, publish, save_failure, Db, Notifier};
+use character::{Notification, ServerError, StatBar, StatType, StatValue};
+use log::{error, info};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+/// How many players' stats are advanced under one hold of the database lock. Requests wait for
+/// the lock, so a tick over many players is broken up to let them in between batches.
+const BATCH_SIZE: usize = 100;
+
+/// Changes every player's stats over time, at the rates given in the server configuration.
+///
+/// Only time while the server is running counts; stats don't change while it is down.
+pub struct StatScheduler {
+    tick: Duration,
+    /// Every stat a rate changes or a condition checks, each once.
+    stats: Vec<StatDefinition>,
+    rates: Vec<Rate>,
+}
+
+/// A configured rate, with its stats resolved to indices into `StatScheduler::stats`.
+struct Rate {
+    stat: usize,
+    per_hour: f32,
+    when: Option<Condition>,
+}
+
+struct Condition {
+    stat: usize,
+    above: Option<f32>,
+    below: Option<f32>,
+}
+
+impl StatScheduler {
+    pub fn new(
+        tick_secs: u64,
+        rates: Vec<StatRateConfig>,
+        registry: &StatRegistry,
+    ) -> Result<Self, String> {
+        if tick_secs == 0 {
+            return Err("stat_tick_secs must be greater than zero".to_string());
+        }
+
+        let mut stats: Vec<StatDefinition> = Vec::new();
+        let mut resolve = |name: &str| -> Result<usize, String> {
+            let definition = registry.get(name).map_err(|e| e.to_string())?;
+            if definition.stat_type != StatType::Bar {
+                return Err(format!(
+                    "stat_rates can only refer to bar stats, and {} isn't one",
+                    name
+                ));
+            }
+            if let Some(index) = stats.iter().position(|stat| stat.name == name) {
+                return Ok(index);
+            }
+            stats.push(definition.clone());
+            Ok(stats.len() - 1)
+        };
+
+        let mut resolved = Vec::with_capacity(rates.len());
+        for rate in rates {
+            if !rate.per_hour.is_finite() {
+                return Err(format!("{} is not a valid per_hour rate", rate.per_hour));
+            }
+            let when = match rate.when {
+                Some(StatCondition { stat, above, below }) => Some(Condition {
+                    stat: resolve(&stat)?,
+                    above,
+                    below,
+                }),
+                None => None,
+            };
+            resolved.push(Rate {
+                stat: resolve(&rate.stat)?,
+                per_hour: rate.per_hour,
+                when,
+            });
+        }
+
+        Ok(Self {
+            tick: Duration::from_secs(tick_secs),
+            stats,
+            rates: resolved,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+
+    /// Applies the rates every tick, forever, publishing a notification for every change.
+    pub async fn run(self, db: Db, notifier: Notifier) {
+        info!(
+            "Applying {} stat rates every {:?}.",
+            self.rates.len(),
+            self.tick
+        );
+        let scheduler = Arc::new(self);
+        let mut interval = tokio::time::interval(scheduler.tick);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately.
+        interval.tick().await;
+        let mut last_tick = Instant::now();
+
+        loop {
+            interval.tick().await;
+            // Ticks can be late, so apply however much time has really passed.
+            let now = Instant::now();
+            let elapsed = now - last_tick;
+            last_tick = now;
+
+            let scheduler = scheduler.clone();
+            let db = db.clone();
+            let notifier = notifier.clone();
+            let result =
+                tokio::task::spawn_blocking(move || scheduler.apply(elapsed, &db, &notifier)).await;
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to apply stat rates: {}", e),
+                Err(e) => error!("Stat rate task failed: {}", e),
+            }
+        }
+    }
+
+    /// Advances every player's stats by `elapsed`, a batch of players per database
+    /// transaction, and publishes a notification for every change.
+    fn apply(&self, elapsed: Duration, db: &Db, notifier: &Notifier) -> Result<(), ServerError> {
+        let hours = elapsed.as_secs_f32() / 3600.0;
+        let citizen_ids = db.blocking_lock().citizen_ids().map_err(load_failure)?;
+        for batch in citizen_ids.chunks(BATCH_SIZE) {
+            let mut db_lock = db.blocking_lock();
+            let result = in_transaction(db_lock.as_mut(), |db| {
+                let mut notifications = Vec::new();
+                for &citizen_id in batch {
+                    self.advance(citizen_id, hours, db, &mut notifications)?;
+                }
+                Ok(notifications)
+            });
+            let notifications = match result {
+                Ok(notifications) => notifications,
+                Err(e) => {
+                    error!(
+                        "Failed to apply stat rates to {} players: {}",
+                        batch.len(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            publish(notifier, &notifications);
+        }
+        Ok(())
+    }
+
+    /// Advances one player's stats by `hours`, adding a notification for every change.
+    fn advance(
+        &self,
+        citizen_id: u32,
+        hours: f32,
+        db: &mut dyn Storage,
+        notifications: &mut Vec<Notification>,
+    ) -> Result<(), ServerError> {
+        let mut stored = db.get_stats(citizen_id).map_err(load_failure)?;
+        let before = self
+            .stats
+            .iter()
+            .map(|definition| read_bar(definition, citizen_id, &stored, db))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut after = before.clone();
+        // Conditions are checked against the stats as they were before this tick, so the
+        // order of the rates doesn't matter.
+        for rate in &self.rates {
+            if rate
+                .when
+                .as_ref()
+                .is_some_and(|c| !c.holds(&before[c.stat]))
+            {
+                continue;
+            }
+            after[rate.stat] = after[rate.stat].saturating_add(rate.per_hour * hours);
+        }
+
+        let mut builtin_changed = false;
+        for ((definition, before), after) in self.stats.iter().zip(&before).zip(after) {
+            if before.to_u32() == after.to_u32() {
+                continue;
+            }
+            match definition.builtin.and_then(|kind| stored.bar_mut(kind)) {
+                Some(bar) => {
+                    *bar = after.clone();
+                    builtin_changed = true;
+                    notifications.push(definition.notification(citizen_id, StatValue::Bar(after)));
+                }
+                None => {
+                    notifications.push(definition.write(citizen_id, StatValue::Bar(after), db)?)
+                }
+            }
+        }
+        if builtin_changed {
+            db.set_stats(citizen_id, &stored).map_err(save_failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl Condition {
+    fn holds(&self, bar: &StatBar) -> bool {
+        let value = bar.to_f32();
+        self.above.is_none_or(|above| value > above) && self.below.is_none_or(|below| value < below)
+    }
+}
+
+fn read_bar(
+    definition: &StatDefinition,
+    citizen_id: u32,
+    stored: &StoredStats,
+    db: &dyn Storage,
+) -> Result<StatBar, ServerError> {
+    match definition.read(citizen_id, stored, db)? {
+        StatValue::Bar(bar) => Ok(bar),
+        StatValue::Integer(_) => unreachable!("only bar stats are scheduled"),
+    }
+}
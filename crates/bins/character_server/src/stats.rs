@@ -0,0 +1,348 @@
+use crate::config::StatConfig;
+use crate::storage::{Storage, StoredStats};
+use crate::{load_failure, save_failure};
+use character::{
+    Notification, Op, ServerError, StatBar, StatDelta, StatInfo, StatKind, StatType, StatValue,
+};
+use std::collections::BTreeMap;
+
+/// The longest name a configured stat or item may have.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// The operations that get, set and add to one built-in bar.
+type BarOps = (fn(u32) -> Op, fn(u32, f32) -> Op, fn(u32, f32) -> Op);
+
+/// Every stat the server knows about, by name: the built-in ones, and those declared in the
+/// server configuration.
+pub struct StatRegistry {
+    stats: BTreeMap<String, StatDefinition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StatDefinition {
+    pub name: String,
+    pub stat_type: StatType,
+    /// Which built-in stat this is, if any. Built-in stats have their own columns and
+    /// operations; the rest are kept in the named stats table.
+    pub builtin: Option<StatKind>,
+    /// The stored value of a player who has never had this stat set.
+    default: i64,
+}
+
+impl StatRegistry {
+    pub fn new(configs: Vec<StatConfig>) -> Result<Self, String> {
+        let mut stats = BTreeMap::new();
+        for (name, stat_type, kind) in [
+            ("creditz", StatType::Integer, StatKind::Creditz),
+            ("happiness", StatType::Bar, StatKind::Happiness),
+            ("hunger", StatType::Bar, StatKind::Hunger),
+            ("boredom", StatType::Bar, StatKind::Boredom),
+        ] {
+            stats.insert(
+                name.to_string(),
+                StatDefinition {
+                    name: name.to_string(),
+                    stat_type,
+                    builtin: Some(kind),
+                    default: 0,
+                },
+            );
+        }
+
+        for config in configs {
+            if !is_valid_name(&config.name) {
+                return Err(format!(
+                    "Stat name {:?} must be 1 to {} lowercase letters, digits or underscores",
+                    config.name, MAX_NAME_LEN
+                ));
+            }
+            if stats.contains_key(&config.name) {
+                return Err(format!("Stat {} is declared more than once", config.name));
+            }
+
+            let default = match config.stat_type {
+                StatType::Bar if (0.0..=1.0).contains(&config.default) => {
+                    i64::from(StatBar::from_f32(config.default as f32).to_u32())
+                }
+                StatType::Integer
+                    if config.default.fract() == 0.0 && config.default.abs() < i64::MAX as f64 =>
+                {
+                    config.default as i64
+                }
+                _ => {
+                    return Err(format!(
+                        "{} is not a valid default for the {:?} stat {}",
+                        config.default, config.stat_type, config.name
+                    ))
+                }
+            };
+            stats.insert(
+                config.name.clone(),
+                StatDefinition {
+                    name: config.name,
+                    stat_type: config.stat_type,
+                    builtin: None,
+                    default,
+                },
+            );
+        }
+
+        Ok(Self { stats })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&StatDefinition, ServerError> {
+        self.stats
+            .get(name)
+            .ok_or_else(|| ServerError::UnknownStat(name.to_string()))
+    }
+
+    pub fn list(&self) -> Vec<StatInfo> {
+        self.stats
+            .values()
+            .map(|definition| StatInfo {
+                name: definition.name.clone(),
+                stat_type: definition.stat_type,
+            })
+            .collect()
+    }
+}
+
+impl StatDefinition {
+    /// Turns a `GetStat`, `SetStat` or `AddStat` on a built-in stat into the operation made
+    /// for it, so that it behaves exactly the same however it is addressed.
+    pub fn builtin_op(&self, op: Op) -> Result<Op, ServerError> {
+        let Some(kind) = self.builtin else {
+            return Err(ServerError::InvalidRequest(format!(
+                "{} is not a built-in stat",
+                self.name
+            )));
+        };
+
+        if kind == StatKind::Creditz {
+            return match op {
+                Op::GetStat { user_id, .. } => Ok(Op::GetCreditz(user_id)),
+                Op::SetStat {
+                    user_id,
+                    value: StatValue::Integer(value),
+                    ..
+                } => match u32::try_from(value) {
+                    Ok(value) => Ok(Op::SetCreditz(user_id, value)),
+                    Err(_) => Err(ServerError::InvalidValue(format!(
+                        "{} is out of range for creditz",
+                        value
+                    ))),
+                },
+                Op::AddStat {
+                    user_id,
+                    delta: StatDelta::Integer(delta),
+                    ..
+                } if delta >= 0 => match u32::try_from(delta) {
+                    Ok(amount) => Ok(Op::AddCreditz(user_id, amount)),
+                    Err(_) => Err(ServerError::Overflow),
+                },
+                Op::AddStat {
+                    user_id,
+                    delta: StatDelta::Integer(delta),
+                    ..
+                } => match u32::try_from(delta.unsigned_abs()) {
+                    Ok(amount) => Ok(Op::SubtractCreditz(user_id, amount)),
+                    // No balance can cover more than u32::MAX.
+                    Err(_) => Err(ServerError::InsufficientFunds),
+                },
+                _ => Err(self.type_mismatch()),
+            };
+        }
+
+        let (get, set, add): BarOps = match kind {
+            StatKind::Happiness => (Op::GetHappiness, Op::SetHappiness, Op::AddHappiness),
+            StatKind::Hunger => (Op::GetHunger, Op::SetHunger, Op::AddHunger),
+            StatKind::Boredom => (Op::GetBoredom, Op::SetBoredom, Op::AddBoredom),
+            StatKind::Creditz | StatKind::Named | StatKind::Inventory => {
+                unreachable!("not a built-in bar")
+            }
+        };
+        match op {
+            Op::GetStat { user_id, .. } => Ok(get(user_id)),
+            Op::SetStat {
+                user_id,
+                value: StatValue::Bar(value),
+                ..
+            } => Ok(set(user_id, value.to_f32())),
+            Op::AddStat {
+                user_id,
+                delta: StatDelta::Bar(delta),
+                ..
+            } => Ok(add(user_id, delta)),
+            _ => Err(self.type_mismatch()),
+        }
+    }
+
+    /// Reads this stat for a player who already exists, given their built-in stats.
+    pub fn read(
+        &self,
+        citizen_id: u32,
+        stats: &StoredStats,
+        db: &dyn Storage,
+    ) -> Result<StatValue, ServerError> {
+        let raw = match self.builtin {
+            Some(StatKind::Creditz) => i64::from(stats.creditz),
+            Some(kind) => match stats.bar(kind) {
+                Some(bar) => i64::from(bar.to_u32()),
+                None => unreachable!("built-in stats other than creditz are bars"),
+            },
+            None => db
+                .get_named_stat(citizen_id, &self.name)
+                .map_err(load_failure)?
+                .unwrap_or(self.default),
+        };
+        Ok(self.decode(raw))
+    }
+
+    /// Stores a new value of this configured stat, returning the notification to send.
+    pub fn write(
+        &self,
+        citizen_id: u32,
+        value: StatValue,
+        db: &mut dyn Storage,
+    ) -> Result<Notification, ServerError> {
+        if value.stat_type() != self.stat_type {
+            return Err(self.type_mismatch());
+        }
+        let raw = match &value {
+            StatValue::Bar(bar) => i64::from(bar.to_u32()),
+            StatValue::Integer(value) => *value,
+        };
+        db.set_named_stat(citizen_id, &self.name, raw)
+            .map_err(save_failure)?;
+        Ok(self.notification(citizen_id, value))
+    }
+
+    /// Applies `delta` to `current`. Bars saturate; integers fail rather than overflow.
+    pub fn add(&self, current: StatValue, delta: StatDelta) -> Result<StatValue, ServerError> {
+        match (current, delta) {
+            (StatValue::Bar(bar), StatDelta::Bar(delta)) => {
+                if !delta.is_finite() {
+                    return Err(ServerError::InvalidValue(format!(
+                        "{} is not a finite number",
+                        delta
+                    )));
+                }
+                Ok(StatValue::Bar(bar.saturating_add(delta)))
+            }
+            (StatValue::Integer(value), StatDelta::Integer(delta)) => value
+                .checked_add(delta)
+                .map(StatValue::Integer)
+                .ok_or(ServerError::Overflow),
+            _ => Err(self.type_mismatch()),
+        }
+    }
+
+    /// The notification announcing that this stat has changed to `value`.
+    pub fn notification(&self, user_id: u32, value: StatValue) -> Notification {
+        match (self.builtin, value) {
+            (Some(StatKind::Happiness), StatValue::Bar(new_value)) => {
+                Notification::HappinessChanged { user_id, new_value }
+            }
+            (Some(StatKind::Hunger), StatValue::Bar(new_value)) => {
+                Notification::HungerChanged { user_id, new_value }
+            }
+            (Some(StatKind::Boredom), StatValue::Bar(new_value)) => {
+                Notification::BoredomChanged { user_id, new_value }
+            }
+            (Some(StatKind::Creditz), StatValue::Integer(value)) => Notification::CreditzChanged {
+                user_id,
+                new_value: u32::try_from(value).unwrap_or(0),
+            },
+            (_, new_value) => Notification::StatChanged {
+                user_id,
+                stat: self.name.clone(),
+                new_value,
+            },
+        }
+    }
+
+    fn decode(&self, raw: i64) -> StatValue {
+        match self.stat_type {
+            StatType::Bar => StatValue::Bar(StatBar::from_u32(u32::try_from(raw).unwrap_or(0))),
+            StatType::Integer => StatValue::Integer(raw),
+        }
+    }
+
+    fn type_mismatch(&self) -> ServerError {
+        let stat_type = match self.stat_type {
+            StatType::Bar => "bar",
+            StatType::Integer => "integer",
+        };
+        ServerError::InvalidValue(format!("{} is a {} stat", self.name, stat_type))
+    }
+}
+
+/// Whether `name` is fit to name a configured stat or item: 1 to `MAX_NAME_LEN` lowercase
+/// letters, digits or underscores.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(name: &str, stat_type: StatType, default: f64) -> StatConfig {
+        StatConfig {
+            name: name.to_string(),
+            stat_type,
+            default,
+        }
+    }
+
+    #[test]
+    fn refuses_bad_declarations() {
+        for config in [
+            stat("Charisma", StatType::Integer, 0.0),
+            stat("", StatType::Integer, 0.0),
+            stat("creditz", StatType::Integer, 0.0),
+            stat("energy", StatType::Bar, 1.5),
+            stat("level", StatType::Integer, 0.5),
+        ] {
+            let name = config.name.clone();
+            assert!(StatRegistry::new(vec![config]).is_err(), "{:?}", name);
+        }
+        let energy = || stat("energy", StatType::Bar, 0.5);
+        assert!(StatRegistry::new(vec![energy(), energy()]).is_err());
+    }
+
+    #[test]
+    fn lists_builtin_and_configured_stats() {
+        let registry = StatRegistry::new(vec![stat("level", StatType::Integer, 1.0)]).unwrap();
+
+        let names: Vec<_> = registry.list().into_iter().map(|info| info.name).collect();
+        assert_eq!(
+            names,
+            ["boredom", "creditz", "happiness", "hunger", "level"]
+        );
+        assert!(registry.get("level").unwrap().builtin.is_none());
+        assert!(matches!(
+            registry.get("charisma"),
+            Err(ServerError::UnknownStat(_))
+        ));
+    }
+
+    #[test]
+    fn integers_fail_rather_than_overflow() {
+        let registry = StatRegistry::new(vec![stat("level", StatType::Integer, 0.0)]).unwrap();
+        let level = registry.get("level").unwrap();
+
+        assert!(matches!(
+            level.add(StatValue::Integer(i64::MAX), StatDelta::Integer(1)),
+            Err(ServerError::Overflow)
+        ));
+        assert!(matches!(
+            level.add(StatValue::Integer(1), StatDelta::Bar(0.5)),
+            Err(ServerError::InvalidValue(_))
+        ));
+    }
+}
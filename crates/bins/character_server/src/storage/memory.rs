@@ -0,0 +1,362 @@
+use super::{CreditzChange, Storage, StorageError, StorageResult, StoredStats};
+use character::{InventoryItem, KeyValue, LedgerEntry};
+use std::collections::{BTreeMap, HashMap};
+
+/// Keeps everything in memory. Nothing survives a restart, which makes it useful for tests
+/// and local development without a database.
+#[derive(Default)]
+pub struct MemoryStorage {
+    players: HashMap<u32, StoredStats>,
+    named_stats: HashMap<(u32, String), i64>,
+    /// Only items a player has at least one of.
+    inventories: HashMap<(u32, String), u32>,
+    /// Keyed by namespace, player and key, so that a player's part of a namespace is a range.
+    key_values: BTreeMap<(String, u32, String), String>,
+    /// Oldest first. Entry ids are their position plus one.
+    ledger: Vec<LedgerEntry>,
+    /// How to undo the current transaction, if one is in progress.
+    transaction: Option<UndoLog>,
+}
+
+/// The entries a transaction has changed, as they were before it changed them. Undoing the
+/// changes newest first leaves each entry as the transaction found it.
+struct UndoLog {
+    changes: Vec<Undo>,
+    ledger_len: usize,
+}
+
+/// One entry as it was before a change, or `None` if it didn't exist.
+enum Undo {
+    Player(u32, Option<StoredStats>),
+    NamedStat((u32, String), Option<i64>),
+    Item((u32, String), Option<u32>),
+    Value((String, u32, String), Option<String>),
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records how to undo a change about to be made, if a transaction is in progress.
+    fn remember(&mut self, undo: impl FnOnce(&Self) -> Undo) {
+        if self.transaction.is_none() {
+            return;
+        }
+        let undo = undo(self);
+        if let Some(transaction) = &mut self.transaction {
+            transaction.changes.push(undo);
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn begin_transaction(&mut self) -> StorageResult<()> {
+        if self.transaction.is_some() {
+            return Err(StorageError::new("A transaction is already in progress"));
+        }
+        self.transaction = Some(UndoLog {
+            changes: Vec::new(),
+            ledger_len: self.ledger.len(),
+        });
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> StorageResult<()> {
+        match self.transaction.take() {
+            Some(_) => Ok(()),
+            None => Err(StorageError::new("No transaction in progress")),
+        }
+    }
+
+    fn rollback_transaction(&mut self) -> StorageResult<()> {
+        let Some(transaction) = self.transaction.take() else {
+            return Err(StorageError::new("No transaction in progress"));
+        };
+        for undo in transaction.changes.into_iter().rev() {
+            match undo {
+                Undo::Player(citizen_id, Some(stats)) => {
+                    self.players.insert(citizen_id, stats);
+                }
+                Undo::Player(citizen_id, None) => {
+                    self.players.remove(&citizen_id);
+                }
+                Undo::NamedStat(key, Some(value)) => {
+                    self.named_stats.insert(key, value);
+                }
+                Undo::NamedStat(key, None) => {
+                    self.named_stats.remove(&key);
+                }
+                Undo::Item(key, Some(quantity)) => {
+                    self.inventories.insert(key, quantity);
+                }
+                Undo::Item(key, None) => {
+                    self.inventories.remove(&key);
+                }
+                Undo::Value(key, Some(value)) => {
+                    self.key_values.insert(key, value);
+                }
+                Undo::Value(key, None) => {
+                    self.key_values.remove(&key);
+                }
+            }
+        }
+        self.ledger.truncate(transaction.ledger_len);
+        Ok(())
+    }
+
+    fn init_player_if_not_exists(&mut self, citizen_id: u32) -> StorageResult<()> {
+        if !self.players.contains_key(&citizen_id) {
+            self.remember(|_| Undo::Player(citizen_id, None));
+            self.players.insert(citizen_id, StoredStats::default());
+        }
+        Ok(())
+    }
+
+    fn player_exists(&self, citizen_id: u32) -> StorageResult<bool> {
+        Ok(self.players.contains_key(&citizen_id))
+    }
+
+    fn citizen_ids(&self) -> StorageResult<Vec<u32>> {
+        Ok(self.players.keys().copied().collect())
+    }
+
+    fn get_stats(&self, citizen_id: u32) -> StorageResult<StoredStats> {
+        self.players
+            .get(&citizen_id)
+            .cloned()
+            .ok_or_else(|| StorageError::new(format!("No player {}", citizen_id)))
+    }
+
+    fn set_stats(&mut self, citizen_id: u32, stats: &StoredStats) -> StorageResult<()> {
+        self.remember(|storage| {
+            Undo::Player(citizen_id, storage.players.get(&citizen_id).cloned())
+        });
+        match self.players.get_mut(&citizen_id) {
+            Some(stored) => {
+                *stored = stats.clone();
+                Ok(())
+            }
+            None => Err(StorageError::new(format!("No player {}", citizen_id))),
+        }
+    }
+
+    fn record_creditz_change(&mut self, change: &CreditzChange) -> StorageResult<()> {
+        self.ledger.push(LedgerEntry {
+            id: self.ledger.len() as u64 + 1,
+            user_id: change.citizen_id,
+            timestamp: change.timestamp,
+            delta: change.delta,
+            balance: change.balance,
+            reason: change.reason.clone(),
+            source: change.source.clone(),
+        });
+        Ok(())
+    }
+
+    fn creditz_history(
+        &self,
+        citizen_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> StorageResult<Vec<LedgerEntry>> {
+        Ok(self
+            .ledger
+            .iter()
+            .rev()
+            .filter(|entry| entry.user_id == citizen_id)
+            .filter(|entry| before.is_none_or(|before| entry.id < before))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn total_creditz(&self) -> StorageResult<u64> {
+        Ok(self
+            .players
+            .values()
+            .map(|stats| u64::from(stats.creditz))
+            .sum())
+    }
+
+    fn get_named_stat(&self, citizen_id: u32, name: &str) -> StorageResult<Option<i64>> {
+        Ok(self
+            .named_stats
+            .get(&(citizen_id, name.to_string()))
+            .copied())
+    }
+
+    fn set_named_stat(&mut self, citizen_id: u32, name: &str, value: i64) -> StorageResult<()> {
+        let key = (citizen_id, name.to_string());
+        self.remember(|storage| {
+            Undo::NamedStat(key.clone(), storage.named_stats.get(&key).copied())
+        });
+        self.named_stats.insert(key, value);
+        Ok(())
+    }
+
+    fn item_count(&self, citizen_id: u32, item: &str) -> StorageResult<u32> {
+        Ok(self
+            .inventories
+            .get(&(citizen_id, item.to_string()))
+            .copied()
+            .unwrap_or(0))
+    }
+
+    fn set_item_count(&mut self, citizen_id: u32, item: &str, quantity: u32) -> StorageResult<()> {
+        let key = (citizen_id, item.to_string());
+        self.remember(|storage| Undo::Item(key.clone(), storage.inventories.get(&key).copied()));
+        if quantity == 0 {
+            self.inventories.remove(&key);
+        } else {
+            self.inventories.insert(key, quantity);
+        }
+        Ok(())
+    }
+
+    fn inventory(&self, citizen_id: u32) -> StorageResult<Vec<InventoryItem>> {
+        let mut items: Vec<InventoryItem> = self
+            .inventories
+            .iter()
+            .filter(|((id, _), _)| *id == citizen_id)
+            .map(|((_, item), quantity)| InventoryItem {
+                item: item.clone(),
+                quantity: *quantity,
+            })
+            .collect();
+        items.sort_by(|a, b| a.item.cmp(&b.item));
+        Ok(items)
+    }
+
+    fn get_value(
+        &self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+    ) -> StorageResult<Option<String>> {
+        Ok(self
+            .key_values
+            .get(&(namespace.to_string(), citizen_id, key.to_string()))
+            .cloned())
+    }
+
+    fn set_value(
+        &mut self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+        value: &str,
+    ) -> StorageResult<()> {
+        let key = (namespace.to_string(), citizen_id, key.to_string());
+        self.remember(|storage| Undo::Value(key.clone(), storage.key_values.get(&key).cloned()));
+        self.key_values.insert(key, value.to_string());
+        Ok(())
+    }
+
+    fn delete_value(&mut self, namespace: &str, citizen_id: u32, key: &str) -> StorageResult<()> {
+        let key = (namespace.to_string(), citizen_id, key.to_string());
+        self.remember(|storage| Undo::Value(key.clone(), storage.key_values.get(&key).cloned()));
+        self.key_values.remove(&key);
+        Ok(())
+    }
+
+    fn values(&self, namespace: &str, citizen_id: u32) -> StorageResult<Vec<KeyValue>> {
+        let start = (namespace.to_string(), citizen_id, String::new());
+        Ok(self
+            .key_values
+            .range(start..)
+            .take_while(|((ns, id, _), _)| ns == namespace && *id == citizen_id)
+            .map(|((_, _, key), value)| KeyValue {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(citizen_id: u32, balance: u32) -> CreditzChange {
+        CreditzChange {
+            citizen_id,
+            timestamp: 0,
+            delta: i64::from(balance),
+            balance,
+            reason: None,
+            source: None,
+        }
+    }
+
+    /// Gives player 1 `creditz`, with a ledger entry and a named stat to match.
+    fn set_creditz(storage: &mut MemoryStorage, creditz: u32) {
+        let mut stats = storage.get_stats(1).unwrap();
+        stats.creditz = creditz;
+        storage.set_stats(1, &stats).unwrap();
+        storage.record_creditz_change(&change(1, creditz)).unwrap();
+        storage.set_named_stat(1, "xp", i64::from(creditz)).unwrap();
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut storage = MemoryStorage::new();
+        storage.init_player_if_not_exists(1).unwrap();
+
+        storage.begin_transaction().unwrap();
+        set_creditz(&mut storage, 5);
+        storage.commit_transaction().unwrap();
+
+        assert_eq!(storage.get_stats(1).unwrap().creditz, 5);
+        assert_eq!(storage.get_named_stat(1, "xp").unwrap(), Some(5));
+        assert_eq!(storage.creditz_history(1, None, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rollback_restores_everything_and_truncates_the_ledger() {
+        let mut storage = MemoryStorage::new();
+        storage.init_player_if_not_exists(1).unwrap();
+        set_creditz(&mut storage, 5);
+
+        storage.set_item_count(1, "apple", 2).unwrap();
+        storage.set_value("game", 1, "level", "3").unwrap();
+        storage.set_value("game", 1, "name", "mia").unwrap();
+
+        storage.begin_transaction().unwrap();
+        set_creditz(&mut storage, 8);
+        set_creditz(&mut storage, 9);
+        storage.init_player_if_not_exists(2).unwrap();
+        storage.set_item_count(1, "apple", 0).unwrap();
+        storage.set_item_count(1, "pear", 1).unwrap();
+        storage.set_value("game", 1, "level", "4").unwrap();
+        storage.delete_value("game", 1, "name").unwrap();
+        storage.set_value("game", 1, "score", "10").unwrap();
+        storage.rollback_transaction().unwrap();
+
+        assert_eq!(storage.get_stats(1).unwrap().creditz, 5);
+        assert_eq!(storage.get_named_stat(1, "xp").unwrap(), Some(5));
+        assert!(storage.get_stats(2).is_err());
+        assert_eq!(storage.item_count(1, "apple").unwrap(), 2);
+        assert_eq!(storage.item_count(1, "pear").unwrap(), 0);
+        let values = storage.values("game", 1).unwrap();
+        let values: Vec<_> = values.iter().map(|v| (&*v.key, &*v.value)).collect();
+        assert_eq!(values, [("level", "3"), ("name", "mia")]);
+        let ledger = storage.creditz_history(1, None, 10).unwrap();
+        assert_eq!(ledger.len(), 1);
+        assert_eq!(ledger[0].balance, 5);
+
+        // Entry ids carry on from the truncated ledger.
+        storage.record_creditz_change(&change(1, 6)).unwrap();
+        assert_eq!(storage.creditz_history(1, None, 1).unwrap()[0].id, 2);
+    }
+
+    #[test]
+    fn transactions_do_not_nest() {
+        let mut storage = MemoryStorage::new();
+
+        assert!(storage.commit_transaction().is_err());
+        assert!(storage.rollback_transaction().is_err());
+        storage.begin_transaction().unwrap();
+        assert!(storage.begin_transaction().is_err());
+    }
+}
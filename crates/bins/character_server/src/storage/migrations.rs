@@ -0,0 +1,161 @@
+/// The SQL flavour a database speaks. Migrations can differ between the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Sqlite,
+    Mysql,
+}
+
+/// One step in the evolution of the schema. Once released, a migration must never be edited;
+/// add a new one instead.
+///
+/// MySQL can't roll back schema changes, so a migration interrupted there is run again from
+/// the start. Every statement must therefore succeed if it has already been applied.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    sqlite: &'static [&'static str],
+    mysql: &'static [&'static str],
+}
+
+impl Migration {
+    /// The statements that apply this migration, run in order.
+    pub fn statements(&self, dialect: Dialect) -> &'static [&'static str] {
+        match dialect {
+            Dialect::Sqlite => self.sqlite,
+            Dialect::Mysql => self.mysql,
+        }
+    }
+}
+
+/// Every migration, in the order they are applied. Versions start at 1 and increase by one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create miuchiz_stats",
+        // Databases from before migrations existed already have this table, so it must not
+        // fail if the table is there.
+        sqlite: &["CREATE TABLE IF NOT EXISTS miuchiz_stats (
+            citizen_id INTEGER PRIMARY KEY NOT NULL,
+            creditz INTEGER NOT NULL DEFAULT 0,
+            happiness INTEGER NOT NULL DEFAULT 0,
+            hunger INTEGER NOT NULL DEFAULT 0,
+            boredom INTEGER NOT NULL DEFAULT 0)"],
+        mysql: &["CREATE TABLE IF NOT EXISTS miuchiz_stats (
+            citizen_id INTEGER PRIMARY KEY NOT NULL,
+            creditz INTEGER NOT NULL DEFAULT 0,
+            happiness INTEGER NOT NULL DEFAULT 0,
+            hunger INTEGER NOT NULL DEFAULT 0,
+            boredom INTEGER NOT NULL DEFAULT 0)"],
+    },
+    Migration {
+        version: 2,
+        description: "Create creditz_ledger",
+        sqlite: &[
+            "CREATE TABLE IF NOT EXISTS creditz_ledger (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            citizen_id INTEGER NOT NULL,
+            created_at BIGINT NOT NULL,
+            delta BIGINT NOT NULL,
+            balance BIGINT NOT NULL,
+            reason VARCHAR(255) NOT NULL DEFAULT '',
+            source VARCHAR(255) NOT NULL DEFAULT '')",
+            "CREATE INDEX IF NOT EXISTS creditz_ledger_citizen ON creditz_ledger (citizen_id, id)",
+        ],
+        mysql: &["CREATE TABLE IF NOT EXISTS creditz_ledger (
+            id BIGINT PRIMARY KEY AUTO_INCREMENT,
+            citizen_id INTEGER NOT NULL,
+            created_at BIGINT NOT NULL,
+            delta BIGINT NOT NULL,
+            balance BIGINT NOT NULL,
+            reason VARCHAR(255) NOT NULL DEFAULT '',
+            source VARCHAR(255) NOT NULL DEFAULT '',
+            INDEX creditz_ledger_citizen (citizen_id, id))"],
+    },
+    Migration {
+        version: 3,
+        description: "Create named_stats",
+        sqlite: &["CREATE TABLE IF NOT EXISTS named_stats (
+            citizen_id INTEGER NOT NULL,
+            name VARCHAR(64) NOT NULL,
+            value BIGINT NOT NULL,
+            PRIMARY KEY (citizen_id, name))"],
+        mysql: &["CREATE TABLE IF NOT EXISTS named_stats (
+            citizen_id INTEGER NOT NULL,
+            name VARCHAR(64) NOT NULL,
+            value BIGINT NOT NULL,
+            PRIMARY KEY (citizen_id, name))"],
+    },
+    Migration {
+        version: 4,
+        description: "Create inventories",
+        sqlite: &["CREATE TABLE IF NOT EXISTS inventories (
+            citizen_id INTEGER NOT NULL,
+            item VARCHAR(64) NOT NULL,
+            quantity INTEGER NOT NULL,
+            PRIMARY KEY (citizen_id, item))"],
+        mysql: &["CREATE TABLE IF NOT EXISTS inventories (
+            citizen_id INTEGER NOT NULL,
+            item VARCHAR(64) NOT NULL,
+            quantity INTEGER UNSIGNED NOT NULL,
+            PRIMARY KEY (citizen_id, item))"],
+    },
+    Migration {
+        version: 5,
+        description: "Create key_values",
+        sqlite: &["CREATE TABLE IF NOT EXISTS key_values (
+            namespace VARCHAR(64) NOT NULL,
+            citizen_id INTEGER NOT NULL,
+            entry_key VARCHAR(128) NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (namespace, citizen_id, entry_key))"],
+        mysql: &["CREATE TABLE IF NOT EXISTS key_values (
+            namespace VARCHAR(64) NOT NULL,
+            citizen_id INTEGER NOT NULL,
+            entry_key VARCHAR(128) NOT NULL,
+            value TEXT NOT NULL,
+            PRIMARY KEY (namespace, citizen_id, entry_key))"],
+    },
+];
+
+/// Records which migrations have been applied, one row each.
+pub const CREATE_SCHEMA_VERSION: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY NOT NULL,
+            description VARCHAR(255) NOT NULL,
+            applied_at BIGINT NOT NULL)";
+
+/// Returns the migrations newer than `current_version`, in order.
+pub fn pending(current_version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.version > current_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1);
+        }
+        assert_eq!(pending(3).next().map(|m| m.version), Some(4));
+    }
+
+    #[test]
+    fn every_statement_can_be_run_again() {
+        for migration in MIGRATIONS {
+            for dialect in [Dialect::Sqlite, Dialect::Mysql] {
+                for statement in migration.statements(dialect) {
+                    assert!(
+                        statement.starts_with("CREATE TABLE IF NOT EXISTS ")
+                            || statement.starts_with("CREATE INDEX IF NOT EXISTS "),
+                        "migration {} can't be run again: {}",
+                        migration.version,
+                        statement
+                    );
+                }
+            }
+        }
+    }
+}
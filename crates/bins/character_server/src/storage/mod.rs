@@ -0,0 +1,147 @@
+use character::{InventoryItem, KeyValue, LedgerEntry, StatBar, StatKind};
+use std::fmt;
+
+mod memory;
+pub use memory::MemoryStorage;
+pub mod migrations;
+mod sql;
+pub use sql::SqlStorage;
+
+/// Where character stats are kept.
+///
+/// Every call is made while holding the server's storage lock, so implementations don't need
+/// to guard against concurrent access themselves.
+pub trait Storage: Send {
+    /// Starts a transaction. Everything up to the matching `commit_transaction` or
+    /// `rollback_transaction` takes effect all at once, or not at all.
+    fn begin_transaction(&mut self) -> StorageResult<()>;
+    fn commit_transaction(&mut self) -> StorageResult<()>;
+    fn rollback_transaction(&mut self) -> StorageResult<()>;
+
+    /// Creates a player with every stat at zero, unless they already exist.
+    fn init_player_if_not_exists(&mut self, citizen_id: u32) -> StorageResult<()>;
+    /// Whether the player has been initialized.
+    fn player_exists(&self, citizen_id: u32) -> StorageResult<bool>;
+    /// Every player that has been initialized.
+    fn citizen_ids(&self) -> StorageResult<Vec<u32>>;
+    /// Fails if the player doesn't exist.
+    fn get_stats(&self, citizen_id: u32) -> StorageResult<StoredStats>;
+    fn set_stats(&mut self, citizen_id: u32, stats: &StoredStats) -> StorageResult<()>;
+
+    /// Appends a change to the creditz ledger.
+    fn record_creditz_change(&mut self, change: &CreditzChange) -> StorageResult<()>;
+    /// Returns up to `limit` of a player's ledger entries with ids below `before`, newest
+    /// first.
+    fn creditz_history(
+        &self,
+        citizen_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> StorageResult<Vec<LedgerEntry>>;
+    /// The creditz of every player added together.
+    fn total_creditz(&self) -> StorageResult<u64>;
+
+    /// A configured stat's stored value, or `None` if it has never been set for the player.
+    fn get_named_stat(&self, citizen_id: u32, name: &str) -> StorageResult<Option<i64>>;
+    fn set_named_stat(&mut self, citizen_id: u32, name: &str, value: i64) -> StorageResult<()>;
+
+    /// How many of `item` a player has; zero if they have never had any.
+    fn item_count(&self, citizen_id: u32, item: &str) -> StorageResult<u32>;
+    /// Setting a count of zero removes the item from the player's inventory.
+    fn set_item_count(&mut self, citizen_id: u32, item: &str, quantity: u32) -> StorageResult<()>;
+    /// Every item a player has at least one of, by name.
+    fn inventory(&self, citizen_id: u32) -> StorageResult<Vec<InventoryItem>>;
+
+    fn get_value(
+        &self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+    ) -> StorageResult<Option<String>>;
+    /// Inserts or replaces a value in the key/value store.
+    fn set_value(
+        &mut self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+        value: &str,
+    ) -> StorageResult<()>;
+    /// Does nothing if the key isn't set.
+    fn delete_value(&mut self, namespace: &str, citizen_id: u32, key: &str) -> StorageResult<()>;
+    /// Every value a player has in a namespace, sorted by key.
+    fn values(&self, namespace: &str, citizen_id: u32) -> StorageResult<Vec<KeyValue>>;
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug, Clone)]
+pub struct StorageError(String);
+
+impl StorageError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// The stats stored for one player.
+#[derive(Debug, Clone)]
+pub struct StoredStats {
+    pub creditz: u32,
+    pub happiness: StatBar,
+    pub hunger: StatBar,
+    pub boredom: StatBar,
+}
+
+impl StoredStats {
+    /// The bar for `stat`, or `None` for creditz, which isn't one, and for configured stats and
+    /// inventories, which aren't stored here.
+    pub fn bar(&self, stat: StatKind) -> Option<&StatBar> {
+        match stat {
+            StatKind::Creditz | StatKind::Named | StatKind::Inventory => None,
+            StatKind::Happiness => Some(&self.happiness),
+            StatKind::Hunger => Some(&self.hunger),
+            StatKind::Boredom => Some(&self.boredom),
+        }
+    }
+
+    pub fn bar_mut(&mut self, stat: StatKind) -> Option<&mut StatBar> {
+        match stat {
+            StatKind::Creditz | StatKind::Named | StatKind::Inventory => None,
+            StatKind::Happiness => Some(&mut self.happiness),
+            StatKind::Hunger => Some(&mut self.hunger),
+            StatKind::Boredom => Some(&mut self.boredom),
+        }
+    }
+}
+
+impl Default for StoredStats {
+    /// A new player: every stat at zero.
+    fn default() -> Self {
+        Self {
+            creditz: 0,
+            happiness: StatBar::from_u32(0),
+            hunger: StatBar::from_u32(0),
+            boredom: StatBar::from_u32(0),
+        }
+    }
+}
+
+/// A ledger entry that hasn't been stored yet, and so has no id.
+#[derive(Debug, Clone)]
+pub struct CreditzChange {
+    pub citizen_id: u32,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub delta: i64,
+    pub balance: u32,
+    pub reason: Option<String>,
+    pub source: Option<String>,
+}
@@ -0,0 +1,498 @@
+use super::migrations::{self, Dialect, Migration};
+use super::{CreditzChange, Storage, StorageError, StorageResult, StoredStats};
+use aw_db::{Database, DatabaseConfig, DatabaseResult, DatabaseType};
+use character::{InventoryItem, KeyValue, LedgerEntry, StatBar};
+use log::{error, info};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keeps stats in a SQLite or MySQL database through `aw_db`.
+///
+/// Transactions are plain `BEGIN` and `COMMIT` statements, so they are only atomic if every
+/// statement in between runs on the same connection. `aw_db` keeps one connection per
+/// `Database`, and on MySQL each transaction checks that this still holds before committing.
+pub struct SqlStorage {
+    db: Database,
+    dialect: Dialect,
+    /// The MySQL connection the current transaction began on.
+    transaction_connection: Option<i64>,
+}
+
+impl SqlStorage {
+    /// Connects to the database and brings its schema up to date.
+    pub fn new(config: DatabaseConfig) -> StorageResult<Self> {
+        let result = Self::connect(config)?;
+
+        result.migrate()?;
+
+        Ok(result)
+    }
+
+    /// Connects to the database without touching its schema.
+    pub fn connect(config: DatabaseConfig) -> StorageResult<Self> {
+        let dialect = match &config.database_type {
+            DatabaseType::MySql => Dialect::Mysql,
+            DatabaseType::Sqlite => Dialect::Sqlite,
+        };
+        let db = Database::new(config).map_err(|e| {
+            StorageError::new(format!("Could not connect to the database: {:?}", e))
+        })?;
+        Ok(Self {
+            db,
+            dialect,
+            transaction_connection: None,
+        })
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// The version of the newest migration applied, or 0 for a database that has never been
+    /// migrated.
+    pub fn schema_version(&self) -> StorageResult<u32> {
+        let query = match self.dialect {
+            Dialect::Sqlite => {
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'"
+            }
+            Dialect::Mysql => {
+                "SELECT table_name FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'schema_version'"
+            }
+        };
+        if checked(self.db.exec(query, vec![]), query)?.is_empty() {
+            return Ok(0);
+        }
+
+        let query = "SELECT version FROM schema_version";
+        let rows = checked(self.db.exec(query, vec![]), query)?;
+        let mut version = 0;
+        for row in &rows {
+            let Some(applied) = row.fetch_int("version") else {
+                return Err(StorageError::new("Missing column version"));
+            };
+            version = version.max(u32::try_from(applied).unwrap_or(0));
+        }
+        Ok(version)
+    }
+
+    /// The migrations `migrate` would apply.
+    pub fn pending_migrations(&self) -> StorageResult<Vec<&'static Migration>> {
+        Ok(migrations::pending(self.schema_version()?).collect())
+    }
+
+    /// Applies every pending migration in order, each in its own transaction.
+    ///
+    /// MySQL commits implicitly after most schema changes, so a migration that fails there
+    /// part way through may leave its earlier statements applied. Every statement is written
+    /// to succeed if it has already been applied, so starting the server again finishes it.
+    pub fn migrate(&self) -> StorageResult<()> {
+        self.exec_statement(migrations::CREATE_SCHEMA_VERSION, vec![])?;
+
+        for migration in self.pending_migrations()? {
+            info!(
+                "Applying migration {}: {}",
+                migration.version, migration.description
+            );
+            self.exec_statement("BEGIN", vec![])?;
+            if let Err(e) = self.apply_migration(migration) {
+                if let Err(rollback_error) = self.exec_statement("ROLLBACK", vec![]) {
+                    error!(
+                        "Failed to roll back migration {}: {}",
+                        migration.version, rollback_error
+                    );
+                }
+                return Err(StorageError::new(format!(
+                    "Migration {} failed: {}",
+                    migration.version, e
+                )));
+            }
+            self.exec_statement("COMMIT", vec![])?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> StorageResult<()> {
+        for statement in migration.statements(self.dialect) {
+            self.exec_statement(statement, vec![])?;
+        }
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.exec_statement(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+            vec![
+                migration.version.to_string(),
+                migration.description.to_string(),
+                applied_at.to_string(),
+            ],
+        )
+    }
+
+    fn exec_statement(&self, statement: &str, params: Vec<String>) -> StorageResult<()> {
+        checked(self.db.exec(statement, params), statement).map(|_| ())
+    }
+
+    /// The id of the MySQL connection statements are running on. SQLite has only the one.
+    fn connection_id(&self) -> StorageResult<Option<i64>> {
+        if self.dialect == Dialect::Sqlite {
+            return Ok(None);
+        }
+        let query = "SELECT CONNECTION_ID() AS connection_id";
+        let rows = checked(self.db.exec(query, vec![]), query)?;
+        rows.first()
+            .and_then(|row| row.fetch_int("connection_id"))
+            .map(Some)
+            .ok_or_else(|| StorageError::new("Missing column connection_id"))
+    }
+}
+
+/// Converts an `aw_db` result, naming the statement if it failed.
+fn checked<T>(result: DatabaseResult<T>, statement: &str) -> StorageResult<T> {
+    match result {
+        DatabaseResult::Ok(value) => Ok(value),
+        DatabaseResult::DatabaseError => {
+            Err(StorageError::new(format!("Query failed: {}", statement)))
+        }
+    }
+}
+
+impl Storage for SqlStorage {
+    fn begin_transaction(&mut self) -> StorageResult<()> {
+        self.exec_statement("BEGIN", vec![])?;
+        self.transaction_connection = self.connection_id()?;
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> StorageResult<()> {
+        let began_on = self.transaction_connection.take();
+        if self.connection_id()? != began_on {
+            error!("A transaction moved between database connections; it was not atomic.");
+            let _ = self.exec_statement("ROLLBACK", vec![]);
+            return Err(StorageError::new(
+                "The transaction's statements ran on more than one database connection",
+            ));
+        }
+        self.exec_statement("COMMIT", vec![])
+    }
+
+    fn rollback_transaction(&mut self) -> StorageResult<()> {
+        self.transaction_connection = None;
+        self.exec_statement("ROLLBACK", vec![])
+    }
+
+    fn init_player_if_not_exists(&mut self, citizen_id: u32) -> StorageResult<()> {
+        // SQLite and MySQL spell "insert unless present" differently, so check first. Callers
+        // hold the storage lock, so nothing can insert the player in between.
+        if self.player_exists(citizen_id)? {
+            return Ok(());
+        }
+
+        self.exec_statement(
+            "INSERT INTO miuchiz_stats (citizen_id) VALUES (?)",
+            vec![citizen_id.to_string()],
+        )
+    }
+
+    fn player_exists(&self, citizen_id: u32) -> StorageResult<bool> {
+        let query = "SELECT citizen_id FROM miuchiz_stats WHERE citizen_id = ?";
+        let existing = checked(self.db.exec(query, vec![citizen_id.to_string()]), query)?;
+        Ok(!existing.is_empty())
+    }
+
+    fn citizen_ids(&self) -> StorageResult<Vec<u32>> {
+        let query = "SELECT citizen_id FROM miuchiz_stats";
+        let rows = checked(self.db.exec(query, vec![]), query)?;
+        rows.iter()
+            .map(|row| {
+                row.fetch_int("citizen_id")
+                    .and_then(|id| u32::try_from(id).ok())
+                    .ok_or_else(|| StorageError::new("Missing column citizen_id"))
+            })
+            .collect()
+    }
+
+    fn get_stats(&self, citizen_id: u32) -> StorageResult<StoredStats> {
+        let query = "SELECT * FROM miuchiz_stats WHERE citizen_id = ?";
+        let rows = checked(self.db.exec(query, vec![citizen_id.to_string()]), query)?;
+
+        if rows.len() > 1 {
+            return Err(StorageError::new(format!(
+                "Found {} rows for player {}",
+                rows.len(),
+                citizen_id
+            )));
+        }
+
+        let Some(row) = rows.first() else {
+            return Err(StorageError::new(format!("No player {}", citizen_id)));
+        };
+
+        let fetch = |column: &str| {
+            row.fetch_int(column)
+                .ok_or_else(|| StorageError::new(format!("Missing column {}", column)))
+        };
+        let creditz_i64 = fetch("creditz")?;
+        let happiness_i64 = fetch("happiness")?;
+        let hunger_i64 = fetch("hunger")?;
+        let boredom_i64 = fetch("boredom")?;
+
+        let creditz = u32::try_from(creditz_i64).unwrap_or(0);
+        let happiness = u32::try_from(happiness_i64).unwrap_or(0);
+        let hunger = u32::try_from(hunger_i64).unwrap_or(0);
+        let boredom = u32::try_from(boredom_i64).unwrap_or(0);
+
+        Ok(StoredStats {
+            creditz,
+            happiness: StatBar::from_u32(happiness),
+            hunger: StatBar::from_u32(hunger),
+            boredom: StatBar::from_u32(boredom),
+        })
+    }
+
+    fn set_stats(&mut self, citizen_id: u32, stats: &StoredStats) -> StorageResult<()> {
+        let creditz = stats.creditz.to_string();
+        let happiness = stats.happiness.to_u32().to_string();
+        let hunger = stats.hunger.to_u32().to_string();
+        let boredom = stats.boredom.to_u32().to_string();
+
+        self.exec_statement(
+            "UPDATE miuchiz_stats SET creditz = ?, happiness = ?, hunger = ?, boredom = ? WHERE citizen_id = ?",
+            vec![creditz, happiness, hunger, boredom, citizen_id.to_string()],
+        )
+    }
+
+    fn record_creditz_change(&mut self, change: &CreditzChange) -> StorageResult<()> {
+        // aw_db can't bind NULL, so a missing reason or source is stored as an empty string.
+        self.exec_statement(
+            "INSERT INTO creditz_ledger (citizen_id, created_at, delta, balance, reason, source) VALUES (?, ?, ?, ?, ?, ?)",
+            vec![
+                change.citizen_id.to_string(),
+                change.timestamp.to_string(),
+                change.delta.to_string(),
+                change.balance.to_string(),
+                change.reason.clone().unwrap_or_default(),
+                change.source.clone().unwrap_or_default(),
+            ],
+        )
+    }
+
+    fn creditz_history(
+        &self,
+        citizen_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> StorageResult<Vec<LedgerEntry>> {
+        // MySQL won't take a string parameter for LIMIT, so it is formatted in; it's a number,
+        // so this is safe.
+        let query = format!(
+            "SELECT id, created_at, delta, balance, reason, source FROM creditz_ledger WHERE citizen_id = ? AND id < ? ORDER BY id DESC LIMIT {}",
+            limit
+        );
+        let before = before.unwrap_or(i64::MAX as u64);
+        let rows = checked(
+            self.db
+                .exec(&query, vec![citizen_id.to_string(), before.to_string()]),
+            &query,
+        )?;
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let fetch = |column: &str| {
+                row.fetch_int(column)
+                    .ok_or_else(|| StorageError::new(format!("Missing column {}", column)))
+            };
+            let text = |column: &str| row.fetch_string(column).filter(|value| !value.is_empty());
+            entries.push(LedgerEntry {
+                id: u64::try_from(fetch("id")?).unwrap_or(0),
+                user_id: citizen_id,
+                timestamp: u64::try_from(fetch("created_at")?).unwrap_or(0),
+                delta: fetch("delta")?,
+                balance: u32::try_from(fetch("balance")?).unwrap_or(0),
+                reason: text("reason"),
+                source: text("source"),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn total_creditz(&self) -> StorageResult<u64> {
+        // MySQL sums integers into a DECIMAL, so cast the sum back to one.
+        let query = match self.dialect {
+            Dialect::Sqlite => "SELECT COALESCE(SUM(creditz), 0) AS total FROM miuchiz_stats",
+            Dialect::Mysql => {
+                "SELECT CAST(COALESCE(SUM(creditz), 0) AS SIGNED) AS total FROM miuchiz_stats"
+            }
+        };
+        let rows = checked(self.db.exec(query, vec![]), query)?;
+        rows.first()
+            .and_then(|row| row.fetch_int("total"))
+            .and_then(|total| u64::try_from(total).ok())
+            .ok_or_else(|| StorageError::new("Missing column total"))
+    }
+
+    fn get_named_stat(&self, citizen_id: u32, name: &str) -> StorageResult<Option<i64>> {
+        let query = "SELECT value FROM named_stats WHERE citizen_id = ? AND name = ?";
+        let rows = checked(
+            self.db
+                .exec(query, vec![citizen_id.to_string(), name.to_string()]),
+            query,
+        )?;
+        match rows.first() {
+            Some(row) => row
+                .fetch_int("value")
+                .map(Some)
+                .ok_or_else(|| StorageError::new("Missing column value")),
+            None => Ok(None),
+        }
+    }
+
+    fn set_named_stat(&mut self, citizen_id: u32, name: &str, value: i64) -> StorageResult<()> {
+        // As with players, check first rather than relying on either dialect's upsert.
+        let statement = if self.get_named_stat(citizen_id, name)?.is_some() {
+            "UPDATE named_stats SET value = ? WHERE citizen_id = ? AND name = ?"
+        } else {
+            "INSERT INTO named_stats (value, citizen_id, name) VALUES (?, ?, ?)"
+        };
+        self.exec_statement(
+            statement,
+            vec![value.to_string(), citizen_id.to_string(), name.to_string()],
+        )
+    }
+
+    fn item_count(&self, citizen_id: u32, item: &str) -> StorageResult<u32> {
+        let query = "SELECT quantity FROM inventories WHERE citizen_id = ? AND item = ?";
+        let rows = checked(
+            self.db
+                .exec(query, vec![citizen_id.to_string(), item.to_string()]),
+            query,
+        )?;
+        match rows.first() {
+            Some(row) => row
+                .fetch_int("quantity")
+                .map(|quantity| u32::try_from(quantity).unwrap_or(0))
+                .ok_or_else(|| StorageError::new("Missing column quantity")),
+            None => Ok(0),
+        }
+    }
+
+    fn set_item_count(&mut self, citizen_id: u32, item: &str, quantity: u32) -> StorageResult<()> {
+        if quantity == 0 {
+            return self.exec_statement(
+                "DELETE FROM inventories WHERE citizen_id = ? AND item = ?",
+                vec![citizen_id.to_string(), item.to_string()],
+            );
+        }
+
+        let statement = if self.item_count(citizen_id, item)? > 0 {
+            "UPDATE inventories SET quantity = ? WHERE citizen_id = ? AND item = ?"
+        } else {
+            "INSERT INTO inventories (quantity, citizen_id, item) VALUES (?, ?, ?)"
+        };
+        self.exec_statement(
+            statement,
+            vec![
+                quantity.to_string(),
+                citizen_id.to_string(),
+                item.to_string(),
+            ],
+        )
+    }
+
+    fn inventory(&self, citizen_id: u32) -> StorageResult<Vec<InventoryItem>> {
+        let query = "SELECT item, quantity FROM inventories WHERE citizen_id = ? ORDER BY item";
+        let rows = checked(self.db.exec(query, vec![citizen_id.to_string()]), query)?;
+        rows.iter()
+            .map(|row| {
+                let item = row
+                    .fetch_string("item")
+                    .ok_or_else(|| StorageError::new("Missing column item"))?;
+                let quantity = row
+                    .fetch_int("quantity")
+                    .ok_or_else(|| StorageError::new("Missing column quantity"))?;
+                Ok(InventoryItem {
+                    item,
+                    quantity: u32::try_from(quantity).unwrap_or(0),
+                })
+            })
+            .collect()
+    }
+
+    fn get_value(
+        &self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+    ) -> StorageResult<Option<String>> {
+        let query =
+            "SELECT value FROM key_values WHERE namespace = ? AND citizen_id = ? AND entry_key = ?";
+        let params = vec![
+            namespace.to_string(),
+            citizen_id.to_string(),
+            key.to_string(),
+        ];
+        let rows = checked(self.db.exec(query, params), query)?;
+        match rows.first() {
+            Some(row) => row
+                .fetch_string("value")
+                .map(Some)
+                .ok_or_else(|| StorageError::new("Missing column value")),
+            None => Ok(None),
+        }
+    }
+
+    fn set_value(
+        &mut self,
+        namespace: &str,
+        citizen_id: u32,
+        key: &str,
+        value: &str,
+    ) -> StorageResult<()> {
+        let statement = if self.get_value(namespace, citizen_id, key)?.is_some() {
+            "UPDATE key_values SET value = ? WHERE namespace = ? AND citizen_id = ? AND entry_key = ?"
+        } else {
+            "INSERT INTO key_values (value, namespace, citizen_id, entry_key) VALUES (?, ?, ?, ?)"
+        };
+        self.exec_statement(
+            statement,
+            vec![
+                value.to_string(),
+                namespace.to_string(),
+                citizen_id.to_string(),
+                key.to_string(),
+            ],
+        )
+    }
+
+    fn delete_value(&mut self, namespace: &str, citizen_id: u32, key: &str) -> StorageResult<()> {
+        self.exec_statement(
+            "DELETE FROM key_values WHERE namespace = ? AND citizen_id = ? AND entry_key = ?",
+            vec![
+                namespace.to_string(),
+                citizen_id.to_string(),
+                key.to_string(),
+            ],
+        )
+    }
+
+    fn values(&self, namespace: &str, citizen_id: u32) -> StorageResult<Vec<KeyValue>> {
+        let query = "SELECT entry_key, value FROM key_values WHERE namespace = ? AND citizen_id = ? ORDER BY entry_key";
+        let rows = checked(
+            self.db
+                .exec(query, vec![namespace.to_string(), citizen_id.to_string()]),
+            query,
+        )?;
+        rows.iter()
+            .map(|row| {
+                let text = |column: &str| {
+                    row.fetch_string(column)
+                        .ok_or_else(|| StorageError::new(format!("Missing column {}", column)))
+                };
+                Ok(KeyValue {
+                    key: text("entry_key")?,
+                    value: text("value")?,
+                })
+            })
+            .collect()
+    }
+}
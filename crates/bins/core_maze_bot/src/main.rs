@@ -8,7 +8,7 @@ use aw_sdk::{
     AvatarChangeInfo, AwEvent, AwInstance, LoginParams, ObjectClickInfo, SdkError, SdkResult,
     StateChangeParams, TeleportParams,
 };
-use character::CharacterClient;
+use character::{CharacterClient, CharacterError, Op, ServerError};
 
 // =================================================================================================
 //                                         CONFIGURATION
@@ -113,8 +113,11 @@ impl CoreMazeBot {
             AwInstance::new("127.0.0.1", 6670).expect("Failed to create TicketTaker instance");
         let core_maze =
             AwInstance::new("127.0.0.1", 6670).expect("Failed to create CoreMaze instance");
-        let client = CharacterClient::connect("127.0.0.1:6675")
-            .expect("Failed to connect to character server");
+        // Needs the creditz and stat write scopes, to charge for tickets and pay out.
+        let character_token = std::env::var("CHARACTER_TOKEN").ok();
+        let client =
+            CharacterClient::connect_with_token("127.0.0.1:6675", character_token.as_deref())
+                .expect("Failed to connect to character server");
 
         Self {
             ticket_taker,
@@ -427,13 +430,19 @@ impl CoreMazeBot {
 
             if ticket_holders.contains_key(&citizen_id) {
                 // Player is returning a ticket
-                self.client.add_creditz(citizen_id, TICKET_PRICE).ok();
+                self.client
+                    .add_creditz_with_reason(citizen_id, TICKET_PRICE, "refund:CoreMaze")
+                    .ok();
                 ticket_holders.remove(&citizen_id);
                 self.ticket_taker
                     .say(&format!("{} has returned their ticket.", click.avatar_name))?;
             } else {
                 // Player is buying a ticket
-                match self.client.sub_creditz(citizen_id, TICKET_PRICE) {
+                match self.client.sub_creditz_with_reason(
+                    citizen_id,
+                    TICKET_PRICE,
+                    "ticket:CoreMaze",
+                ) {
                     Ok(_) => {
                         let player_info = PlayerInfo {
                             citizen_id,
@@ -447,12 +456,22 @@ impl CoreMazeBot {
                             ticket_holders.len()
                         ))?;
                     }
-                    Err(_) => {
+                    Err(CharacterError::Server(ServerError::InsufficientFunds)) => {
                         self.ticket_taker.say(&format!(
                             "Sorry {}, you don't have enough creditz to buy a ticket.",
                             click.avatar_name
                         ))?;
                     }
+                    Err(e) => {
+                        println!(
+                            "Failed to sell a CoreMaze ticket to citizen {}: {}",
+                            citizen_id, e
+                        );
+                        self.ticket_taker.say(&format!(
+                            "Sorry {}, tickets can't be sold right now. Please try again later.",
+                            click.avatar_name
+                        ))?;
+                    }
                 }
             }
         } else {
@@ -471,17 +490,16 @@ impl CoreMazeBot {
                 "{} collected {} points",
                 player.name, player.score
             ))?;
+            // Pay out the score and stat bonuses together, so a failure can't leave a
+            // player partly rewarded.
+            let payout = vec![
+                Op::AddCreditz(player.citizen_id, player.score),
+                Op::AddHappiness(player.citizen_id, 0.1),
+                Op::AddBoredom(player.citizen_id, 0.25),
+            ];
             self.client
-                .add_creditz(player.citizen_id, player.score)
+                .transaction_with_reason(payout, "prize:CoreMaze")
                 .ok();
-            if let Ok(mut happiness) = self.client.get_happiness(player.citizen_id) {
-                happiness += 0.1;
-                self.client.set_happiness(player.citizen_id, happiness).ok();
-            }
-            if let Ok(mut boredom) = self.client.get_boredom(player.citizen_id) {
-                boredom += 0.25;
-                self.client.set_boredom(player.citizen_id, boredom).ok();
-            }
         }
 
         self.core_maze
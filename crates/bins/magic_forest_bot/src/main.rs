@@ -36,6 +36,8 @@ pub struct MagicForestBotConfig {
     pub port: u16,
     pub character_host: String,
     pub character_port: u16,
+    #[serde(default)]
+    pub character_token: Option<String>,
 
     pub owner_id: u32,
     pub privilege_password: String,
@@ -85,6 +87,7 @@ fn main() {
             port: config.port,
             character_host: config.character_host.clone(),
             character_port: config.character_port,
+            character_token: config.character_token.clone(),
             owner_id: config.owner_id,
             privilege_password: config.privilege_password.clone(),
             bump_keyword: "PawzRacer".to_string(),
@@ -5,7 +5,7 @@ use aw_sdk::{
     AvatarAddInfo, AvatarDeleteInfo, AwEvent, AwInstance, HudCreateParams, HudElementFlags,
     HudOrigin, HudType, LoginParams, SdkResult, StateChangeParams,
 };
-use character::{CharacterClient, Notification, StatBar};
+use character::{Character, CharacterClient, CharacterError, Notification, StatBar, StatKind};
 
 const HUD_FRAME_ELEMENT_ID: u32 = 1;
 const HUD_CREDITZ_ELEMENT_ID: u32 = 2;
@@ -28,6 +28,17 @@ struct PlayerHudState {
     boredom: StatBar,
 }
 
+impl From<Character> for PlayerHudState {
+    fn from(character: Character) -> Self {
+        Self {
+            creditz: character.creditz,
+            happiness: character.happiness,
+            hunger: character.hunger,
+            boredom: character.boredom,
+        }
+    }
+}
+
 use clap::Parser;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,6 +47,9 @@ pub struct StatsHudBotConfig {
     pub port: u16,
     pub character_host: String,
     pub character_port: u16,
+    /// Only needs the read scope.
+    #[serde(default)]
+    pub character_token: Option<String>,
 
     pub owner_id: u32,
     pub privilege_password: String,
@@ -63,7 +77,11 @@ impl StatsHudBot {
             format!("{}:{}", config.character_host, config.character_port);
         Self {
             instance: AwInstance::new(&config.host, config.port).unwrap(),
-            client: CharacterClient::connect(&character_server_address).unwrap(),
+            client: CharacterClient::connect_with_token(
+                &character_server_address,
+                config.character_token.as_deref(),
+            )
+            .unwrap(),
             hud_states: HashMap::new(),
             session_to_citizen: HashMap::new(),
             citizen_to_session: HashMap::new(),
@@ -108,6 +126,10 @@ impl StatsHudBot {
                         self.on_notification(notif)?;
                     }
                 }
+                Err(CharacterError::ResyncRequired) => {
+                    println!("[Missed some stat changes; refreshing every HUD]");
+                    self.resync()?;
+                }
                 Err(e) => {
                     println!("[Error checking server events: {}]", e);
                 }
@@ -145,6 +167,10 @@ impl StatsHudBot {
                 }
                 cid
             }
+            // The HUD only shows the built-in stats.
+            Notification::StatChanged { .. }
+            | Notification::ItemGranted { .. }
+            | Notification::ItemConsumed { .. } => return Ok(()),
         };
 
         if let Some(session_id) = self.citizen_to_session.get(&citizen_id).cloned() {
@@ -160,17 +186,11 @@ impl StatsHudBot {
             return Ok(());
         };
 
-        let creditz = self.client.get_creditz(citizen_id).unwrap_or(0);
-        let happiness = self.client.get_happiness(citizen_id).unwrap_or(0.0);
-        let hunger = self.client.get_hunger(citizen_id).unwrap_or(0.0);
-        let boredom = self.client.get_boredom(citizen_id).unwrap_or(0.0);
-
-        let player_hud_state = PlayerHudState {
-            creditz,
-            happiness: StatBar::from_f32(happiness),
-            hunger: StatBar::from_f32(hunger),
-            boredom: StatBar::from_f32(boredom),
-        };
+        // Subscribe before reading, so that no change can slip in between the two.
+        if let Err(e) = self.client.subscribe(&[citizen_id], &StatKind::BUILT_IN) {
+            println!("[Failed to subscribe to stats for {}: {}]", citizen_id, e);
+        }
+        let player_hud_state = self.fetch_hud_state(citizen_id);
 
         self.hud_states.insert(citizen_id, player_hud_state);
         self.session_to_citizen.insert(session_id, citizen_id);
@@ -180,11 +200,52 @@ impl StatsHudBot {
         Ok(())
     }
 
+    /// Re-reads the stats of everyone with a HUD, after notifications were lost.
+    fn resync(&mut self) -> SdkResult<()> {
+        let citizen_ids: Vec<u32> = self.hud_states.keys().copied().collect();
+        let characters = match self.client.get_characters(&citizen_ids) {
+            Ok(characters) => characters,
+            Err(e) => {
+                println!("[Failed to resync HUD states: {}]", e);
+                return Ok(());
+            }
+        };
+        for character in characters {
+            let citizen_id = character.user_id;
+            self.hud_states.insert(citizen_id, character.into());
+            if let Some(session_id) = self.citizen_to_session.get(&citizen_id).cloned() {
+                self.render_hud_for(session_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_hud_state(&self, citizen_id: u32) -> PlayerHudState {
+        match self.client.get_character(citizen_id) {
+            Ok(character) => character.into(),
+            Err(e) => {
+                println!("[Failed to load stats for {}: {}]", citizen_id, e);
+                PlayerHudState {
+                    creditz: 0,
+                    happiness: StatBar::from_u32(0),
+                    hunger: StatBar::from_u32(0),
+                    boredom: StatBar::from_u32(0),
+                }
+            }
+        }
+    }
+
     fn on_avatar_delete(&mut self, avatar_delete: &AvatarDeleteInfo) -> SdkResult<()> {
         let session_id = avatar_delete.session_id;
         if let Some(citizen_id) = self.session_to_citizen.remove(&session_id) {
             self.citizen_to_session.remove(&citizen_id);
             self.hud_states.remove(&citizen_id);
+            if let Err(e) = self.client.unsubscribe(&[citizen_id], &StatKind::BUILT_IN) {
+                println!(
+                    "[Failed to unsubscribe from stats for {}: {}]",
+                    citizen_id, e
+                );
+            }
         }
 
         // No need to destroy the HUD elements for this, since the user is gone
@@ -0,0 +1,780 @@
+use crate::client::{
+    ClientOptions, Handshake, NegotiatedProtocol, SETUP_REQUEST_ID, check_frame_size, session_setup,
+};
+use crate::connect::{Attempts, ConnectionState};
+use crate::error::CharacterError;
+use crate::protocol::{
+    Character, ClientMessage, Hello, InventoryItem, KeyValue, LedgerEntry, Notification, Op,
+    OperationId, Request, RequestId, Response, ServerMessage, StatDelta, StatInfo, StatKind,
+    StatValue, capability,
+};
+use crate::subscription::Subscriptions;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// An async client for interacting with the character server.
+///
+/// A background task owns the connection. It writes requests, routes each response to its
+/// caller by request ID, forwards notifications to the receiver returned by `connect`, and
+/// reconnects (resending anything still in flight) when the connection drops.
+pub struct AsyncCharacterClient {
+    commands: mpsc::UnboundedSender<Command>,
+    shared: Arc<Shared>,
+    /// Random identity used to scope this client's operation IDs on the server.
+    client_id: u64,
+    next_sequence: AtomicU64,
+    next_request_id: AtomicU64,
+}
+
+/// A request handed from a caller to the connection task.
+struct Command {
+    request_id: RequestId,
+    payload: Vec<u8>,
+    reply: oneshot::Sender<Response>,
+}
+
+/// A request that has been queued, and where its response will arrive.
+struct Reply {
+    receiver: oneshot::Receiver<Response>,
+    /// When to stop waiting for the response, if ever.
+    deadline: Option<Instant>,
+}
+
+impl Reply {
+    /// Waits for the response until the deadline. A request that times out is dropped by the
+    /// connection task rather than resent after a reconnect.
+    async fn wait(self) -> Result<Response, CharacterError> {
+        let response = async {
+            self.receiver
+                .await
+                .map_err(|_| CharacterError::ConnectionClosed)
+        };
+        match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, response)
+                .await
+                .map_err(|_| CharacterError::Timeout)?,
+            None => response.await,
+        }
+    }
+}
+
+/// The receiving end for notifications. After a reconnect, the notifications missed in the
+/// meantime are replayed; if the server no longer has them all, a
+/// `CharacterError::ResyncRequired` arrives instead, and whatever the receiver tracks should
+/// be fetched afresh.
+pub type NotificationReceiver = mpsc::UnboundedReceiver<Result<Notification, CharacterError>>;
+
+/// The session state shared between the client handles and the connection task.
+struct Shared {
+    /// Presented on every (re)connect, if the client was created with a token.
+    token: Option<String>,
+    options: ClientOptions,
+    protocol: Mutex<NegotiatedProtocol>,
+    /// Mirrors the server's subscriptions for this client, to restore them after a reconnect.
+    subscriptions: Mutex<Subscriptions>,
+    /// The sequence number of the latest notification received, to resume from after a
+    /// reconnect. `None` until the client first subscribes.
+    last_sequence: Mutex<Option<u64>>,
+}
+
+/// What the reader task reports back to the connection task.
+enum ReaderEvent {
+    Message(ServerMessage),
+    Disconnected(CharacterError),
+}
+
+impl AsyncCharacterClient {
+    /// Connects to the character server and returns a new client, along with the receiver
+    /// that notifications will be delivered to.
+    ///
+    /// This waits until a connection is established or the connection policy gives up, and
+    /// fails if the server speaks an incompatible protocol version. The connection is anonymous.
+    pub async fn connect(addr: &str) -> Result<(Self, NotificationReceiver), CharacterError> {
+        Self::connect_with_token(addr, None).await
+    }
+
+    /// Connects like `connect`, then authenticates with `token` if one is given. The token is
+    /// presented again after every reconnect.
+    pub async fn connect_with_token(
+        addr: &str,
+        token: Option<&str>,
+    ) -> Result<(Self, NotificationReceiver), CharacterError> {
+        Self::connect_with_options(addr, token, ClientOptions::default()).await
+    }
+
+    /// Connects like `connect_with_token`, with `options` in place of the defaults.
+    pub async fn connect_with_options(
+        addr: &str,
+        token: Option<&str>,
+        options: ClientOptions,
+    ) -> Result<(Self, NotificationReceiver), CharacterError> {
+        let server_addrs: Vec<String> = std::iter::once(addr.to_string())
+            .chain(options.fallback_addrs.iter().cloned())
+            .collect();
+        let setup = session_setup(token, &Subscriptions::new(), None);
+        let (stream, handshake) = establish_connection(&server_addrs, &setup, &options).await?;
+        let shared = Arc::new(Shared {
+            token: token.map(str::to_string),
+            options,
+            protocol: Mutex::new(handshake.protocol.clone()),
+            subscriptions: Mutex::new(Subscriptions::new()),
+            last_sequence: Mutex::new(None),
+        });
+
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (notification_tx, notifications) = mpsc::unbounded_channel();
+        shared.absorb_handshake(handshake, &notification_tx);
+        tokio::spawn(run_connection(
+            server_addrs,
+            shared.clone(),
+            stream,
+            command_rx,
+            notification_tx,
+        ));
+
+        let client = Self {
+            commands,
+            shared,
+            client_id: RandomState::new().build_hasher().finish(),
+            next_sequence: AtomicU64::new(0),
+            next_request_id: AtomicU64::new(0),
+        };
+        Ok((client, notifications))
+    }
+
+    /// The protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.shared.protocol.lock().unwrap().version
+    }
+
+    /// Returns true if both this client and the server support `capability`.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.shared
+            .protocol
+            .lock()
+            .unwrap()
+            .has_capability(capability)
+    }
+
+    /// A helper to send a request and wait for its response.
+    async fn request(&self, request: Request) -> Result<Response, CharacterError> {
+        self.send(request, None)?.wait().await
+    }
+
+    /// Like `request`, with a reason recorded in the ledger for any creditz it changes.
+    async fn request_with_reason(
+        &self,
+        request: Request,
+        reason: &str,
+    ) -> Result<Response, CharacterError> {
+        self.send(request, Some(reason))?.wait().await
+    }
+
+    /// Queues a request without waiting, returning where its response will arrive.
+    ///
+    /// Mutating requests are tagged with a fresh `OperationId` here, so a request resent
+    /// after a reconnect is recognised by the server and not applied twice.
+    fn send(&self, request: Request, reason: Option<&str>) -> Result<Reply, CharacterError> {
+        let operation_id = request.is_mutating().then(|| OperationId {
+            client_id: self.client_id,
+            sequence: self.next_sequence.fetch_add(1, Ordering::Relaxed),
+        });
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let message = ClientMessage::Request {
+            request_id,
+            operation_id,
+            reason: reason.map(str::to_string),
+            request,
+        };
+        let payload = bincode::serialize(&message)?;
+        check_frame_size(payload.len(), self.shared.options.max_frame_size)?;
+
+        let deadline = self
+            .shared
+            .options
+            .request_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let (reply, receiver) = oneshot::channel();
+        self.commands
+            .send(Command {
+                request_id,
+                payload,
+                reply,
+            })
+            .map_err(|_| CharacterError::ConnectionClosed)?;
+        Ok(Reply { receiver, deadline })
+    }
+
+    /// Sends every request back-to-back and then waits for all of the responses, so the
+    /// whole batch costs a single round trip. Responses are returned in request order.
+    pub async fn pipeline(&self, requests: Vec<Request>) -> Result<Vec<Response>, CharacterError> {
+        let replies = requests
+            .into_iter()
+            .map(|request| self.send(request, None))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut responses = Vec::with_capacity(replies.len());
+        for reply in replies {
+            responses.push(reply.wait().await?);
+        }
+        Ok(responses)
+    }
+
+    crate::methods::request_methods!(async);
+
+    /// Sends a `Subscribe` or `Unsubscribe`, and records it once the server has applied it.
+    async fn update_subscriptions(&self, request: Request) -> Result<(), CharacterError> {
+        match self.request(request.clone()).await? {
+            Response::Subscribed { sequence } => {
+                self.shared.subscriptions.lock().unwrap().apply(&request);
+                // Nothing has been missed up to here, so a reconnect can resume from this point
+                // even if no notification has arrived yet.
+                self.shared
+                    .last_sequence
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(sequence);
+                Ok(())
+            }
+            Response::Error(e) => Err(CharacterError::Server(e)),
+            _ => Err(CharacterError::UnexpectedPacket),
+        }
+    }
+}
+
+impl Shared {
+    fn deliver(
+        &self,
+        sequence: u64,
+        notification: Notification,
+        notifications: &mpsc::UnboundedSender<Result<Notification, CharacterError>>,
+    ) {
+        *self.last_sequence.lock().unwrap() = Some(sequence);
+        // Nobody listening for notifications is fine.
+        let _ = notifications.send(Ok(notification));
+    }
+
+    /// Takes on the protocol and any notifications from a fresh connection's handshake.
+    fn absorb_handshake(
+        &self,
+        handshake: Handshake,
+        notifications: &mpsc::UnboundedSender<Result<Notification, CharacterError>>,
+    ) {
+        *self.protocol.lock().unwrap() = handshake.protocol;
+        for (sequence, notification) in handshake.notifications {
+            self.deliver(sequence, notification, notifications);
+        }
+        if handshake.resync_required {
+            warn!("Notifications were missed while reconnecting; a resync is required.");
+            let _ = notifications.send(Err(CharacterError::ResyncRequired));
+        }
+    }
+}
+
+// --- Connection Task ---
+
+/// Owns the connection until every `AsyncCharacterClient` handle has been dropped, or the
+/// server can no longer be reached with a compatible protocol.
+async fn run_connection(
+    server_addrs: Vec<String>,
+    shared: Arc<Shared>,
+    stream: TcpStream,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    notifications: mpsc::UnboundedSender<Result<Notification, CharacterError>>,
+) {
+    let max_frame_size = shared.options.max_frame_size;
+    let (reader, mut writer) = stream.into_split();
+    // Each connection gets its own event channel, so nothing a replaced reader task reported
+    // can be mistaken for news about its replacement.
+    let (event_tx, mut events) = mpsc::unbounded_channel();
+    let mut reader_task = tokio::spawn(read_loop(reader, event_tx, max_frame_size));
+    // Requests still waiting for a response, with their payloads for resending.
+    let mut pending: HashMap<RequestId, (Vec<u8>, oneshot::Sender<Response>)> = HashMap::new();
+    let mut heartbeat = shared.options.ping_interval.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let mut last_received = Instant::now();
+    let mut nonce = 0;
+
+    loop {
+        // Each branch yields why the connection broke, if it did.
+        let broken = tokio::select! {
+            command = commands.recv() => {
+                let Some(command) = command else {
+                    break; // The client was dropped
+                };
+                // If the write fails the request stays pending; the reader task will report
+                // the broken connection, and the request is resent after reconnecting.
+                if let Err(e) = write_frame(&mut writer, &command.payload).await {
+                    warn!("Failed to send request: {}. Waiting for reconnect...", e);
+                }
+                pending.insert(command.request_id, (command.payload, command.reply));
+                None
+            }
+            Some(event) = events.recv() => match event {
+                ReaderEvent::Message(message) => {
+                    last_received = Instant::now();
+                    match message {
+                        ServerMessage::Response { request_id, response } => {
+                            match pending.remove(&request_id) {
+                                // The requester may have given up waiting, so a failed send
+                                // is fine.
+                                Some((_, reply)) => drop(reply.send(response)),
+                                None => warn!("Received a response to unknown request {}.", request_id),
+                            }
+                        }
+                        ServerMessage::Notification { sequence, notification } => {
+                            shared.deliver(sequence, notification, &notifications);
+                        }
+                        ServerMessage::Ping { nonce } => {
+                            // A failed write shows up as a failed read soon enough.
+                            let _ = write_message(&mut writer, &ClientMessage::Pong { nonce }).await;
+                        }
+                        // Arriving at all was the point.
+                        ServerMessage::Pong { .. } => {}
+                    }
+                    None
+                }
+                ReaderEvent::Disconnected(e) => Some(e),
+            },
+            _ = tick(&mut heartbeat) => {
+                let period = shared.options.ping_interval.unwrap_or_default();
+                if !shared.protocol.lock().unwrap().has_capability(capability::HEARTBEAT) {
+                    None
+                } else if last_received.elapsed() > period + shared.options.pong_timeout {
+                    Some(CharacterError::Timeout)
+                } else {
+                    nonce += 1;
+                    let _ = write_message(&mut writer, &ClientMessage::Ping { nonce }).await;
+                    None
+                }
+            }
+        };
+        let Some(e) = broken else {
+            continue;
+        };
+
+        warn!("Lost the server: {}. Reconnecting...", e);
+        shared.options.notify(ConnectionState::Disconnected {
+            error: e.to_string(),
+        });
+        reader_task.abort();
+        let (new_reader, new_writer) =
+            match reconnect(&server_addrs, &shared, &notifications, &mut pending).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Giving up on server: {}", e);
+                    break;
+                }
+            };
+        writer = new_writer;
+        let event_tx;
+        (event_tx, events) = mpsc::unbounded_channel();
+        reader_task = tokio::spawn(read_loop(new_reader, event_tx, max_frame_size));
+        last_received = Instant::now();
+    }
+
+    // Dropping `pending` wakes any waiting requesters with an error.
+    reader_task.abort();
+}
+
+/// Opens a new connection in place of a broken one, restoring the session and resending
+/// every request still waiting for a response.
+async fn reconnect(
+    server_addrs: &[String],
+    shared: &Shared,
+    notifications: &mpsc::UnboundedSender<Result<Notification, CharacterError>>,
+    pending: &mut HashMap<RequestId, (Vec<u8>, oneshot::Sender<Response>)>,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf), CharacterError> {
+    let setup = session_setup(
+        shared.token.as_deref(),
+        &shared.subscriptions.lock().unwrap(),
+        *shared.last_sequence.lock().unwrap(),
+    );
+    let (stream, handshake) = establish_connection(server_addrs, &setup, &shared.options).await?;
+    shared.absorb_handshake(handshake, notifications);
+    let (reader, mut writer) = stream.into_split();
+
+    // Requests whose requester stopped waiting, e.g. because they timed out, are dropped
+    // rather than applied late.
+    pending.retain(|_, (_, reply)| !reply.is_closed());
+    // Resend in the original order. Mutating requests carry operation IDs, so the server
+    // won't apply any that it already processed.
+    let mut request_ids: Vec<_> = pending.keys().copied().collect();
+    request_ids.sort_unstable();
+    for request_id in request_ids {
+        if let Err(e) = write_frame(&mut writer, &pending[&request_id].0).await {
+            warn!("Failed to resend request {}: {}", request_id, e);
+            break;
+        }
+    }
+    Ok((reader, writer))
+}
+
+/// Waits for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads messages until the connection fails, reporting each one to the connection task.
+/// Reading happens on its own task so that a partially read frame is never abandoned.
+async fn read_loop(
+    mut reader: OwnedReadHalf,
+    events: mpsc::UnboundedSender<ReaderEvent>,
+    max_frame_size: u32,
+) {
+    loop {
+        let payload = match read_frame(&mut reader, max_frame_size).await {
+            Ok(payload) => payload,
+            Err(e) => {
+                let _ = events.send(ReaderEvent::Disconnected(e));
+                return;
+            }
+        };
+        // The frame was read whole, so the stream is still in step; a message this build
+        // can't decode, e.g. one from a newer server, is skipped rather than reconnected over.
+        match bincode::deserialize::<ServerMessage>(&payload) {
+            Ok(message) => {
+                if events.send(ReaderEvent::Message(message)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!(
+                "Skipping a message from the server that could not be decoded: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// The connect loop. It tries each address in turn, backing off between attempts, until one
+/// works or the connection policy gives up. An incompatible protocol version or a refused
+/// setup request end it early, since retrying won't help with either.
+async fn establish_connection(
+    addrs: &[String],
+    setup: &[Request],
+    options: &ClientOptions,
+) -> Result<(TcpStream, Handshake), CharacterError> {
+    let mut attempts = Attempts::new(&options.connect);
+    loop {
+        let attempt = attempts.start();
+        let mut last_error = None;
+        for addr in addrs {
+            info!("Attempting to connect to server at {}...", addr);
+            options.notify(ConnectionState::Connecting {
+                addr: addr.clone(),
+                attempt,
+            });
+            let connecting = connect_to(addr, setup, options.max_frame_size);
+            let result = match attempts.deadline() {
+                Some(deadline) => tokio::time::timeout_at(Instant::from_std(deadline), connecting)
+                    .await
+                    .unwrap_or(Err(CharacterError::Timeout)),
+                None => connecting.await,
+            };
+            match result {
+                Ok((stream, handshake)) => {
+                    info!(
+                        "Successfully connected to server at {} (protocol version {}).",
+                        addr, handshake.protocol.version
+                    );
+                    options.notify(ConnectionState::Connected {
+                        addr: addr.clone(),
+                        protocol_version: handshake.protocol.version,
+                    });
+                    return Ok((stream, handshake));
+                }
+                Err(e @ CharacterError::IncompatibleProtocol { .. })
+                | Err(e @ CharacterError::Server(_)) => {
+                    options.notify(ConnectionState::GaveUp {
+                        error: e.to_string(),
+                    });
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", addr, e);
+                    options.notify(ConnectionState::Failed {
+                        addr: addr.clone(),
+                        error: e.to_string(),
+                    });
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let last_error = last_error.expect("a client always has at least one address");
+        match attempts.retry(last_error) {
+            Ok(delay) => {
+                warn!("Retrying in {:.1?}...", delay);
+                options.notify(ConnectionState::Waiting { delay });
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                options.notify(ConnectionState::GaveUp {
+                    error: e.to_string(),
+                });
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Opens a connection to `addr` and completes the handshake on it.
+async fn connect_to(
+    addr: &str,
+    setup: &[Request],
+    max_frame_size: u32,
+) -> Result<(TcpStream, Handshake), CharacterError> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let handshake = handshake(&mut stream, setup, max_frame_size).await?;
+    Ok((stream, handshake))
+}
+
+// --- Framing Helpers ---
+
+/// Exchanges `Hello`s with the server on a freshly opened stream, then sends each setup
+/// request in turn and waits for it to succeed.
+async fn handshake(
+    stream: &mut TcpStream,
+    setup: &[Request],
+    max_frame_size: u32,
+) -> Result<Handshake, CharacterError> {
+    let (mut reader, mut writer) = stream.split();
+    write_frame(&mut writer, &bincode::serialize(&Hello::current())?).await?;
+    let protocol = NegotiatedProtocol::from_reply(bincode::deserialize(
+        &read_frame(&mut reader, max_frame_size).await?,
+    )?)?;
+
+    let mut handshake = Handshake {
+        protocol,
+        notifications: Vec::new(),
+        resync_required: false,
+    };
+    for request in setup {
+        let message = ClientMessage::Request {
+            request_id: SETUP_REQUEST_ID,
+            operation_id: None,
+            reason: None,
+            request: request.clone(),
+        };
+        write_message(&mut writer, &message).await?;
+        loop {
+            match bincode::deserialize(&read_frame(&mut reader, max_frame_size).await?)? {
+                ServerMessage::Response {
+                    response: Response::Error(e),
+                    ..
+                } => return Err(CharacterError::Server(e)),
+                ServerMessage::Response {
+                    response: Response::ResyncRequired,
+                    ..
+                } => {
+                    handshake.resync_required = true;
+                    break;
+                }
+                ServerMessage::Response { .. } => break,
+                ServerMessage::Notification {
+                    sequence,
+                    notification,
+                } => handshake.notifications.push((sequence, notification)),
+                ServerMessage::Ping { nonce } => {
+                    write_message(&mut writer, &ClientMessage::Pong { nonce }).await?;
+                }
+                ServerMessage::Pong { .. } => {}
+            }
+        }
+    }
+    Ok(handshake)
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &ClientMessage,
+) -> Result<(), CharacterError> {
+    write_frame(writer, &bincode::serialize(message)?).await
+}
+
+/// Writes a bincode-serialized payload to the stream with a 4-byte length prefix.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    payload: &[u8],
+) -> Result<(), CharacterError> {
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+/// Reads a frame from the stream, expecting a 4-byte length prefix. The length is checked
+/// before anything is allocated for the frame.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> Result<Vec<u8>, CharacterError> {
+    let len = reader.read_u32().await?;
+    check_frame_size(len as usize, max_frame_size)?;
+    let mut buffer = vec![0u8; len as usize];
+    reader.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HelloReply, PROTOCOL_VERSION};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Accepts one connection and answers its `Hello`, as a server of this build with only
+    /// `capabilities` would.
+    async fn accept(listener: &TcpListener, capabilities: &[&str]) -> TcpStream {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let hello: Hello =
+            bincode::deserialize(&read_frame(&mut stream, u32::MAX).await.unwrap()).unwrap();
+        assert_eq!(hello.protocol_version, PROTOCOL_VERSION);
+        let welcome = HelloReply::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        };
+        write_frame(&mut stream, &bincode::serialize(&welcome).unwrap())
+            .await
+            .unwrap();
+        stream
+    }
+
+    async fn next_request(stream: &mut TcpStream) -> (RequestId, Request) {
+        match bincode::deserialize(&read_frame(stream, u32::MAX).await.unwrap()).unwrap() {
+            ClientMessage::Request {
+                request_id,
+                request,
+                ..
+            } => (request_id, request),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    async fn send(stream: &mut TcpStream, message: &ServerMessage) {
+        write_frame(stream, &bincode::serialize(message).unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_responses_and_notifications() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut stream = accept(&listener, &[]).await;
+            let (request_id, request) = next_request(&mut stream).await;
+            assert!(matches!(request, Request::GetCreditz(1)));
+            let notification = Notification::CreditzChanged {
+                user_id: 2,
+                new_value: 7,
+            };
+            send(
+                &mut stream,
+                &ServerMessage::Notification {
+                    sequence: 1,
+                    notification,
+                },
+            )
+            .await;
+            let response = Response::Creditz(42);
+            send(
+                &mut stream,
+                &ServerMessage::Response {
+                    request_id,
+                    response,
+                },
+            )
+            .await;
+            stream
+        });
+
+        let (client, mut notifications) = AsyncCharacterClient::connect(&addr).await.unwrap();
+
+        assert_eq!(client.get_creditz(1).await.unwrap(), 42);
+        assert!(matches!(
+            notifications.recv().await,
+            Some(Ok(Notification::CreditzChanged {
+                user_id: 2,
+                new_value: 7
+            }))
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resends_requests_in_flight_after_a_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut stream = accept(&listener, &[]).await;
+            let (first_id, _) = next_request(&mut stream).await;
+            drop(stream);
+
+            let mut stream = accept(&listener, &[]).await;
+            let (request_id, request) = next_request(&mut stream).await;
+            assert_eq!(request_id, first_id);
+            assert!(matches!(request, Request::GetCreditz(1)));
+            let response = Response::Creditz(42);
+            send(
+                &mut stream,
+                &ServerMessage::Response {
+                    request_id,
+                    response,
+                },
+            )
+            .await;
+            stream
+        });
+
+        let (client, _notifications) = AsyncCharacterClient::connect(&addr).await.unwrap();
+
+        assert_eq!(client.get_creditz(1).await.unwrap(), 42);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replaces_a_connection_that_stops_answering_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let options = ClientOptions {
+            ping_interval: Some(Duration::from_millis(20)),
+            pong_timeout: Duration::from_millis(30),
+            ..ClientOptions::default()
+        };
+        let server = tokio::spawn(async move {
+            let mut silent = accept(&listener, &[capability::HEARTBEAT]).await;
+            let frame = read_frame(&mut silent, u32::MAX).await.unwrap();
+            assert!(matches!(
+                bincode::deserialize(&frame).unwrap(),
+                ClientMessage::Ping { .. }
+            ));
+            let replacement = accept(&listener, &[capability::HEARTBEAT]);
+            tokio::time::timeout(Duration::from_secs(5), replacement)
+                .await
+                .expect("the client never reconnected");
+        });
+
+        let (_client, _notifications) =
+            AsyncCharacterClient::connect_with_options(&addr, None, options)
+                .await
+                .unwrap();
+
+        server.await.unwrap();
+    }
+}
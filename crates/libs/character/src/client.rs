@@ -1,237 +1,796 @@
+use crate::connect::{Attempts, ConnectPolicy, ConnectionState, StateListener};
 use crate::error::CharacterError;
-use crate::protocol::{Notification, Request, Response, ServerMessage};
-use log::{info, warn};
-use std::collections::VecDeque;
-use std::io::{Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::Mutex;
-use std::time::Duration;
+use crate::protocol::{
+    Character, ClientMessage, DEFAULT_MAX_FRAME_SIZE, Hello, HelloReply, InventoryItem, KeyValue,
+    LedgerEntry, MIN_PROTOCOL_VERSION, Notification, Op, OperationId, PROTOCOL_VERSION, Request,
+    RequestId, Response, ServerMessage, StatDelta, StatInfo, StatKind, StatValue, capability,
+};
+use crate::subscription::Subscriptions;
+use log::{error, info, warn};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
+
+/// The request ID used for setup requests sent during the handshake. Nothing else is in
+/// flight on the connection at that point, so it can't be confused with a real request.
+pub(crate) const SETUP_REQUEST_ID: RequestId = RequestId::MAX;
+
+/// The protocol version and capabilities agreed with the server during the handshake.
+#[derive(Debug, Clone)]
+pub(crate) struct NegotiatedProtocol {
+    pub(crate) version: u32,
+    pub(crate) capabilities: Vec<String>,
+}
+
+impl NegotiatedProtocol {
+    /// Interprets the server's answer to our `Hello`.
+    pub(crate) fn from_reply(reply: HelloReply) -> Result<Self, CharacterError> {
+        match reply {
+            HelloReply::Welcome {
+                protocol_version,
+                capabilities,
+            } => Ok(Self {
+                version: protocol_version,
+                capabilities,
+            }),
+            HelloReply::Rejected {
+                protocol_version,
+                min_protocol_version,
+            } => Err(CharacterError::IncompatibleProtocol {
+                client_version: PROTOCOL_VERSION,
+                client_min_version: MIN_PROTOCOL_VERSION,
+                server_version: protocol_version,
+                server_min_version: min_protocol_version,
+            }),
+        }
+    }
+
+    pub(crate) fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// How a `CharacterClient` or `AsyncCharacterClient` talks to the server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// The largest frame the client sends or accepts, in bytes. A larger request fails with
+    /// `CharacterError::FrameTooLarge`; a larger frame from the server is treated as a broken
+    /// connection.
+    pub max_frame_size: u32,
+    /// How often to ping the server, if it supports heartbeats. `None` never pings, so a
+    /// silently dead connection goes unnoticed until a write fails.
+    pub ping_interval: Option<Duration>,
+    /// How long the server may go on saying nothing after a ping is due before the
+    /// connection is given up as dead and replaced.
+    pub pong_timeout: Duration,
+    /// How long to wait for each response before failing with `CharacterError::Timeout`.
+    /// `None` waits for as long as it takes, across any number of reconnects.
+    pub request_timeout: Option<Duration>,
+    /// When to give up on reaching the server, and how long to wait between attempts.
+    pub connect: ConnectPolicy,
+    /// Addresses to try, in order, whenever the server's own address can't be reached.
+    pub fallback_addrs: Vec<String>,
+    /// Told about every connect, disconnect and failed attempt, in addition to the log.
+    pub on_state_change: Option<StateListener>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            ping_interval: Some(Duration::from_secs(10)),
+            pong_timeout: Duration::from_secs(10),
+            request_timeout: Some(Duration::from_secs(30)),
+            connect: ConnectPolicy::default(),
+            fallback_addrs: Vec::new(),
+            on_state_change: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    pub(crate) fn notify(&self, state: ConnectionState) {
+        if let Some(listener) = &self.on_state_change {
+            listener.notify(&state);
+        }
+    }
+}
+
+/// What a successful handshake established on a fresh connection.
+pub(crate) struct Handshake {
+    pub(crate) protocol: NegotiatedProtocol,
+    /// Notifications that arrived while the setup requests were in flight.
+    pub(crate) notifications: Vec<(u64, Notification)>,
+    /// Set if the server couldn't replay everything missed since the last connection.
+    pub(crate) resync_required: bool,
+}
 
 /// A client for interacting with the character server.
+///
+/// Every request is tagged with a `RequestId`, and a background reader thread matches
+/// responses to requests and buffers notifications. The client can therefore be shared
+/// between threads, with several requests in flight on one connection at once.
 pub struct CharacterClient {
-    server_addr: String,
-    stream: Mutex<TcpStream>,
+    connection: Arc<Connection>,
+    /// Random identity used to scope this client's operation IDs on the server.
+    client_id: u64,
+    next_sequence: AtomicU64,
+    next_request_id: AtomicU64,
+}
+
+/// The state shared between a `CharacterClient` and its reader thread.
+struct Connection {
+    /// The server's addresses followed by the fallbacks, in the order they are tried.
+    server_addrs: Vec<String>,
+    /// Presented on every (re)connect, if the client was created with a token.
+    token: Option<String>,
+    options: ClientOptions,
+    /// Mirrors the server's subscriptions for this client, to restore them after a reconnect.
+    subscriptions: Mutex<Subscriptions>,
+    /// The sequence number of the latest notification received, to resume from after a
+    /// reconnect. `None` until the client first subscribes.
+    last_sequence: Mutex<Option<u64>>,
+    /// Set when notifications were lost in a reconnect; reported once by `check_events`.
+    resync_required: AtomicBool,
+    /// The stream requests are written to. It is locked while a request is registered and
+    /// written, and while the stream is replaced after a reconnect.
+    writer: Mutex<TcpStream>,
+    /// Requests still waiting for a response, kept so they can be resent after a reconnect.
+    pending: Mutex<HashMap<RequestId, PendingRequest>>,
     notification_buffer: Mutex<VecDeque<Notification>>,
+    protocol: Mutex<NegotiatedProtocol>,
+    /// When the latest frame arrived from the server, to notice when it goes quiet.
+    last_received: Mutex<Instant>,
+    /// Set once the client has been dropped or has given up on the server.
+    closed: AtomicBool,
+}
+
+struct PendingRequest {
+    payload: Vec<u8>,
+    reply: mpsc::Sender<Response>,
+}
+
+/// A request that has been written, and where its response will arrive.
+struct Reply {
+    request_id: RequestId,
+    receiver: mpsc::Receiver<Response>,
+    /// When to stop waiting for the response, if ever.
+    deadline: Option<Instant>,
 }
 
 impl CharacterClient {
     /// Connects to the character server and returns a new client.
-    /// This will block until a connection is established.
+    /// This will block until a connection is established or the connection policy gives up,
+    /// and fails if the server speaks an incompatible protocol version.
+    ///
+    /// The connection is anonymous, so it can only do what the server allows without a token.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, CharacterError> {
-        let server_addr = addr.to_socket_addrs()?.next().unwrap().to_string();
-        let stream = Self::establish_connection(&server_addr)?;
-        Ok(Self {
-            server_addr,
-            stream: Mutex::new(stream),
-            notification_buffer: Mutex::new(VecDeque::new()),
-        })
+        Self::connect_with_token(addr, None)
     }
 
-    /// The internal reconnect loop.
-    fn establish_connection(addr: &str) -> Result<TcpStream, CharacterError> {
-        loop {
-            info!("Attempting to connect to server at {}...", addr);
-            match TcpStream::connect(addr) {
-                Ok(stream) => {
-                    info!("Successfully connected to server.");
-                    // Do NOT set a global read timeout. Let `request` calls block.
-                    return Ok(stream);
-                }
-                Err(e) => {
-                    warn!("Connection failed: {}. Retrying in 5 seconds...", e);
-                    std::thread::sleep(Duration::from_secs(5));
-                }
+    /// Connects like `connect`, then authenticates with `token` if one is given. The token is
+    /// presented again after every reconnect. Fails with `ServerError::Unauthorized` if the
+    /// server doesn't accept the token.
+    pub fn connect_with_token<A: ToSocketAddrs>(
+        addr: A,
+        token: Option<&str>,
+    ) -> Result<Self, CharacterError> {
+        Self::connect_with_options(addr, token, ClientOptions::default())
+    }
+
+    /// Connects like `connect_with_token`, with `options` in place of the defaults.
+    pub fn connect_with_options<A: ToSocketAddrs>(
+        addr: A,
+        token: Option<&str>,
+        options: ClientOptions,
+    ) -> Result<Self, CharacterError> {
+        let resolved = match addr.to_socket_addrs() {
+            Ok(resolved) => resolved.map(|addr| addr.to_string()).collect(),
+            Err(e) if options.fallback_addrs.is_empty() => return Err(e.into()),
+            Err(e) => {
+                warn!(
+                    "Failed to resolve the server's address: {}. Trying the fallbacks.",
+                    e
+                );
+                Vec::new()
             }
+        };
+        let server_addrs: Vec<String> = resolved
+            .into_iter()
+            .chain(options.fallback_addrs.iter().cloned())
+            .collect();
+        if server_addrs.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to").into(),
+            );
         }
+        let token = token.map(str::to_string);
+        let (stream, handshake) = establish_connection(
+            &server_addrs,
+            &session_setup(token.as_deref(), &Subscriptions::new(), None),
+            &options,
+            &AtomicBool::new(false),
+        )?;
+        let reader = stream.try_clone()?;
+
+        let connection = Arc::new(Connection {
+            server_addrs,
+            token,
+            options,
+            subscriptions: Mutex::new(Subscriptions::new()),
+            last_sequence: Mutex::new(None),
+            resync_required: AtomicBool::new(false),
+            writer: Mutex::new(stream),
+            pending: Mutex::new(HashMap::new()),
+            notification_buffer: Mutex::new(VecDeque::new()),
+            protocol: Mutex::new(handshake.protocol.clone()),
+            last_received: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
+        connection.absorb_handshake(handshake);
+        let reader_connection = connection.clone();
+        std::thread::spawn(move || reader_connection.read_loop(reader));
+        if let Some(interval) = connection.options.ping_interval {
+            let heartbeat_connection = connection.clone();
+            std::thread::spawn(move || heartbeat_connection.heartbeat_loop(interval));
+        }
+
+        Ok(Self {
+            connection,
+            client_id: RandomState::new().build_hasher().finish(),
+            next_sequence: AtomicU64::new(0),
+            next_request_id: AtomicU64::new(0),
+        })
+    }
+
+    /// The protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u32 {
+        self.connection.protocol.lock().unwrap().version
     }
 
-    /// A helper to send a request and receive a response, with reconnect logic.
+    /// Returns true if both this client and the server support `capability`.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.connection
+            .protocol
+            .lock()
+            .unwrap()
+            .has_capability(capability)
+    }
+
+    /// A helper to send a request and wait for its response. If the connection drops, the
+    /// reader thread reconnects and resends the request, until its deadline passes.
     fn request(&self, request: Request) -> Result<Response, CharacterError> {
-        let mut stream_lock = self.stream.lock().unwrap();
-        let payload = bincode::serialize(&request)?;
-
-        'retry_loop: loop {
-            // Attempt to write the payload.
-            if let Err(e) = write_frame(&mut *stream_lock, &payload) {
-                warn!("Failed to send request: {}. Reconnecting...", e);
-                *stream_lock = Self::establish_connection(&self.server_addr)?;
-                continue 'retry_loop; // Retry write
-            }
+        self.wait(self.send(request, None)?)
+    }
 
-            // Attempt to read a response, handling notifications that may arrive first.
-            loop {
-                match read_frame(&mut *stream_lock) {
-                    Ok(response_payload) => {
-                        match bincode::deserialize::<ServerMessage>(&response_payload)? {
-                            ServerMessage::Response(response) => {
-                                // This is the direct response we were waiting for.
-                                return Ok(response);
-                            }
-                            ServerMessage::Notification(notification) => {
-                                // This is a broadcasted notification. Buffer it and keep waiting.
-                                self.notification_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .push_back(notification);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Failed to read response: {}. Reconnecting...", e);
-                        *stream_lock = Self::establish_connection(&self.server_addr)?;
-                        // After reconnecting, the original request must be resent.
-                        continue 'retry_loop;
-                    }
-                }
+    /// Like `request`, with a reason recorded in the ledger for any creditz it changes.
+    fn request_with_reason(
+        &self,
+        request: Request,
+        reason: &str,
+    ) -> Result<Response, CharacterError> {
+        self.wait(self.send(request, Some(reason))?)
+    }
+
+    /// Writes a request without waiting, returning where its response will arrive.
+    ///
+    /// Mutating requests are tagged with a fresh `OperationId` here, so a request resent
+    /// after a reconnect is recognised by the server and not applied twice.
+    fn send(&self, request: Request, reason: Option<&str>) -> Result<Reply, CharacterError> {
+        let operation_id = request.is_mutating().then(|| OperationId {
+            client_id: self.client_id,
+            sequence: self.next_sequence.fetch_add(1, Ordering::Relaxed),
+        });
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let message = ClientMessage::Request {
+            request_id,
+            operation_id,
+            reason: reason.map(str::to_string),
+            request,
+        };
+        let payload = bincode::serialize(&message)?;
+        check_frame_size(payload.len(), self.connection.options.max_frame_size)?;
+
+        let deadline = self
+            .connection
+            .options
+            .request_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let (reply, receiver) = mpsc::channel();
+        self.connection.send(request_id, payload, reply)?;
+        Ok(Reply {
+            request_id,
+            receiver,
+            deadline,
+        })
+    }
+
+    /// Waits for a response until the request's deadline. A request that times out is
+    /// forgotten, so it isn't resent after a reconnect.
+    fn wait(&self, reply: Reply) -> Result<Response, CharacterError> {
+        let Some(deadline) = reply.deadline else {
+            return reply
+                .receiver
+                .recv()
+                .map_err(|_| CharacterError::ConnectionClosed);
+        };
+        match reply
+            .receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(response) => Ok(response),
+            Err(RecvTimeoutError::Timeout) => {
+                self.connection
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .remove(&reply.request_id);
+                Err(CharacterError::Timeout)
             }
+            Err(RecvTimeoutError::Disconnected) => Err(CharacterError::ConnectionClosed),
         }
     }
 
+    /// Sends every request back-to-back and then waits for all of the responses, so the
+    /// whole batch costs a single round trip. Responses are returned in request order.
+    pub fn pipeline(&self, requests: Vec<Request>) -> Result<Vec<Response>, CharacterError> {
+        let replies = requests
+            .into_iter()
+            .map(|request| self.send(request, None))
+            .collect::<Result<Vec<_>, _>>()?;
+        replies.into_iter().map(|reply| self.wait(reply)).collect()
+    }
+
     /// Checks for any pending notifications from the server.
     /// This is a non-blocking check.
+    ///
+    /// After a reconnect, the notifications missed in the meantime are replayed. If the server
+    /// no longer has them all, this returns `CharacterError::ResyncRequired` once, and drops
+    /// anything buffered before it; the caller should then fetch afresh whatever it tracks.
     pub fn check_events(&self) -> Result<Vec<Notification>, CharacterError> {
-        // First, drain any notifications that were buffered during a previous request.
-        let mut notifications: Vec<_> =
-            self.notification_buffer.lock().unwrap().drain(..).collect();
+        if self
+            .connection
+            .resync_required
+            .swap(false, Ordering::AcqRel)
+        {
+            self.connection.notification_buffer.lock().unwrap().clear();
+            return Err(CharacterError::ResyncRequired);
+        }
+
+        let notifications: Vec<_> = self
+            .connection
+            .notification_buffer
+            .lock()
+            .unwrap()
+            .drain(..)
+            .collect();
+
+        if notifications.is_empty() && self.connection.closed.load(Ordering::Acquire) {
+            return Err(CharacterError::ConnectionClosed);
+        }
+        Ok(notifications)
+    }
+
+    crate::methods::request_methods!(sync);
+
+    /// Sends a `Subscribe` or `Unsubscribe`, and records it once the server has applied it.
+    fn update_subscriptions(&self, request: Request) -> Result<(), CharacterError> {
+        match self.request(request.clone())? {
+            Response::Subscribed { sequence } => {
+                self.connection
+                    .subscriptions
+                    .lock()
+                    .unwrap()
+                    .apply(&request);
+                // Nothing has been missed up to here, so a reconnect can resume from this point
+                // even if no notification has arrived yet.
+                self.connection
+                    .last_sequence
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(sequence);
+                Ok(())
+            }
+            Response::Error(e) => Err(CharacterError::Server(e)),
+            _ => Err(CharacterError::UnexpectedPacket),
+        }
+    }
+}
+
+impl Drop for CharacterClient {
+    fn drop(&mut self) {
+        // Shutting the socket down wakes the reader thread, which then sees `closed` and exits.
+        self.connection.close();
+    }
+}
 
-        let mut stream_lock = self.stream.lock().unwrap();
+impl Connection {
+    /// Registers a request as pending and writes it to the server.
+    fn send(
+        &self,
+        request_id: RequestId,
+        payload: Vec<u8>,
+        reply: mpsc::Sender<Response>,
+    ) -> Result<(), CharacterError> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.closed.load(Ordering::Acquire) {
+            return Err(CharacterError::ConnectionClosed);
+        }
 
-        // Temporarily set a non-blocking read timeout for this check.
-        stream_lock.set_read_timeout(Some(Duration::from_millis(10)))?;
+        // Register before writing, so that the reader thread can't see the response first.
+        // If the write fails the request stays pending, and is resent once the reader thread
+        // notices the broken connection and reconnects.
+        self.pending.lock().unwrap().insert(
+            request_id,
+            PendingRequest {
+                payload: payload.clone(),
+                reply,
+            },
+        );
+        if let Err(e) = write_frame(&mut writer, &payload) {
+            warn!("Failed to send request: {}. Waiting for reconnect...", e);
+        }
+        Ok(())
+    }
 
+    /// Runs on the reader thread, routing responses to their requests and buffering
+    /// notifications, until the client is closed.
+    fn read_loop(&self, mut stream: TcpStream) {
         loop {
-            match read_frame(&mut *stream_lock) {
-                Ok(payload) => {
-                    // We assume any message read here is a notification.
-                    // If it's a Response, it's a protocol error, as we aren't in a request.
-                    match bincode::deserialize::<ServerMessage>(&payload)? {
-                        ServerMessage::Notification(notification) => {
-                            notifications.push(notification);
-                        }
-                        ServerMessage::Response(_) => {
-                            warn!("Received unexpected Response outside of a request cycle.");
-                            // We still need to restore the timeout before returning.
-                            let _ = stream_lock.set_read_timeout(None);
-                            return Err(CharacterError::UnexpectedPacket);
+            let payload = match read_frame(&mut stream, self.options.max_frame_size) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    if self.closed.load(Ordering::Acquire) {
+                        return;
+                    }
+                    warn!("Failed to read from server: {}. Reconnecting...", e);
+                    self.options.notify(ConnectionState::Disconnected {
+                        error: e.to_string(),
+                    });
+                    match self.reconnect() {
+                        Ok(new_stream) => stream = new_stream,
+                        Err(e) => {
+                            if !self.closed.load(Ordering::Acquire) {
+                                error!("Giving up on server: {}", e);
+                                self.close();
+                            }
+                            return;
                         }
                     }
+                    continue;
                 }
-                Err(CharacterError::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // This is expected when there are no more events.
-                    break;
-                }
+            };
+            *self.last_received.lock().unwrap() = Instant::now();
+
+            // The frame was read whole, so the stream is still in step; a message this build
+            // can't decode, e.g. one from a newer server, is skipped rather than reconnected over.
+            let message = match bincode::deserialize::<ServerMessage>(&payload) {
+                Ok(message) => message,
                 Err(e) => {
-                    // A real error occurred. Restore blocking and then reconnect.
-                    let _ = stream_lock.set_read_timeout(None);
-                    warn!("Error checking events: {}. Reconnecting...", e);
-                    *stream_lock = Self::establish_connection(&self.server_addr)?;
-                    return Ok(Vec::new());
+                    warn!(
+                        "Skipping a message from the server that could not be decoded: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            match message {
+                ServerMessage::Response {
+                    request_id,
+                    response,
+                } => match self.pending.lock().unwrap().remove(&request_id) {
+                    // The requester may have given up waiting, so a failed send is fine.
+                    Some(pending) => drop(pending.reply.send(response)),
+                    None => warn!("Received a response to unknown request {}.", request_id),
+                },
+                ServerMessage::Notification {
+                    sequence,
+                    notification,
+                } => self.buffer_notification(sequence, notification),
+                ServerMessage::Ping { nonce } => {
+                    let mut writer = self.writer.lock().unwrap();
+                    // A failed write shows up as a failed read soon enough.
+                    let _ = write_message(&mut writer, &ClientMessage::Pong { nonce });
                 }
+                // Arriving at all was the point.
+                ServerMessage::Pong { .. } => {}
             }
         }
-
-        // IMPORTANT: Restore the default blocking behavior for subsequent requests.
-        stream_lock.set_read_timeout(None)?;
-
-        Ok(notifications)
     }
 
-    // --- Public API Methods ---
+    /// Replaces a broken stream with a freshly negotiated connection and resends every
+    /// pending request. Returns the read side of the new connection.
+    fn reconnect(&self) -> Result<TcpStream, CharacterError> {
+        let setup = session_setup(
+            self.token.as_deref(),
+            &self.subscriptions.lock().unwrap(),
+            *self.last_sequence.lock().unwrap(),
+        );
+        let (stream, handshake) =
+            establish_connection(&self.server_addrs, &setup, &self.options, &self.closed)?;
+        let reader = stream.try_clone()?;
 
-    pub fn get_creditz(&self, user_id: u32) -> Result<u32, CharacterError> {
-        let request = Request::GetCreditz(user_id);
-        match self.request(request)? {
-            Response::Creditz(value) => Ok(value),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+        let mut writer = self.writer.lock().unwrap();
+        if self.closed.load(Ordering::Acquire) {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(CharacterError::ConnectionClosed);
         }
-    }
+        *writer = stream;
+        *self.last_received.lock().unwrap() = Instant::now();
+        self.absorb_handshake(handshake);
 
-    pub fn set_creditz(&self, user_id: u32, value: u32) -> Result<(), CharacterError> {
-        let request = Request::SetCreditz(user_id, value);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+        // Resend in the original order. Mutating requests carry operation IDs, so the server
+        // won't apply any that it already processed before the connection dropped.
+        let pending = self.pending.lock().unwrap();
+        let mut request_ids: Vec<_> = pending.keys().copied().collect();
+        request_ids.sort_unstable();
+        for request_id in request_ids {
+            if let Err(e) = write_frame(&mut writer, &pending[&request_id].payload) {
+                warn!("Failed to resend request {}: {}", request_id, e);
+                break;
+            }
         }
-    }
 
-    pub fn add_creditz(&self, user_id: u32, amount: u32) -> Result<(), CharacterError> {
-        let request = Request::AddCreditz(user_id, amount);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
-        }
+        Ok(reader)
     }
 
-    pub fn sub_creditz(&self, user_id: u32, amount: u32) -> Result<(), CharacterError> {
-        let request = Request::SubtractCreditz(user_id, amount);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+    /// Runs on the heartbeat thread, pinging the server every `interval` until the client is
+    /// closed. If the server has been silent for longer than `interval` plus the pong timeout,
+    /// the connection is shut down, so the reader thread notices and reconnects.
+    fn heartbeat_loop(&self, interval: Duration) {
+        let mut nonce = 0;
+        loop {
+            std::thread::sleep(interval);
+            if self.closed.load(Ordering::Acquire) {
+                return;
+            }
+            if !self
+                .protocol
+                .lock()
+                .unwrap()
+                .has_capability(capability::HEARTBEAT)
+            {
+                continue;
+            }
+
+            let mut writer = self.writer.lock().unwrap();
+            let mut last_received = self.last_received.lock().unwrap();
+            if last_received.elapsed() > interval + self.options.pong_timeout {
+                warn!("The server has stopped answering. Reconnecting...");
+                let _ = writer.shutdown(Shutdown::Both);
+                // Give the replacement connection a full timeout of its own.
+                *last_received = Instant::now();
+                continue;
+            }
+            drop(last_received);
+            nonce += 1;
+            // A failed write shows up as a failed read soon enough.
+            let _ = write_message(&mut writer, &ClientMessage::Ping { nonce });
         }
     }
 
-    // --- Stats ---
+    fn buffer_notification(&self, sequence: u64, notification: Notification) {
+        *self.last_sequence.lock().unwrap() = Some(sequence);
+        self.notification_buffer
+            .lock()
+            .unwrap()
+            .push_back(notification);
+    }
 
-    pub fn get_happiness(&self, user_id: u32) -> Result<f32, CharacterError> {
-        let request = Request::GetHappiness(user_id);
-        match self.request(request)? {
-            Response::Happiness(value) => Ok(value.to_f32()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+    /// Takes on the protocol and any notifications from a fresh connection's handshake.
+    fn absorb_handshake(&self, handshake: Handshake) {
+        *self.protocol.lock().unwrap() = handshake.protocol;
+        for (sequence, notification) in handshake.notifications {
+            self.buffer_notification(sequence, notification);
+        }
+        if handshake.resync_required {
+            warn!("Notifications were missed while reconnecting; a resync is required.");
+            self.resync_required.store(true, Ordering::Release);
         }
     }
 
-    pub fn set_happiness(&self, user_id: u32, value: f32) -> Result<(), CharacterError> {
-        let request = Request::SetHappiness(user_id, value);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
-        }
+    /// Stops the reader thread and fails every pending request.
+    fn close(&self) {
+        let writer = self.writer.lock().unwrap();
+        self.closed.store(true, Ordering::Release);
+        let _ = writer.shutdown(Shutdown::Both);
+        // Dropping the reply senders wakes any waiting requesters with an error.
+        self.pending.lock().unwrap().clear();
     }
+}
 
-    pub fn get_hunger(&self, user_id: u32) -> Result<f32, CharacterError> {
-        let request = Request::GetHunger(user_id);
-        match self.request(request)? {
-            Response::Hunger(value) => Ok(value.to_f32()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+/// The requests that restore a connection's session state, sent before anything else. Once
+/// the subscriptions are back in place, the notifications missed since `last_sequence` are
+/// asked for.
+pub(crate) fn session_setup(
+    token: Option<&str>,
+    subscriptions: &Subscriptions,
+    last_sequence: Option<u64>,
+) -> Vec<Request> {
+    let resume = last_sequence
+        .filter(|_| !subscriptions.is_empty())
+        .map(|last_sequence| Request::Resume { last_sequence });
+    token
+        .map(|token| Request::Authenticate(token.to_string()))
+        .into_iter()
+        .chain(subscriptions.to_requests())
+        .chain(resume)
+        .collect()
+}
+
+/// The connect loop. It tries each address in turn, backing off between attempts, until one
+/// works or the connection policy gives up. An incompatible protocol version, a refused setup
+/// request or a closed client end it early, since retrying won't help with any of them.
+fn establish_connection(
+    addrs: &[String],
+    setup: &[Request],
+    options: &ClientOptions,
+    closed: &AtomicBool,
+) -> Result<(TcpStream, Handshake), CharacterError> {
+    let mut attempts = Attempts::new(&options.connect);
+    loop {
+        let attempt = attempts.start();
+        let mut last_error = None;
+        for addr in addrs {
+            if closed.load(Ordering::Acquire) {
+                return Err(CharacterError::ConnectionClosed);
+            }
+            info!("Attempting to connect to server at {}...", addr);
+            options.notify(ConnectionState::Connecting {
+                addr: addr.clone(),
+                attempt,
+            });
+            match connect_to(addr, setup, options, attempts.deadline()) {
+                Ok((stream, handshake)) => {
+                    info!(
+                        "Successfully connected to server at {} (protocol version {}).",
+                        addr, handshake.protocol.version
+                    );
+                    options.notify(ConnectionState::Connected {
+                        addr: addr.clone(),
+                        protocol_version: handshake.protocol.version,
+                    });
+                    return Ok((stream, handshake));
+                }
+                Err(e @ CharacterError::IncompatibleProtocol { .. })
+                | Err(e @ CharacterError::Server(_)) => {
+                    options.notify(ConnectionState::GaveUp {
+                        error: e.to_string(),
+                    });
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Failed to connect to {}: {}", addr, e);
+                    options.notify(ConnectionState::Failed {
+                        addr: addr.clone(),
+                        error: e.to_string(),
+                    });
+                    last_error = Some(e);
+                }
+            }
         }
-    }
 
-    pub fn set_hunger(&self, user_id: u32, value: f32) -> Result<(), CharacterError> {
-        let request = Request::SetHunger(user_id, value);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+        let last_error = last_error.expect("a client always has at least one address");
+        match attempts.retry(last_error) {
+            Ok(delay) => {
+                warn!("Retrying in {:.1?}...", delay);
+                options.notify(ConnectionState::Waiting { delay });
+                std::thread::sleep(delay);
+            }
+            Err(e) => {
+                options.notify(ConnectionState::GaveUp {
+                    error: e.to_string(),
+                });
+                return Err(e);
+            }
         }
     }
+}
 
-    pub fn get_boredom(&self, user_id: u32) -> Result<f32, CharacterError> {
-        let request = Request::GetBoredom(user_id);
-        match self.request(request)? {
-            Response::Boredom(value) => Ok(value.to_f32()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+/// Opens a connection to `addr` and completes the handshake on it, by `deadline` if there is
+/// one.
+fn connect_to(
+    addr: &str,
+    setup: &[Request],
+    options: &ClientOptions,
+    deadline: Option<Instant>,
+) -> Result<(TcpStream, Handshake), CharacterError> {
+    let time_left = || match deadline {
+        None => Ok(None),
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(CharacterError::Timeout),
+        },
+    };
+    let mut stream = match time_left()? {
+        Some(left) => connect_timeout(addr, left)?,
+        None => TcpStream::connect(addr)?,
+    };
+    // The handshake has to finish in time too. Afterwards reads block for as long as it
+    // takes, since the reader thread waits on them.
+    stream.set_read_timeout(time_left()?)?;
+    let handshake = handshake(&mut stream, setup, options.max_frame_size)?;
+    stream.set_read_timeout(None)?;
+    Ok((stream, handshake))
+}
+
+/// `TcpStream::connect_timeout` for an address that may need resolving first.
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing");
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
         }
     }
+    Err(last_error)
+}
 
-    pub fn set_boredom(&self, user_id: u32, value: f32) -> Result<(), CharacterError> {
-        let request = Request::SetBoredom(user_id, value);
-        match self.request(request)? {
-            Response::Success => Ok(()),
-            Response::Error(e) => Err(CharacterError::Server(e)),
-            _ => Err(CharacterError::UnexpectedPacket),
+// --- Framing Helpers ---
+
+/// Exchanges `Hello`s with the server on a freshly opened stream, then sends each setup
+/// request in turn and waits for it to succeed.
+fn handshake(
+    stream: &mut TcpStream,
+    setup: &[Request],
+    max_frame_size: u32,
+) -> Result<Handshake, CharacterError> {
+    write_frame(stream, &bincode::serialize(&Hello::current())?)?;
+    let protocol = NegotiatedProtocol::from_reply(bincode::deserialize(&read_frame(
+        stream,
+        max_frame_size,
+    )?)?)?;
+
+    let mut handshake = Handshake {
+        protocol,
+        notifications: Vec::new(),
+        resync_required: false,
+    };
+    for request in setup {
+        let message = ClientMessage::Request {
+            request_id: SETUP_REQUEST_ID,
+            operation_id: None,
+            reason: None,
+            request: request.clone(),
+        };
+        write_message(stream, &message)?;
+        loop {
+            match bincode::deserialize(&read_frame(stream, max_frame_size)?)? {
+                ServerMessage::Response {
+                    response: Response::Error(e),
+                    ..
+                } => return Err(CharacterError::Server(e)),
+                ServerMessage::Response {
+                    response: Response::ResyncRequired,
+                    ..
+                } => {
+                    handshake.resync_required = true;
+                    break;
+                }
+                ServerMessage::Response { .. } => break,
+                ServerMessage::Notification {
+                    sequence,
+                    notification,
+                } => handshake.notifications.push((sequence, notification)),
+                ServerMessage::Ping { nonce } => {
+                    write_message(stream, &ClientMessage::Pong { nonce })?;
+                }
+                ServerMessage::Pong { .. } => {}
+            }
         }
     }
+    Ok(handshake)
 }
 
-// --- Framing Helpers ---
+fn write_message(stream: &mut TcpStream, message: &ClientMessage) -> Result<(), CharacterError> {
+    write_frame(stream, &bincode::serialize(message)?)
+}
 
 /// Writes a bincode-serialized payload to the stream with a 4-byte length prefix.
 fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<(), CharacterError> {
@@ -241,13 +800,52 @@ fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> Result<(), CharacterEr
     Ok(())
 }
 
-/// Reads a frame from the stream, expecting a 4-byte length prefix.
-fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>, CharacterError> {
+/// Reads a frame from the stream, expecting a 4-byte length prefix. The length is checked
+/// before anything is allocated for the frame.
+fn read_frame(stream: &mut TcpStream, max_frame_size: u32) -> Result<Vec<u8>, CharacterError> {
     let mut len_bytes = [0u8; 4];
     stream.read_exact(&mut len_bytes)?;
     let len = u32::from_be_bytes(len_bytes);
+    check_frame_size(len as usize, max_frame_size)?;
 
     let mut buffer = vec![0u8; len as usize];
     stream.read_exact(&mut buffer)?;
     Ok(buffer)
 }
+
+pub(crate) fn check_frame_size(size: usize, max_frame_size: u32) -> Result<(), CharacterError> {
+    if size > max_frame_size as usize {
+        return Err(CharacterError::FrameTooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setup_authenticates_then_subscribes_then_resumes() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(&[1], &[StatKind::Creditz]);
+
+        let setup = session_setup(Some("token"), &subscriptions, Some(41));
+
+        assert!(matches!(
+            &setup[..],
+            [
+                Request::Authenticate(token),
+                Request::Subscribe { .. },
+                Request::Resume { last_sequence: 41 },
+            ] if token == "token"
+        ));
+    }
+
+    #[test]
+    fn setup_resumes_nothing_without_subscriptions() {
+        assert!(session_setup(None, &Subscriptions::new(), Some(41)).is_empty());
+    }
+}
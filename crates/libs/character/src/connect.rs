@@ -0,0 +1,197 @@
+//! How clients go about reaching the server, on the first connect and every reconnect.
+
+use crate::error::CharacterError;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How hard a client tries to reach the server. Each attempt tries the server's address and
+/// then every fallback address in turn; between attempts the client backs off exponentially.
+///
+/// The policy applies afresh to every reconnect, so a client that gives up on a lost
+/// connection closes, and its requests fail with `CharacterError::ConnectionClosed`.
+#[derive(Debug, Clone)]
+pub struct ConnectPolicy {
+    /// How many attempts to make before giving up. `None` keeps trying.
+    pub max_attempts: Option<u32>,
+    /// How long to keep trying before giving up, counting the attempts themselves. `None`
+    /// keeps trying.
+    pub timeout: Option<Duration>,
+    /// Roughly how long to wait after the first failed attempt. The wait doubles after each
+    /// further one.
+    pub initial_backoff: Duration,
+    /// The longest wait between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            timeout: None,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A change in a client's connection to the server, as reported to a `StateListener`.
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    /// Attempt number `attempt`, counting from 1, is trying `addr`.
+    Connecting { addr: String, attempt: u32 },
+    /// Connected to `addr`, and the session is set up.
+    Connected { addr: String, protocol_version: u32 },
+    /// Couldn't connect to `addr`.
+    Failed { addr: String, error: String },
+    /// Every address failed. The next attempt starts after `delay`.
+    Waiting { delay: Duration },
+    /// An established connection was lost. The client starts reconnecting straight away.
+    Disconnected { error: String },
+    /// The client gave up on the server.
+    GaveUp { error: String },
+}
+
+/// Called with every `ConnectionState` a client passes through. It runs on the thread or
+/// task doing the connecting, so it should return quickly.
+#[derive(Clone)]
+pub struct StateListener(Arc<dyn Fn(&ConnectionState) + Send + Sync>);
+
+impl StateListener {
+    pub fn new(listener: impl Fn(&ConnectionState) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(listener))
+    }
+
+    pub(crate) fn notify(&self, state: &ConnectionState) {
+        (self.0)(state)
+    }
+}
+
+impl fmt::Debug for StateListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateListener").finish_non_exhaustive()
+    }
+}
+
+/// Counts the attempts of one connect, and decides whether and when to make another.
+pub(crate) struct Attempts<'a> {
+    policy: &'a ConnectPolicy,
+    deadline: Option<Instant>,
+    count: u32,
+}
+
+impl<'a> Attempts<'a> {
+    pub(crate) fn new(policy: &'a ConnectPolicy) -> Self {
+        Self {
+            policy,
+            deadline: policy.timeout.map(|timeout| Instant::now() + timeout),
+            count: 0,
+        }
+    }
+
+    /// Starts the next attempt and returns its number, counting from 1.
+    pub(crate) fn start(&mut self) -> u32 {
+        self.count += 1;
+        self.count
+    }
+
+    /// When the policy gives up, if it has a timeout.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Called once every address has failed the current attempt, the last with `error`.
+    /// Returns how long to wait before the next attempt, or the error to give up with.
+    pub(crate) fn retry(&self, error: CharacterError) -> Result<Duration, CharacterError> {
+        let delay = self.backoff();
+        let out_of_attempts = self
+            .policy
+            .max_attempts
+            .is_some_and(|max| self.count >= max);
+        let out_of_time = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() + delay >= deadline);
+        if out_of_attempts || out_of_time {
+            return Err(CharacterError::ConnectFailed {
+                attempts: self.count,
+                source: Box::new(error),
+            });
+        }
+        Ok(delay)
+    }
+
+    /// Somewhere between half and all of the current backoff, so that clients which lost the
+    /// server at the same moment don't all come back at the same moment.
+    fn backoff(&self) -> Duration {
+        let doublings = self.count.saturating_sub(1).min(31);
+        let ceiling = self
+            .policy
+            .initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.policy.max_backoff);
+        let jitter =
+            (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64;
+        ceiling / 2 + (ceiling / 2).mul_f64(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: Option<u32>, timeout: Option<Duration>) -> ConnectPolicy {
+        ConnectPolicy {
+            max_attempts,
+            timeout,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_with_jitter() {
+        let policy = policy(None, None);
+        let mut attempts = Attempts::new(&policy);
+        for ceiling in [100, 200, 400, 800, 1000, 1000, 1000] {
+            attempts.start();
+            let ceiling = Duration::from_millis(ceiling);
+            for _ in 0..20 {
+                let delay = attempts.retry(CharacterError::ConnectionClosed).unwrap();
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt() {
+        let policy = policy(Some(3), None);
+        let mut attempts = Attempts::new(&policy);
+        for _ in 0..2 {
+            attempts.start();
+            assert!(attempts.retry(CharacterError::ConnectionClosed).is_ok());
+        }
+        attempts.start();
+
+        match attempts.retry(CharacterError::Timeout) {
+            Err(CharacterError::ConnectFailed { attempts, source }) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*source, CharacterError::Timeout));
+            }
+            result => panic!("expected to give up, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn gives_up_when_the_next_attempt_would_be_too_late() {
+        let policy = policy(None, Some(Duration::from_millis(10)));
+        let mut attempts = Attempts::new(&policy);
+        attempts.start();
+
+        assert!(matches!(
+            attempts.retry(CharacterError::ConnectionClosed),
+            Err(CharacterError::ConnectFailed { attempts: 1, .. })
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::protocol::ServerError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -7,9 +8,34 @@ pub enum CharacterError {
     #[error("Serialization/deserialization error: {0}")]
     Bincode(#[from] Box<bincode::ErrorKind>),
     #[error("Server returned an error: {0}")]
-    Server(String),
+    Server(ServerError),
     #[error("Received an unexpected packet from the server")]
     UnexpectedPacket,
     #[error("Connection was closed")]
     ConnectionClosed,
+    /// No response arrived before the request's deadline. The request may still have been
+    /// applied; it won't be resent after a reconnect.
+    #[error("Timed out waiting for the server")]
+    Timeout,
+    /// The connection policy ran out of attempts or time before the server could be reached.
+    #[error("Gave up connecting to the server after {attempts} attempts: {source}")]
+    ConnectFailed {
+        attempts: u32,
+        source: Box<CharacterError>,
+    },
+    #[error("Frame of {size} bytes is larger than the maximum of {max}")]
+    FrameTooLarge { size: usize, max: u32 },
+    #[error(
+        "Incompatible protocol: client speaks versions {client_min_version}-{client_version}, server speaks {server_min_version}-{server_version}"
+    )]
+    IncompatibleProtocol {
+        client_version: u32,
+        client_min_version: u32,
+        server_version: u32,
+        server_min_version: u32,
+    },
+    /// Notifications were lost while reconnecting, so any state built from them is stale and
+    /// must be fetched again.
+    #[error("Notifications were missed while reconnecting; a full resync is required")]
+    ResyncRequired,
 }
@@ -2,12 +2,25 @@
 //!
 //! This crate contains all the shared logic, including the communication
 //! protocol, error types, and the `CharacterClient` for interacting with
-//! the server.
+//! the server. With the `async` feature, it also provides `AsyncCharacterClient`.
 
+#[cfg(feature = "async")]
+pub mod async_client;
 pub mod client;
+pub mod connect;
 pub mod error;
+mod methods;
 pub mod protocol;
+pub mod subscription;
 
-pub use client::CharacterClient;
+#[cfg(feature = "async")]
+pub use async_client::{AsyncCharacterClient, NotificationReceiver};
+pub use client::{CharacterClient, ClientOptions};
+pub use connect::{ConnectPolicy, ConnectionState, StateListener};
 pub use error::CharacterError;
-pub use protocol::{Notification, Request, Response, ServerMessage, StatBar};
+pub use protocol::{
+    Character, ClientMessage, Hello, HelloReply, InventoryItem, KeyValue, LedgerEntry,
+    Notification, Op, OperationId, PROTOCOL_VERSION, Request, RequestId, Response, Scope,
+    ServerError, ServerMessage, StatBar, StatDelta, StatInfo, StatKind, StatType, StatValue,
+};
+pub use subscription::Subscriptions;
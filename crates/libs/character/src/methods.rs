@@ -0,0 +1,557 @@
+//! The request methods both clients share. Each client sends requests its own way, blocking
+//! or async, but builds them and decodes the responses in exactly the same way.
+
+/// Defines the request methods of a client, from `get_creditz` to `transaction_with_reason`,
+/// inside its `impl` block: `request_methods!(sync)` for `CharacterClient`, and
+/// `request_methods!(async)` for `AsyncCharacterClient`, whose methods are then `async`.
+///
+/// The client must have `request`, `request_with_reason` and `update_subscriptions` methods,
+/// blocking or async to match, and import the protocol types the methods use.
+macro_rules! request_methods {
+    (sync) => {
+        $crate::methods::request_methods!(@ [] []);
+    };
+    (async) => {
+        $crate::methods::request_methods!(@ [async] [.await]);
+    };
+    (@ [$($async:tt)*] [$($await:tt)*]) => {
+        // --- Creditz ---
+
+        pub $($async)* fn get_creditz(&self, user_id: u32) -> Result<u32, CharacterError> {
+            let request = Request::GetCreditz(user_id);
+            match self.request(request)$($await)*? {
+                Response::Creditz(value) => Ok(value),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn set_creditz(
+            &self,
+            user_id: u32,
+            value: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetCreditz(user_id, value);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn add_creditz(
+            &self,
+            user_id: u32,
+            amount: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddCreditz(user_id, amount);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn sub_creditz(
+            &self,
+            user_id: u32,
+            amount: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SubtractCreditz(user_id, amount);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Like `add_creditz`, recording `reason` (e.g. `"prize:CoreMaze"`) in the ledger.
+        pub $($async)* fn add_creditz_with_reason(
+            &self,
+            user_id: u32,
+            amount: u32,
+            reason: &str,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddCreditz(user_id, amount);
+            match self.request_with_reason(request, reason)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Like `sub_creditz`, recording `reason` (e.g. `"ticket:MagicForest"`) in the ledger.
+        pub $($async)* fn sub_creditz_with_reason(
+            &self,
+            user_id: u32,
+            amount: u32,
+            reason: &str,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SubtractCreditz(user_id, amount);
+            match self.request_with_reason(request, reason)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads up to `limit` of `user_id`'s creditz changes, newest first, starting after the
+        /// entry with id `before` if given.
+        pub $($async)* fn get_creditz_history(
+            &self,
+            user_id: u32,
+            before: Option<u64>,
+            limit: u32,
+        ) -> Result<Vec<LedgerEntry>, CharacterError> {
+            let request = Request::GetCreditzHistory {
+                user_id,
+                before,
+                limit,
+            };
+            match self.request(request)$($await)*? {
+                Response::CreditzHistory(entries) => Ok(entries),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Moves `amount` creditz from `from` to `to` atomically: either both balances change or
+        /// neither does.
+        pub $($async)* fn transfer_creditz(
+            &self,
+            from: u32,
+            to: u32,
+            amount: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::TransferCreditz { from, to, amount };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Like `transfer_creditz`, recording `reason` (e.g. `"gift"`) in the ledger.
+        pub $($async)* fn transfer_creditz_with_reason(
+            &self,
+            from: u32,
+            to: u32,
+            amount: u32,
+            reason: &str,
+        ) -> Result<(), CharacterError> {
+            let request = Request::TransferCreditz { from, to, amount };
+            match self.request_with_reason(request, reason)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        // --- Stats ---
+
+        pub $($async)* fn get_happiness(&self, user_id: u32) -> Result<f32, CharacterError> {
+            let request = Request::GetHappiness(user_id);
+            match self.request(request)$($await)*? {
+                Response::Happiness(value) => Ok(value.to_f32()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn set_happiness(
+            &self,
+            user_id: u32,
+            value: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetHappiness(user_id, value);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Adjusts happiness by `delta` on the server, saturating at 0.0 and 1.0.
+        pub $($async)* fn add_happiness(
+            &self,
+            user_id: u32,
+            delta: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddHappiness(user_id, delta);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn get_hunger(&self, user_id: u32) -> Result<f32, CharacterError> {
+            let request = Request::GetHunger(user_id);
+            match self.request(request)$($await)*? {
+                Response::Hunger(value) => Ok(value.to_f32()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn set_hunger(
+            &self,
+            user_id: u32,
+            value: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetHunger(user_id, value);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Adjusts hunger by `delta` on the server, saturating at 0.0 and 1.0.
+        pub $($async)* fn add_hunger(
+            &self,
+            user_id: u32,
+            delta: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddHunger(user_id, delta);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn get_boredom(&self, user_id: u32) -> Result<f32, CharacterError> {
+            let request = Request::GetBoredom(user_id);
+            match self.request(request)$($await)*? {
+                Response::Boredom(value) => Ok(value.to_f32()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        pub $($async)* fn set_boredom(
+            &self,
+            user_id: u32,
+            value: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetBoredom(user_id, value);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Adjusts boredom by `delta` on the server, saturating at 0.0 and 1.0.
+        pub $($async)* fn add_boredom(
+            &self,
+            user_id: u32,
+            delta: f32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddBoredom(user_id, delta);
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads any stat by name, including the built-in ones.
+        pub $($async)* fn get_stat(
+            &self,
+            user_id: u32,
+            stat: &str,
+        ) -> Result<StatValue, CharacterError> {
+            let request = Request::GetStat {
+                user_id,
+                stat: stat.to_string(),
+            };
+            match self.request(request)$($await)*? {
+                Response::Stat(value) => Ok(value),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Overwrites any stat by name. `value` must be of the stat's type.
+        pub $($async)* fn set_stat(
+            &self,
+            user_id: u32,
+            stat: &str,
+            value: StatValue,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetStat {
+                user_id,
+                stat: stat.to_string(),
+                value,
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Adjusts any stat by name. Bars saturate at 0.0 and 1.0.
+        pub $($async)* fn add_stat(
+            &self,
+            user_id: u32,
+            stat: &str,
+            delta: StatDelta,
+        ) -> Result<(), CharacterError> {
+            let request = Request::AddStat {
+                user_id,
+                stat: stat.to_string(),
+                delta,
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Lists every stat the server knows about, built-in or configured.
+        pub $($async)* fn list_stats(&self) -> Result<Vec<StatInfo>, CharacterError> {
+            match self.request(Request::ListStats)$($await)*? {
+                Response::StatList(stats) => Ok(stats),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        // --- Items ---
+
+        /// Gives `user_id` `quantity` of `item`.
+        pub $($async)* fn grant_item(
+            &self,
+            user_id: u32,
+            item: &str,
+            quantity: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::GrantItem {
+                user_id,
+                item: item.to_string(),
+                quantity,
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Uses up `quantity` of `user_id`'s `item`, applying its stat effects once for each.
+        pub $($async)* fn consume_item(
+            &self,
+            user_id: u32,
+            item: &str,
+            quantity: u32,
+        ) -> Result<(), CharacterError> {
+            let request = Request::ConsumeItem {
+                user_id,
+                item: item.to_string(),
+                quantity,
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads how many of `item` `user_id` has.
+        pub $($async)* fn count_item(
+            &self,
+            user_id: u32,
+            item: &str,
+        ) -> Result<u32, CharacterError> {
+            let request = Request::CountItem {
+                user_id,
+                item: item.to_string(),
+            };
+            match self.request(request)$($await)*? {
+                Response::ItemCount(count) => Ok(count),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads everything `user_id` has, in no particular order.
+        pub $($async)* fn get_inventory(
+            &self,
+            user_id: u32,
+        ) -> Result<Vec<InventoryItem>, CharacterError> {
+            let request = Request::GetInventory(user_id);
+            match self.request(request)$($await)*? {
+                Response::Inventory(items) => Ok(items),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        // --- Key/Value Store ---
+
+        /// Reads `key` from `user_id`'s part of `namespace`, or `None` if it isn't set.
+        pub $($async)* fn get_value(
+            &self,
+            namespace: &str,
+            user_id: u32,
+            key: &str,
+        ) -> Result<Option<String>, CharacterError> {
+            let request = Request::GetValue {
+                namespace: namespace.to_string(),
+                user_id,
+                key: key.to_string(),
+            };
+            match self.request(request)$($await)*? {
+                Response::Value(value) => Ok(value),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Stores `value` under `key` in `user_id`'s part of `namespace`.
+        pub $($async)* fn set_value(
+            &self,
+            namespace: &str,
+            user_id: u32,
+            key: &str,
+            value: &str,
+        ) -> Result<(), CharacterError> {
+            let request = Request::SetValue {
+                namespace: namespace.to_string(),
+                user_id,
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Removes `key` from `user_id`'s part of `namespace`, if it is set.
+        pub $($async)* fn delete_value(
+            &self,
+            namespace: &str,
+            user_id: u32,
+            key: &str,
+        ) -> Result<(), CharacterError> {
+            let request = Request::DeleteValue {
+                namespace: namespace.to_string(),
+                user_id,
+                key: key.to_string(),
+            };
+            match self.request(request)$($await)*? {
+                Response::Success => Ok(()),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads everything in `user_id`'s part of `namespace`, sorted by key.
+        pub $($async)* fn list_values(
+            &self,
+            namespace: &str,
+            user_id: u32,
+        ) -> Result<Vec<KeyValue>, CharacterError> {
+            let request = Request::ListValues {
+                namespace: namespace.to_string(),
+                user_id,
+            };
+            match self.request(request)$($await)*? {
+                Response::Values(values) => Ok(values),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        // --- Characters ---
+
+        /// Reads every stat of `user_id` in one round trip.
+        pub $($async)* fn get_character(&self, user_id: u32) -> Result<Character, CharacterError> {
+            let request = Request::GetCharacter(user_id);
+            match self.request(request)$($await)*? {
+                Response::Character(character) => Ok(character),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Reads every stat of each of `user_ids` in one round trip, in the same order.
+        pub $($async)* fn get_characters(
+            &self,
+            user_ids: &[u32],
+        ) -> Result<Vec<Character>, CharacterError> {
+            let request = Request::GetCharacters(user_ids.to_vec());
+            match self.request(request)$($await)*? {
+                Response::Characters(characters) => Ok(characters),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        // --- Notifications ---
+
+        /// Starts delivering notifications about `stats` for each of `user_ids`, or for every user
+        /// if `user_ids` is empty. A new client is subscribed to nothing.
+        pub $($async)* fn subscribe(
+            &self,
+            user_ids: &[u32],
+            stats: &[StatKind],
+        ) -> Result<(), CharacterError> {
+            self.update_subscriptions(Request::Subscribe {
+                user_ids: user_ids.to_vec(),
+                stats: stats.to_vec(),
+            })$($await)*
+        }
+
+        /// Stops delivering notifications about `stats` for each of `user_ids`. An empty
+        /// `user_ids` removes every subscription to `stats`.
+        pub $($async)* fn unsubscribe(
+            &self,
+            user_ids: &[u32],
+            stats: &[StatKind],
+        ) -> Result<(), CharacterError> {
+            self.update_subscriptions(Request::Unsubscribe {
+                user_ids: user_ids.to_vec(),
+                stats: stats.to_vec(),
+            })$($await)*
+        }
+
+        // --- Transactions ---
+
+        /// Applies all `ops` atomically. If any operation fails, none of them take effect.
+        /// Returns one response per operation, in order.
+        pub $($async)* fn transaction(
+            &self,
+            ops: Vec<Op>,
+        ) -> Result<Vec<Response>, CharacterError> {
+            let request = Request::Transaction(ops);
+            match self.request(request)$($await)*? {
+                Response::Transaction(responses) => Ok(responses),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+
+        /// Like `transaction`, recording `reason` in the ledger for every creditz change.
+        pub $($async)* fn transaction_with_reason(
+            &self,
+            ops: Vec<Op>,
+            reason: &str,
+        ) -> Result<Vec<Response>, CharacterError> {
+            let request = Request::Transaction(ops);
+            match self.request_with_reason(request, reason)$($await)*? {
+                Response::Transaction(responses) => Ok(responses),
+                Response::Error(e) => Err(CharacterError::Server(e)),
+                _ => Err(CharacterError::UnexpectedPacket),
+            }
+        }
+    };
+}
+
+pub(crate) use request_methods;
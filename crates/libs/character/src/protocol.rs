@@ -1,6 +1,109 @@
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 pub type UserId = u32;
+/// Chosen by the client for each request and echoed back on the matching response.
+pub type RequestId = u64;
+
+/// The protocol version spoken by this build. Bump it whenever a message changes shape, and
+/// only ever append new enum variants so that older peers can still decode the rest.
+pub const PROTOCOL_VERSION: u32 = 4;
+/// The oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+/// Leads every `Hello`, so that the server can recognise a peer that isn't a character client.
+pub const PROTOCOL_MAGIC: u32 = 0x4D49_5543; // "MIUC"
+/// The largest frame either side sends or accepts unless configured otherwise, in bytes. The
+/// biggest responses, such as a full namespace of the key/value store, fit comfortably.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Optional protocol features, advertised in the handshake.
+pub mod capability {
+    pub const TRANSACTIONS: &str = "transactions";
+    pub const STAT_DELTAS: &str = "stat-deltas";
+    pub const TYPED_ERRORS: &str = "typed-errors";
+    pub const AUTHENTICATION: &str = "authentication";
+    pub const SUBSCRIPTIONS: &str = "subscriptions";
+    pub const CHARACTERS: &str = "characters";
+    pub const LEDGER: &str = "ledger";
+    pub const TRANSFERS: &str = "transfers";
+    pub const NAMED_STATS: &str = "named-stats";
+    pub const INVENTORY: &str = "inventory";
+    pub const KEY_VALUE: &str = "key-value";
+    /// Either side may send `Ping`, and the other answers with `Pong`.
+    pub const HEARTBEAT: &str = "heartbeat";
+
+    /// Every capability this build supports.
+    pub fn all() -> Vec<String> {
+        [
+            TRANSACTIONS,
+            STAT_DELTAS,
+            TYPED_ERRORS,
+            AUTHENTICATION,
+            SUBSCRIPTIONS,
+            CHARACTERS,
+            LEDGER,
+            TRANSFERS,
+            NAMED_STATS,
+            INVENTORY,
+            KEY_VALUE,
+            HEARTBEAT,
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+    }
+}
+
+/// The first frame a client sends after connecting.
+///
+/// Unlike the other messages, the layout of `Hello` and `HelloReply` must never change, since
+/// they are how two peers of different versions find out whether they can talk at all.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub magic: u32,
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// A `Hello` describing this build.
+    pub fn current() -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: capability::all(),
+        }
+    }
+}
+
+/// The server's answer to a `Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum HelloReply {
+    /// The connection will use `protocol_version`, and only the listed capabilities, which
+    /// both peers support.
+    Welcome {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
+    /// The versions don't overlap; the server closes the connection after sending this.
+    Rejected {
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+}
+
+/// Picks the version two peers should speak, or `None` if their supported ranges don't overlap.
+pub fn negotiate_version(
+    local_version: u32,
+    local_min_version: u32,
+    remote_version: u32,
+    remote_min_version: u32,
+) -> Option<u32> {
+    let version = local_version.min(remote_version);
+    (version >= local_min_version.max(remote_min_version)).then_some(version)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatBar {
@@ -29,6 +132,57 @@ impl StatBar {
     pub fn to_u32(&self) -> u32 {
         self.value_u32
     }
+
+    /// Returns this value adjusted by `delta`, saturating at the ends of the 0..=1.0 range.
+    pub fn saturating_add(&self, delta: f32) -> Self {
+        Self::from_f32(self.to_f32() + delta)
+    }
+}
+
+/// Identifies a single state-changing request so that the server can recognise a resend.
+///
+/// `client_id` is chosen at random when a `CharacterClient` is created, and `sequence`
+/// increases with every mutating request that client makes. The server tells resends apart
+/// per authenticated identity, so a resend must come from the same credential.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OperationId {
+    pub client_id: u64,
+    pub sequence: u64,
+}
+
+/// A top-level message sent from a client to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientMessage {
+    /// A request to process. Mutating requests carry an `OperationId`; if the server has
+    /// already applied that operation, it replies with the original response instead.
+    /// Responses may arrive in any order, and are matched up by `request_id`. Requests that
+    /// change state are applied in the order they were sent on the connection.
+    ///
+    /// `reason` explains any creditz change the request makes, e.g. `"ticket:MagicForest"`,
+    /// and is kept with it in the ledger.
+    Request {
+        request_id: RequestId,
+        operation_id: Option<OperationId>,
+        reason: Option<String>,
+        request: Request,
+    },
+    /// Asks the server to answer with a `Pong` carrying the same nonce, to show that the
+    /// connection still works. Only sent once the `heartbeat` capability is agreed.
+    Ping { nonce: u64 },
+    /// The answer to a server's `Ping`.
+    Pong { nonce: u64 },
+}
+
+impl ClientMessage {
+    /// Reads just the request ID from an encoded `ClientMessage::Request` that failed to
+    /// decode, e.g. because its request is one this build doesn't know, so that the error can
+    /// still be matched to the request.
+    pub fn peek_request_id(frame: &[u8]) -> Option<RequestId> {
+        // bincode encodes the variant index as a u32, followed by the variant's fields in
+        // order, and ignores whatever comes after the part it was asked for.
+        let (variant, request_id): (u32, RequestId) = bincode::deserialize(frame).ok()?;
+        (variant == 0).then_some(request_id)
+    }
 }
 
 /// A request sent from a client to the server.
@@ -40,17 +194,284 @@ pub enum Request {
     SubtractCreditz(UserId, u32),
     GetHappiness(UserId),
     SetHappiness(UserId, f32),
+    AddHappiness(UserId, f32),
+    GetBoredom(UserId),
+    SetBoredom(UserId, f32),
+    AddBoredom(UserId, f32),
+    GetHunger(UserId),
+    SetHunger(UserId, f32),
+    AddHunger(UserId, f32),
+    /// Applies every operation in order, all-or-nothing. The server replies with
+    /// `Response::Transaction` holding one response per operation.
+    Transaction(Vec<Op>),
+    /// Presents a token from the server's configuration. On success the server replies with
+    /// `Response::Authenticated`, and the connection is granted that token's scopes.
+    Authenticate(String),
+    /// Starts delivering notifications about `stats` for each of `user_ids`, or for every
+    /// user if `user_ids` is empty. A new connection is subscribed to nothing.
+    Subscribe {
+        user_ids: Vec<UserId>,
+        stats: Vec<StatKind>,
+    },
+    /// Stops delivering notifications about `stats` for each of `user_ids`. An empty
+    /// `user_ids` removes every subscription to `stats`, whether per user or for all users.
+    Unsubscribe {
+        user_ids: Vec<UserId>,
+        stats: Vec<StatKind>,
+    },
+    /// Asks the server to resend the notifications this connection's subscriptions match
+    /// that were sent after `last_sequence`. The server replies with `Response::Resumed`, or
+    /// with `Response::ResyncRequired` if it no longer has all of them.
+    Resume {
+        last_sequence: u64,
+    },
+    /// Reads every stat of one user. The server replies with `Response::Character`.
+    GetCharacter(UserId),
+    /// Reads every stat of several users. The server replies with `Response::Characters`, in
+    /// the order the users were requested.
+    GetCharacters(Vec<UserId>),
+    /// Reads a user's creditz changes, newest first. Pass the `id` of the last entry received
+    /// as `before` to get the next page. The server replies with `Response::CreditzHistory`,
+    /// which holds fewer than `limit` entries only once the history is exhausted.
+    GetCreditzHistory {
+        user_id: UserId,
+        before: Option<u64>,
+        limit: u32,
+    },
+    /// Moves `amount` creditz from one user to another, all-or-nothing. Fails with
+    /// `ServerError::InsufficientFunds` if `from` can't afford it.
+    TransferCreditz {
+        from: UserId,
+        to: UserId,
+        amount: u32,
+    },
+    /// Reads any stat by name, including the built-in ones. The server replies with
+    /// `Response::Stat`.
+    GetStat {
+        user_id: UserId,
+        stat: String,
+    },
+    /// Overwrites any stat by name. The value must be of the stat's type.
+    SetStat {
+        user_id: UserId,
+        stat: String,
+        value: StatValue,
+    },
+    /// Adjusts any stat by name. Bars saturate at 0.0 and 1.0; integers fail with
+    /// `ServerError::Overflow` rather than wrap.
+    AddStat {
+        user_id: UserId,
+        stat: String,
+        delta: StatDelta,
+    },
+    /// Lists every stat the server knows about. The server replies with `Response::StatList`.
+    ListStats,
+    /// Gives a user `quantity` of an item declared in the server configuration.
+    GrantItem {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+    },
+    /// Takes `quantity` of an item from a user and applies the item's stat effects once for
+    /// each, all-or-nothing. Fails with `ServerError::NotEnoughItems` if the user has fewer.
+    ConsumeItem {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+    },
+    /// Reads how many of an item a user has. The server replies with `Response::ItemCount`.
+    CountItem {
+        user_id: UserId,
+        item: String,
+    },
+    /// Reads everything a user has. The server replies with `Response::Inventory`.
+    GetInventory(UserId),
+    /// Reads one value from a user's part of the key/value store. The server replies with
+    /// `Response::Value`, holding `None` if the key isn't set.
+    GetValue {
+        namespace: String,
+        user_id: UserId,
+        key: String,
+    },
+    /// Stores a value, replacing any already under `key`. The server enforces limits on the
+    /// sizes of namespaces, keys and values, and on the number of keys per user per namespace.
+    SetValue {
+        namespace: String,
+        user_id: UserId,
+        key: String,
+        value: String,
+    },
+    /// Removes a value. Succeeds whether or not the key was set.
+    DeleteValue {
+        namespace: String,
+        user_id: UserId,
+        key: String,
+    },
+    /// Reads every value a user has in a namespace. The server replies with
+    /// `Response::Values`, sorted by key.
+    ListValues {
+        namespace: String,
+        user_id: UserId,
+    },
+}
+
+impl Request {
+    /// Returns true if this request changes server state and must not be applied twice.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Request::GetCreditz(_)
+            | Request::GetHappiness(_)
+            | Request::GetBoredom(_)
+            | Request::GetHunger(_)
+            | Request::Authenticate(_)
+            | Request::Subscribe { .. }
+            | Request::Unsubscribe { .. }
+            | Request::Resume { .. }
+            | Request::GetCharacter(_)
+            | Request::GetCharacters(_)
+            | Request::GetCreditzHistory { .. }
+            | Request::GetStat { .. }
+            | Request::ListStats
+            | Request::CountItem { .. }
+            | Request::GetInventory(_)
+            | Request::GetValue { .. }
+            | Request::ListValues { .. } => false,
+            Request::SetCreditz(..)
+            | Request::AddCreditz(..)
+            | Request::SubtractCreditz(..)
+            | Request::SetHappiness(..)
+            | Request::AddHappiness(..)
+            | Request::SetBoredom(..)
+            | Request::AddBoredom(..)
+            | Request::SetHunger(..)
+            | Request::AddHunger(..)
+            | Request::TransferCreditz { .. }
+            | Request::SetStat { .. }
+            | Request::AddStat { .. }
+            | Request::GrantItem { .. }
+            | Request::ConsumeItem { .. }
+            | Request::SetValue { .. }
+            | Request::DeleteValue { .. } => true,
+            Request::Transaction(ops) => ops.iter().any(Op::is_mutating),
+        }
+    }
+}
+
+/// A single operation within a `Request::Transaction`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Op {
+    GetCreditz(UserId),
+    SetCreditz(UserId, u32),
+    AddCreditz(UserId, u32),
+    SubtractCreditz(UserId, u32),
+    GetHappiness(UserId),
+    SetHappiness(UserId, f32),
+    AddHappiness(UserId, f32),
     GetBoredom(UserId),
     SetBoredom(UserId, f32),
+    AddBoredom(UserId, f32),
     GetHunger(UserId),
     SetHunger(UserId, f32),
+    AddHunger(UserId, f32),
+    GetStat {
+        user_id: UserId,
+        stat: String,
+    },
+    SetStat {
+        user_id: UserId,
+        stat: String,
+        value: StatValue,
+    },
+    AddStat {
+        user_id: UserId,
+        stat: String,
+        delta: StatDelta,
+    },
+    GrantItem {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+    },
+    ConsumeItem {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+    },
+    CountItem {
+        user_id: UserId,
+        item: String,
+    },
+}
+
+impl Op {
+    /// Returns true if this operation changes server state.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Op::GetCreditz(_)
+            | Op::GetHappiness(_)
+            | Op::GetBoredom(_)
+            | Op::GetHunger(_)
+            | Op::GetStat { .. }
+            | Op::CountItem { .. } => false,
+            Op::SetCreditz(..)
+            | Op::AddCreditz(..)
+            | Op::SubtractCreditz(..)
+            | Op::SetHappiness(..)
+            | Op::AddHappiness(..)
+            | Op::SetBoredom(..)
+            | Op::AddBoredom(..)
+            | Op::SetHunger(..)
+            | Op::AddHunger(..)
+            | Op::SetStat { .. }
+            | Op::AddStat { .. }
+            | Op::GrantItem { .. }
+            | Op::ConsumeItem { .. } => true,
+        }
+    }
+
+    pub fn user_id(&self) -> UserId {
+        match self {
+            Op::GetCreditz(id)
+            | Op::SetCreditz(id, _)
+            | Op::AddCreditz(id, _)
+            | Op::SubtractCreditz(id, _)
+            | Op::GetHappiness(id)
+            | Op::SetHappiness(id, _)
+            | Op::AddHappiness(id, _)
+            | Op::GetBoredom(id)
+            | Op::SetBoredom(id, _)
+            | Op::AddBoredom(id, _)
+            | Op::GetHunger(id)
+            | Op::SetHunger(id, _)
+            | Op::AddHunger(id, _)
+            | Op::GetStat { user_id: id, .. }
+            | Op::SetStat { user_id: id, .. }
+            | Op::AddStat { user_id: id, .. }
+            | Op::GrantItem { user_id: id, .. }
+            | Op::ConsumeItem { user_id: id, .. }
+            | Op::CountItem { user_id: id, .. } => *id,
+        }
+    }
 }
 
 /// A top-level message sent from the server to clients.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ServerMessage {
-    Response(Response),
-    Notification(Notification),
+    Response {
+        request_id: RequestId,
+        response: Response,
+    },
+    /// Sequence numbers increase by one with every notification the server sends to anyone,
+    /// so a client will see gaps for the notifications it isn't subscribed to.
+    Notification {
+        sequence: u64,
+        notification: Notification,
+    },
+    /// Asks the client to answer with a `Pong` carrying the same nonce. Only sent once the
+    /// `heartbeat` capability is agreed.
+    Ping { nonce: u64 },
+    /// The answer to a client's `Ping`.
+    Pong { nonce: u64 },
 }
 
 /// A direct response to a specific client Request.
@@ -61,14 +482,341 @@ pub enum Response {
     Boredom(StatBar),
     Hunger(StatBar),
     Success,
-    Error(String),
+    Error(ServerError),
+    /// The responses to each operation of a committed `Request::Transaction`, in order.
+    Transaction(Vec<Response>),
+    /// The scopes granted by a successful `Request::Authenticate`.
+    Authenticated(Vec<Scope>),
+    /// The answer to `Subscribe` and `Unsubscribe`. Every notification after `sequence` is
+    /// filtered by the updated subscriptions.
+    Subscribed {
+        sequence: u64,
+    },
+    /// Everything missed since the `Request::Resume` sequence number follows.
+    Resumed,
+    /// Some missed notifications are gone, so the client has to fetch afresh the stats it is
+    /// interested in.
+    ResyncRequired,
+    Character(Character),
+    Characters(Vec<Character>),
+    CreditzHistory(Vec<LedgerEntry>),
+    Stat(StatValue),
+    StatList(Vec<StatInfo>),
+    ItemCount(u32),
+    Inventory(Vec<InventoryItem>),
+    Value(Option<String>),
+    Values(Vec<KeyValue>),
+}
+
+/// Whether a stat is a bar, like happiness, or a whole number, like creditz.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatType {
+    Bar,
+    Integer,
+}
+
+/// The value of a stat read or written by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StatValue {
+    Bar(StatBar),
+    Integer(i64),
+}
+
+impl StatValue {
+    pub fn stat_type(&self) -> StatType {
+        match self {
+            StatValue::Bar(_) => StatType::Bar,
+            StatValue::Integer(_) => StatType::Integer,
+        }
+    }
+}
+
+/// An adjustment to a stat read or written by name.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum StatDelta {
+    /// On the same 0.0 to 1.0 scale as the bar itself.
+    Bar(f32),
+    Integer(i64),
+}
+
+impl StatDelta {
+    pub fn stat_type(&self) -> StatType {
+        match self {
+            StatDelta::Bar(_) => StatType::Bar,
+            StatDelta::Integer(_) => StatType::Integer,
+        }
+    }
+}
+
+/// A stat as listed by `Request::ListStats`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatInfo {
+    pub name: String,
+    pub stat_type: StatType,
+}
+
+/// How many of one item a user has.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InventoryItem {
+    pub item: String,
+    pub quantity: u32,
 }
 
-/// A notification broadcast from the server to all connected clients.
+/// One entry in the key/value store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: String,
+}
+
+/// One change to a user's creditz.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerEntry {
+    /// Increases with every change, across all users.
+    pub id: u64,
+    pub user_id: UserId,
+    /// When the change was made, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub delta: i64,
+    /// The balance after the change.
+    pub balance: u32,
+    /// Why the change was made, as given by the client that made it.
+    pub reason: Option<String>,
+    /// The name of the credential the change was made with, or `None` for anonymous clients
+    /// and the server itself.
+    pub source: Option<String>,
+}
+
+/// Every stat of one user, as returned by `Request::GetCharacter`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Character {
+    pub user_id: UserId,
+    pub creditz: u32,
+    pub happiness: StatBar,
+    pub boredom: StatBar,
+    pub hunger: StatBar,
+}
+
+/// What an authenticated connection is allowed to do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read stats, and receive change notifications.
+    Read,
+    /// Award or charge creditz with `AddCreditz` and `SubtractCreditz`.
+    CreditzWrite,
+    /// Set or adjust happiness, boredom and hunger.
+    StatWrite,
+    /// Everything, including overwriting a balance with `SetCreditz`.
+    Admin,
+    /// Grant and consume items. Consuming also needs the scopes for changing the stats the
+    /// item affects.
+    ItemWrite,
+    /// Read and write the key/value store.
+    KeyValue,
+}
+
+/// Why the server could not fulfil a request.
+#[derive(Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    #[error("Insufficient funds")]
+    InsufficientFunds,
+    #[error("Storage failure: {0}")]
+    StorageFailure(String),
+    #[error("Unknown user {0}")]
+    UnknownUser(UserId),
+    #[error("The result would overflow")]
+    Overflow,
+    #[error("Invalid value: {0}")]
+    InvalidValue(String),
+    #[error("Not authorized to perform this request")]
+    Unauthorized,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Unknown stat {0}")]
+    UnknownStat(String),
+    #[error("Unknown item {0}")]
+    UnknownItem(String),
+    #[error("Not enough items")]
+    NotEnoughItems,
+}
+
+/// A notification sent from the server to every client subscribed to it.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Notification {
-    CreditzChanged { user_id: UserId, new_value: u32 },
-    HappinessChanged { user_id: UserId, new_value: StatBar },
-    BoredomChanged { user_id: UserId, new_value: StatBar },
-    HungerChanged { user_id: UserId, new_value: StatBar },
+    CreditzChanged {
+        user_id: UserId,
+        new_value: u32,
+    },
+    HappinessChanged {
+        user_id: UserId,
+        new_value: StatBar,
+    },
+    BoredomChanged {
+        user_id: UserId,
+        new_value: StatBar,
+    },
+    HungerChanged {
+        user_id: UserId,
+        new_value: StatBar,
+    },
+    /// A stat declared in the server configuration, rather than built in, has changed.
+    StatChanged {
+        user_id: UserId,
+        stat: String,
+        new_value: StatValue,
+    },
+    /// A user was given `quantity` of an item, and now has `new_count`.
+    ItemGranted {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+        new_count: u32,
+    },
+    /// A user used up `quantity` of an item, and now has `new_count`. The item's effects are
+    /// announced separately, as ordinary stat changes.
+    ItemConsumed {
+        user_id: UserId,
+        item: String,
+        quantity: u32,
+        new_count: u32,
+    },
+}
+
+impl Notification {
+    pub fn user_id(&self) -> UserId {
+        match self {
+            Notification::CreditzChanged { user_id, .. }
+            | Notification::HappinessChanged { user_id, .. }
+            | Notification::BoredomChanged { user_id, .. }
+            | Notification::HungerChanged { user_id, .. }
+            | Notification::StatChanged { user_id, .. }
+            | Notification::ItemGranted { user_id, .. }
+            | Notification::ItemConsumed { user_id, .. } => *user_id,
+        }
+    }
+
+    /// The stat this notification is about.
+    pub fn stat(&self) -> StatKind {
+        match self {
+            Notification::CreditzChanged { .. } => StatKind::Creditz,
+            Notification::HappinessChanged { .. } => StatKind::Happiness,
+            Notification::BoredomChanged { .. } => StatKind::Boredom,
+            Notification::HungerChanged { .. } => StatKind::Hunger,
+            Notification::StatChanged { .. } => StatKind::Named,
+            Notification::ItemGranted { .. } | Notification::ItemConsumed { .. } => {
+                StatKind::Inventory
+            }
+        }
+    }
+}
+
+/// The stats a client can subscribe to notifications about.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum StatKind {
+    Creditz,
+    Happiness,
+    Boredom,
+    Hunger,
+    /// Every stat declared in the server configuration, rather than built in.
+    Named,
+    /// Items being granted and consumed.
+    Inventory,
+}
+
+impl StatKind {
+    /// The four built-in stats, for subscribing to all of them at once.
+    pub const BUILT_IN: [StatKind; 4] = [
+        StatKind::Creditz,
+        StatKind::Happiness,
+        StatKind::Boredom,
+        StatKind::Hunger,
+    ];
+
+    /// Every kind of notification, named stats and inventory included.
+    pub const EVERY: [StatKind; 6] = [
+        StatKind::Creditz,
+        StatKind::Happiness,
+        StatKind::Boredom,
+        StatKind::Hunger,
+        StatKind::Named,
+        StatKind::Inventory,
+    ];
+
+    /// The old name of `EVERY`.
+    #[deprecated(
+        note = "use `StatKind::EVERY`, or `StatKind::BUILT_IN` for the four built-in stats"
+    )]
+    pub const ALL: [StatKind; 6] = Self::EVERY;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_bar_saturates_at_both_ends() {
+        let half = StatBar::from_f32(0.5);
+
+        assert_eq!(half.saturating_add(0.75).to_f32(), 1.0);
+        assert_eq!(half.saturating_add(-0.75).to_f32(), 0.0);
+        assert!((half.saturating_add(0.25).to_f32() - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stat_bar_clamps_values_out_of_range() {
+        assert_eq!(StatBar::from_f32(2.0).to_f32(), 1.0);
+        assert_eq!(StatBar::from_f32(-1.0).to_f32(), 0.0);
+        assert_eq!(StatBar::from_u32(u32::MAX).to_f32(), 1.0);
+    }
+
+    #[test]
+    fn negotiation_picks_the_newest_version_both_speak() {
+        assert_eq!(negotiate_version(3, 1, 2, 1), Some(2));
+        assert_eq!(negotiate_version(2, 1, 3, 2), Some(2));
+        assert_eq!(negotiate_version(2, 2, 2, 2), Some(2));
+    }
+
+    #[test]
+    fn negotiation_fails_when_the_ranges_dont_overlap() {
+        assert_eq!(negotiate_version(1, 1, 3, 2), None);
+        assert_eq!(negotiate_version(4, 4, 3, 1), None);
+    }
+
+    #[test]
+    fn current_hello_speaks_its_own_version() {
+        let hello = Hello::current();
+
+        assert_eq!(hello.magic, PROTOCOL_MAGIC);
+        assert_eq!(
+            negotiate_version(
+                hello.protocol_version,
+                hello.min_protocol_version,
+                PROTOCOL_VERSION,
+                MIN_PROTOCOL_VERSION,
+            ),
+            Some(PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn request_id_can_be_read_from_a_request_that_cannot_be_decoded() {
+        let message = ClientMessage::Request {
+            request_id: 42,
+            operation_id: None,
+            reason: None,
+            request: Request::ListStats,
+        };
+        let mut frame = bincode::serialize(&message).unwrap();
+        // A request variant from some future version of the protocol.
+        let len = frame.len();
+        frame[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(bincode::deserialize::<ClientMessage>(&frame).is_err());
+        assert_eq!(ClientMessage::peek_request_id(&frame), Some(42));
+        let ping = bincode::serialize(&ClientMessage::Ping { nonce: 42 }).unwrap();
+        assert_eq!(ClientMessage::peek_request_id(&ping), None);
+    }
 }
@@ -0,0 +1,166 @@
+use crate::protocol::{Notification, Request, StatKind, UserId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The result of every `Subscribe` and `Unsubscribe` request applied to a connection so far.
+///
+/// The server keeps one per connection to filter notifications, and the clients keep a mirror
+/// of their own so that they can restore it after reconnecting.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions {
+    /// Stats subscribed to for every user.
+    all_users: HashSet<StatKind>,
+    /// Stats subscribed to for particular users.
+    users: HashMap<UserId, HashSet<StatKind>>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a `Request::Subscribe` or `Request::Unsubscribe`. Returns false, changing
+    /// nothing, for any other request.
+    pub fn apply(&mut self, request: &Request) -> bool {
+        match request {
+            Request::Subscribe { user_ids, stats } => self.subscribe(user_ids, stats),
+            Request::Unsubscribe { user_ids, stats } => self.unsubscribe(user_ids, stats),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn subscribe(&mut self, user_ids: &[UserId], stats: &[StatKind]) {
+        if user_ids.is_empty() {
+            self.all_users.extend(stats);
+        }
+        for user_id in user_ids {
+            self.users.entry(*user_id).or_default().extend(stats);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, user_ids: &[UserId], stats: &[StatKind]) {
+        if user_ids.is_empty() {
+            self.all_users.retain(|stat| !stats.contains(stat));
+            self.users.retain(|_, user_stats| {
+                user_stats.retain(|stat| !stats.contains(stat));
+                !user_stats.is_empty()
+            });
+        }
+        for user_id in user_ids {
+            if let Some(user_stats) = self.users.get_mut(user_id) {
+                user_stats.retain(|stat| !stats.contains(stat));
+                if user_stats.is_empty() {
+                    self.users.remove(user_id);
+                }
+            }
+        }
+    }
+
+    /// Drops every subscription.
+    pub fn clear(&mut self) {
+        self.all_users.clear();
+        self.users.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all_users.is_empty() && self.users.is_empty()
+    }
+
+    /// Returns true if `notification` should be delivered.
+    pub fn matches(&self, notification: &Notification) -> bool {
+        let stat = notification.stat();
+        self.all_users.contains(&stat)
+            || self
+                .users
+                .get(&notification.user_id())
+                .is_some_and(|user_stats| user_stats.contains(&stat))
+    }
+
+    /// The `Subscribe` requests that rebuild these subscriptions on a fresh connection.
+    /// Users subscribed to the same stats share a request.
+    pub fn to_requests(&self) -> Vec<Request> {
+        let mut requests = Vec::new();
+        if !self.all_users.is_empty() {
+            requests.push(Request::Subscribe {
+                user_ids: Vec::new(),
+                stats: self.all_users.iter().copied().collect(),
+            });
+        }
+
+        let mut users_by_stats: BTreeMap<Vec<StatKind>, Vec<UserId>> = BTreeMap::new();
+        for (user_id, stats) in &self.users {
+            let mut stats: Vec<StatKind> = stats.iter().copied().collect();
+            stats.sort_unstable();
+            users_by_stats.entry(stats).or_default().push(*user_id);
+        }
+        for (stats, user_ids) in users_by_stats {
+            requests.push(Request::Subscribe { user_ids, stats });
+        }
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creditz(user_id: UserId) -> Notification {
+        Notification::CreditzChanged {
+            user_id,
+            new_value: 1,
+        }
+    }
+
+    #[test]
+    fn matches_only_the_subscribed_users_and_stats() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(&[1], &[StatKind::Creditz]);
+
+        assert!(subscriptions.matches(&creditz(1)));
+        assert!(!subscriptions.matches(&creditz(2)));
+        assert!(!subscriptions.matches(&Notification::ItemGranted {
+            user_id: 1,
+            item: "coin".to_string(),
+            quantity: 1,
+            new_count: 1,
+        }));
+    }
+
+    #[test]
+    fn subscribing_without_users_covers_everyone() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(&[], &[StatKind::Creditz]);
+
+        assert!(subscriptions.matches(&creditz(1)));
+        assert!(subscriptions.matches(&creditz(2)));
+    }
+
+    #[test]
+    fn unsubscribing_without_users_removes_per_user_subscriptions_too() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(&[], &[StatKind::Creditz]);
+        subscriptions.subscribe(&[1], &[StatKind::Creditz]);
+
+        subscriptions.unsubscribe(&[], &[StatKind::Creditz]);
+
+        assert!(!subscriptions.matches(&creditz(1)));
+        assert!(subscriptions.is_empty());
+    }
+
+    #[test]
+    fn requests_rebuild_the_same_subscriptions() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(&[], &[StatKind::Hunger]);
+        subscriptions.subscribe(&[1, 2], &[StatKind::Creditz]);
+        subscriptions.subscribe(&[3], &[StatKind::Creditz, StatKind::Inventory]);
+
+        let mut rebuilt = Subscriptions::new();
+        for request in subscriptions.to_requests() {
+            assert!(rebuilt.apply(&request));
+        }
+
+        assert_eq!(subscriptions.to_requests().len(), 3);
+        assert_eq!(rebuilt.all_users, subscriptions.all_users);
+        assert_eq!(rebuilt.users, subscriptions.users);
+    }
+}
@@ -10,7 +10,7 @@ use aw_sdk::{
     AwEvent, AwInstance, ConsoleMessageParams, ObjectClickInfo, ObjectInfo, QueryResult, SdkError,
     SdkResult, StateChangeParams, TeleportParams,
 };
-use character::CharacterClient;
+use character::{CharacterClient, CharacterError, ServerError};
 
 // =================================================================================================
 //                                         CONFIGURATION
@@ -80,11 +80,12 @@ impl GameManager {
         host: &str,
         port: u16,
         character_addr: &str,
+        character_token: Option<&str>,
         config: GameConfig,
     ) -> Result<Self, String> {
         let ticket_taker =
             AwInstance::new(host, port).map_err(|e| format!("TicketTaker: {}", e))?;
-        let client = CharacterClient::connect(character_addr)
+        let client = CharacterClient::connect_with_token(character_addr, character_token)
             .map_err(|e| format!("CharacterClient: {}", e))?;
 
         Ok(Self {
@@ -288,7 +289,11 @@ impl GameManager {
                         // Player already has a ticket, refund it.
                         self.ticket_holders.remove(&citizen_id);
                         self.client
-                            .add_creditz(citizen_id, self.config.ticket_price)
+                            .add_creditz_with_reason(
+                                citizen_id,
+                                self.config.ticket_price,
+                                &format!("refund:{}", self.config.game_name),
+                            )
                             .ok();
                         self.ticket_taker.console_message(ConsoleMessageParams {
                             message: format!(
@@ -302,10 +307,11 @@ impl GameManager {
                         })?;
                     } else {
                         // Player does not have a ticket, sell one.
-                        match self
-                            .client
-                            .sub_creditz(citizen_id, self.config.ticket_price)
-                        {
+                        match self.client.sub_creditz_with_reason(
+                            citizen_id,
+                            self.config.ticket_price,
+                            &format!("ticket:{}", self.config.game_name),
+                        ) {
                             Ok(_) => {
                                 let player_info = PlayerInfo {
                                     citizen_id,
@@ -327,7 +333,7 @@ impl GameManager {
                                     color: (0, 0, 0),
                                 })?;
                             }
-                            Err(_) => {
+                            Err(CharacterError::Server(ServerError::InsufficientFunds)) => {
                                 self.ticket_taker.console_message(ConsoleMessageParams {
                                     message: format!(
                                         "Sorry {}, you don't have enough creditz to buy a ticket.",
@@ -339,6 +345,22 @@ impl GameManager {
                                     color: (0, 0, 0),
                                 })?;
                             }
+                            Err(e) => {
+                                println!(
+                                    "Failed to sell a {} ticket to citizen {}: {}",
+                                    self.config.game_name, citizen_id, e
+                                );
+                                self.ticket_taker.console_message(ConsoleMessageParams {
+                                    message: format!(
+                                        "Sorry {}, tickets can't be sold right now. Please try again later.",
+                                        click.avatar_name
+                                    ),
+                                    session_id: click.avatar_session,
+                                    bold: false,
+                                    italics: false,
+                                    color: (0, 0, 0),
+                                })?;
+                            }
                         }
                     }
                 }
@@ -7,7 +7,7 @@ use aw_sdk::{
     AwEvent, AwInstance, ConsoleMessageParams, LoginParams, ObjectBumpInfo, SdkError, SdkResult,
     StateChangeParams, TeleportParams, cell_from_cm, sector_from_cell,
 };
-use character::CharacterClient;
+use character::{CharacterClient, Op};
 use game_manager::{GameConfig, GameManager, PlayerInfo};
 
 // =================================================================================================
@@ -89,13 +89,19 @@ impl ObstacleBot {
 
         let character_addr = format!("{}:{}", config.character_host, config.character_port);
 
-        let game_manager =
-            GameManager::new(&config.host, config.port, &character_addr, game_config)
-                .map_err(InitError::GameManager)?;
+        let game_manager = GameManager::new(
+            &config.host,
+            config.port,
+            &character_addr,
+            config.character_token.as_deref(),
+            game_config,
+        )
+        .map_err(InitError::GameManager)?;
         let game_world_instance = AwInstance::new(&config.host, config.port)
             .map_err(|e| InitError::GameInstance(e.to_string()))?;
-        let client = CharacterClient::connect(&character_addr)
-            .map_err(|e| InitError::CharacterClient(e.to_string()))?;
+        let client =
+            CharacterClient::connect_with_token(&character_addr, config.character_token.as_deref())
+                .map_err(|e| InitError::CharacterClient(e.to_string()))?;
 
         Ok(Self {
             config,
@@ -406,17 +412,16 @@ impl ObstacleBot {
 
             let message = format!("{} collected {} credits", player.name, score);
             self.broadcast_console_message_ingame(&players, &message)?;
-            self.client.add_creditz(player.citizen_id, score).ok();
-            if let Ok(happiness) = self.client.get_happiness(player.citizen_id) {
-                self.client
-                    .set_happiness(player.citizen_id, happiness + 0.1)
-                    .ok();
-            }
-            if let Ok(boredom) = self.client.get_boredom(player.citizen_id) {
-                self.client
-                    .set_boredom(player.citizen_id, boredom + 0.25)
-                    .ok();
-            }
+            // Pay out the prize and stat bonuses together, so a failure can't leave a
+            // player partly rewarded.
+            let payout = vec![
+                Op::AddCreditz(player.citizen_id, score),
+                Op::AddHappiness(player.citizen_id, 0.1),
+                Op::AddBoredom(player.citizen_id, 0.25),
+            ];
+            self.client
+                .transaction_with_reason(payout, &format!("prize:{}", self.config.game_name))
+                .ok();
         }
 
         self.broadcast_console_message_ingame(
@@ -465,6 +470,8 @@ pub struct ObstacleBotConfig {
     pub port: u16,
     pub character_host: String,
     pub character_port: u16,
+    /// Should grant the creditz and stat write scopes, to charge for tickets and pay out.
+    pub character_token: Option<String>,
 
     pub owner_id: u32,
     pub privilege_password: String,